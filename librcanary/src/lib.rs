@@ -2,14 +2,30 @@
 extern crate serde_derive;
 extern crate serde;
 
+use std::collections::BTreeMap;
 use std::fmt;
 
 use serde::{Serialize, Serializer};
 
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
 pub struct CanaryMetricsConfig {
+    /// Prometheus endpoint, required when `enabled` is set
+    #[serde(default)]
     pub address: String,
+    /// Serves the Prometheus endpoint, StatsD and push metrics have their own `enabled`
+    #[serde(default)]
     pub enabled: bool,
+    /// Upper bounds of the latency histogram buckets
+    #[serde(default = "default_latency_buckets_ms")]
+    pub latency_buckets_ms: Vec<u64>,
+    #[serde(default)]
+    pub push: Option<CanaryPushMetricsConfig>,
+    #[serde(default)]
+    pub statsd: Option<CanaryStatsdConfig>,
+}
+
+fn default_latency_buckets_ms() -> Vec<u64> {
+    vec![5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000]
 }
 
 impl Default for CanaryMetricsConfig {
@@ -17,6 +33,87 @@ impl Default for CanaryMetricsConfig {
         CanaryMetricsConfig {
             address: "".to_string(),
             enabled: false,
+            latency_buckets_ms: default_latency_buckets_ms(),
+            push: None,
+            statsd: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryPushMetricsConfig {
+    /// InfluxDB write URL, or Graphite `host:port`
+    pub address: String,
+    /// Points are flushed early once this many are buffered
+    pub batch_size: usize,
+    /// Points kept while the sink is down, the oldest are dropped first
+    pub buffer_size: usize,
+    pub enabled: bool,
+    pub flush_interval_ms: u64,
+    /// `influxdb` or `graphite`
+    pub format: String,
+    /// Graphite metric path prefix
+    pub prefix: String,
+}
+
+impl Default for CanaryPushMetricsConfig {
+    fn default() -> Self {
+        CanaryPushMetricsConfig {
+            address: "".to_string(),
+            batch_size: 500,
+            buffer_size: 10000,
+            enabled: false,
+            flush_interval_ms: 10000,
+            format: "influxdb".to_string(),
+            prefix: "rcanary".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryStatsdConfig {
+    pub address: String,
+    pub enabled: bool,
+    pub prefix: String,
+    /// Send target details as DogStatsD tags instead of in metric names
+    pub dogstatsd: bool,
+}
+
+impl Default for CanaryStatsdConfig {
+    fn default() -> Self {
+        CanaryStatsdConfig {
+            address: "127.0.0.1:8125".to_string(),
+            enabled: false,
+            prefix: "rcanary".to_string(),
+            dogstatsd: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryOtlpConfig {
+    pub enabled: bool,
+    /// OTLP/HTTP collector, spans and metrics are posted to `/v1/traces` and `/v1/metrics`
+    pub endpoint: String,
+    pub metrics: bool,
+    pub service_name: String,
+    /// Send a `traceparent` header with probe requests so backend traces link to the check
+    pub traceparent: bool,
+    pub traces: bool,
+}
+
+impl Default for CanaryOtlpConfig {
+    fn default() -> Self {
+        CanaryOtlpConfig {
+            enabled: false,
+            endpoint: "http://127.0.0.1:4318".to_string(),
+            metrics: true,
+            service_name: "rcanary".to_string(),
+            traceparent: false,
+            traces: true,
         }
     }
 }
@@ -36,12 +133,221 @@ impl Default for CanaryHealthCheckConfig {
     }
 }
 
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryApiConfig {
+    pub address: String,
+    pub enabled: bool,
+    /// Serves badges without credentials, to clients from allowed addresses
+    #[serde(default)]
+    pub public_badges: bool,
+    /// Targets added, changed or removed through the API are saved here, and read instead of
+    /// `targets` on startup once it exists
+    #[serde(default)]
+    pub targets_file: Option<String>,
+}
+
+impl Default for CanaryApiConfig {
+    fn default() -> Self {
+        CanaryApiConfig {
+            address: "".to_string(),
+            enabled: false,
+            public_badges: false,
+            targets_file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryDashboardConfig {
+    pub address: String,
+    pub enabled: bool,
+    /// Websocket URL the dashboard connects to, eg. behind a reverse proxy. Defaults to the
+    /// host the dashboard was loaded from, on the port of `server_listen_address`.
+    pub websocket_url: Option<String>,
+}
+
+impl Default for CanaryDashboardConfig {
+    fn default() -> Self {
+        CanaryDashboardConfig {
+            address: "".to_string(),
+            enabled: false,
+            websocket_url: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CanaryAuthConfig {
+    /// IP addresses or CIDR ranges, eg. `10.0.0.0/8`, allowed to connect. Everyone if empty.
+    pub allowed_ips: Vec<String>,
+    pub enabled: bool,
+    /// Clients need to present one of these users' credentials. Anyone allowed to connect
+    /// is an admin if empty.
+    pub users: Vec<CanaryAuthUser>,
+}
+
+/// Authenticates with basic auth using `name` and `password`, or with `token` as a bearer token
+#[derive(Deserialize, Eq, PartialEq, Clone)]
+pub struct CanaryAuthUser {
+    pub name: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub scope: CanaryAuthScope,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl fmt::Debug for CanaryAuthUser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CanaryAuthUser {{ name: {:?}, scope: {:?}, ... }}",
+            self.name, self.scope
+        )
+    }
+}
+
+impl Serialize for CanaryAuthUser {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str("redacted")
+    }
+}
+
+/// `admin` can do everything `read` can
+#[derive(Deserialize, Serialize, Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CanaryAuthScope {
+    /// Dashboard, websocket, health, metrics and `GET` API requests
+    Read,
+    /// Acknowledging and silencing alerts
+    Admin,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryStorageConfig {
+    /// Raw checks older than this are downsampled
+    pub downsample_after_days: u64,
+    /// Width of downsampled buckets
+    pub downsample_interval_s: u64,
+    pub enabled: bool,
+    /// SQLite database file, created if missing
+    pub path: String,
+    /// Downsampled checks and transitions older than this are deleted
+    pub retention_days: u64,
+}
+
+impl Default for CanaryStorageConfig {
+    fn default() -> Self {
+        CanaryStorageConfig {
+            downsample_after_days: 7,
+            downsample_interval_s: 3600,
+            enabled: false,
+            path: "rcanary.db".to_string(),
+            retention_days: 90,
+        }
+    }
+}
+
+/// Streams check results as Server-Sent Events, for clients which cannot use websockets
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanarySseConfig {
+    pub address: String,
+    /// Results kept for clients resuming with `Last-Event-ID`
+    pub buffer_size: usize,
+    pub enabled: bool,
+}
+
+impl Default for CanarySseConfig {
+    fn default() -> Self {
+        CanarySseConfig {
+            address: "".to_string(),
+            buffer_size: 1000,
+            enabled: false,
+        }
+    }
+}
+
+/// Customer-facing status page, built from components which group targets under public names
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryStatusPageConfig {
+    /// Serves the status page live, eg. `0.0.0.0:8097`
+    pub address: Option<String>,
+    pub components: Vec<CanaryStatusPageComponent>,
+    /// Days of uptime bars, and of incidents listed
+    pub days: u64,
+    pub enabled: bool,
+    /// Writes `index.html` and `status.json` to this directory whenever a status changes
+    pub output_dir: Option<String>,
+    pub title: String,
+}
+
+impl Default for CanaryStatusPageConfig {
+    fn default() -> Self {
+        CanaryStatusPageConfig {
+            address: None,
+            components: vec![],
+            days: 90,
+            enabled: false,
+            output_dir: None,
+            title: "Status".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryStatusPageComponent {
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Public name, shown instead of the targets' names and hosts
+    pub name: String,
+    /// Names of the targets making up the component
+    pub targets: Vec<String>,
+}
+
+/// Serves the websocket, API, health check and metrics listeners over TLS
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryTlsConfig {
+    /// PEM certificate, followed by any intermediates
+    pub certificate_path: String,
+    pub enabled: bool,
+    /// PEM private key
+    pub key_path: String,
+}
+
+impl Default for CanaryTlsConfig {
+    fn default() -> Self {
+        CanaryTlsConfig {
+            certificate_path: "".to_string(),
+            enabled: false,
+            key_path: "".to_string(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
 pub struct CanaryEmailAlertConfig {
     pub alert_email: String,
     pub smtp_server: String,
     pub smtp_username: String,
     pub smtp_password: String,
+    #[serde(default)]
+    pub subject_template: Option<String>,
+    #[serde(default)]
+    pub text_template: Option<String>,
+    #[serde(default)]
+    pub html_template: Option<String>,
+    #[serde(default)]
+    pub group_subject_template: Option<String>,
+    #[serde(default)]
+    pub group_text_template: Option<String>,
+    #[serde(default)]
+    pub group_html_template: Option<String>,
 }
 
 impl Default for CanaryEmailAlertConfig {
@@ -51,35 +357,94 @@ impl Default for CanaryEmailAlertConfig {
             smtp_server: "".to_string(),
             smtp_username: "".to_string(),
             smtp_password: "".to_string(),
+            subject_template: None,
+            text_template: None,
+            html_template: None,
+            group_subject_template: None,
+            group_text_template: None,
+            group_html_template: None,
         }
     }
 }
 
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
-pub struct CanaryAlertConfig {
-    pub enabled: bool,
-    pub email: Option<CanaryEmailAlertConfig>,
+#[serde(default)]
+pub struct CanaryAlertDeliveryConfig {
+    pub queue_size: usize,
+    pub max_retries: u32,
+    pub backoff_initial_ms: u64,
+    pub backoff_max_ms: u64,
+    pub spool_path: Option<String>,
+    pub dead_letter_path: Option<String>,
 }
 
-impl Default for CanaryAlertConfig {
+impl Default for CanaryAlertDeliveryConfig {
     fn default() -> Self {
-        CanaryAlertConfig {
-            enabled: false,
-            email: None,
+        CanaryAlertDeliveryConfig {
+            queue_size: 1000,
+            max_retries: 5,
+            backoff_initial_ms: 1000,
+            backoff_max_ms: 300_000,
+            spool_path: None,
+            dead_letter_path: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[serde(default)]
+pub struct CanaryAlertGroupingConfig {
+    pub window_s: u64,
+    pub group_by: String,
+    pub digest_interval_s: u64,
+}
+
+impl Default for CanaryAlertGroupingConfig {
+    fn default() -> Self {
+        CanaryAlertGroupingConfig {
+            window_s: 0,
+            group_by: "tag".to_string(),
+            digest_interval_s: 0,
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug, Default)]
+pub struct CanaryAlertConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub delivery: CanaryAlertDeliveryConfig,
+    #[serde(default)]
+    pub grouping: CanaryAlertGroupingConfig,
+    pub email: Option<CanaryEmailAlertConfig>,
+}
+
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
 pub struct CanaryConfig {
     #[serde(default)]
     pub alert: CanaryAlertConfig,
     #[serde(default)]
+    pub api: Option<CanaryApiConfig>,
+    #[serde(default)]
+    pub auth: Option<CanaryAuthConfig>,
+    #[serde(default)]
+    pub dashboard: Option<CanaryDashboardConfig>,
+    #[serde(default)]
     pub health_check: Option<CanaryHealthCheckConfig>,
     #[serde(default)]
     pub metrics: Option<CanaryMetricsConfig>,
+    #[serde(default)]
+    pub otlp: Option<CanaryOtlpConfig>,
     pub server_listen_address: String,
+    #[serde(default)]
+    pub sse: Option<CanarySseConfig>,
+    #[serde(default)]
+    pub status_page: Option<CanaryStatusPageConfig>,
+    #[serde(default)]
+    pub storage: Option<CanaryStorageConfig>,
     pub targets: CanaryTargetTypes,
+    #[serde(default)]
+    pub tls: Option<CanaryTlsConfig>,
 }
 
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
@@ -91,9 +456,18 @@ pub struct CanaryTargetTypes {
 pub struct CanaryTarget {
     pub alert: bool,
     pub basic_auth: Option<Auth>,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
     pub host: String,
     pub interval_s: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
     pub name: String,
+    /// Paused targets are not checked
+    #[serde(default)]
+    pub paused: bool,
+    /// Deprecated: metrics are labelled with the target's name, host and tag instead
+    #[serde(default)]
     pub tag_metric: Option<String>,
     pub tag: Option<String>,
 }
@@ -119,6 +493,11 @@ impl Serialize for Auth {
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
 pub struct CanaryCheck {
     pub alert: bool,
+    /// Chain of dependencies leading to a failing ancestor, nearest first
+    #[serde(default)]
+    pub blocked_by: Vec<String>,
+    #[serde(default)]
+    pub elements: Vec<CanaryCheckElement>,
     pub latency_ms: u64,
     pub need_to_alert: bool,
     pub status_code: String,
@@ -126,6 +505,71 @@ pub struct CanaryCheck {
     pub status: Status,
     pub target: CanaryTarget,
     pub time: String,
+    /// W3C trace context sent with the probe requests, if any
+    #[serde(default)]
+    pub traceparent: Option<String>,
+}
+
+/// Result of probing a single resolved address of a target
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryCheckElement {
+    pub address: String,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub phases: Vec<CanaryCheckPhase>,
+    pub status_code: u16,
+}
+
+/// Time spent in one step of a check, e.g. `dns`, `tls-handshake` or `http`
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryCheckPhase {
+    pub latency_ms: u64,
+    pub name: String,
+    /// Time from the start of the check to the start of this step
+    #[serde(default)]
+    pub offset_ms: u64,
+}
+
+/// Someone has taken ownership of a failing target
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryAcknowledgement {
+    pub author: String,
+    pub comment: String,
+    pub target: String,
+    pub time: String,
+}
+
+/// Mutes alerts for a target or every target with a tag until `ends_at`
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanarySilence {
+    pub author: String,
+    pub comment: String,
+    pub ends_at: String,
+    pub id: u64,
+    pub starts_at: String,
+    pub tag: Option<String>,
+    pub target: Option<String>,
+}
+
+/// An outage of a target, from its first failing check until it is okay again
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+pub struct CanaryIncident {
+    pub acknowledgements: Vec<CanaryAcknowledgement>,
+    pub alerts_sent: u64,
+    pub duration_s: u64,
+    /// `None` while the incident is ongoing
+    pub ended_at: Option<String>,
+    pub id: u64,
+    /// `Fire` if the target was ever on fire during the incident
+    pub peak_status: Status,
+    /// Shown on the public status page
+    #[serde(default)]
+    pub public_note: Option<String>,
+    pub started_at: String,
+    /// Every distinct `status_reason` seen, in order
+    pub status_reasons: Vec<String>,
+    pub target: String,
 }
 
 #[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
@@ -1,38 +1,108 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use super::dispatch::Receiver;
 use super::email::EmailAlerter;
-use super::Alerter;
-use log::info;
+use time::Timespec;
 
-use crate::{CanaryCheck, CanaryConfig, CanaryTarget, Status};
+use crate::{CanaryCheck, CanaryConfig, Status};
 
 // Checks if alert would be spam.
 // Alert would be spam if state has not changed since last poll
-pub fn check_spam(last_statuses: &HashMap<CanaryTarget, Status>, result: &CanaryCheck) -> bool {
+pub fn check_spam(last_statuses: &HashMap<String, Status>, result: &CanaryCheck) -> bool {
     #[allow(clippy::match_like_matches_macro)]
-    match last_statuses.get(&result.target) {
+    match last_statuses.get(&result.target.name) {
         Some(status) => status == &result.status,
         _ => false,
     }
 }
 
-pub fn check_fixed(last_statuses: &HashMap<CanaryTarget, Status>, result: &CanaryCheck) -> bool {
+pub fn check_fixed(last_statuses: &HashMap<String, Status>, result: &CanaryCheck) -> bool {
     #[allow(clippy::match_like_matches_macro)]
-    match (last_statuses.get(&result.target), &result.status) {
+    match (last_statuses.get(&result.target.name), &result.status) {
         (Some(&Status::Fire), &Status::Okay) | (Some(&Status::Unknown), &Status::Okay) => true,
         _ => false,
     }
 }
 
-pub fn send_alert(config: &CanaryConfig, result: &CanaryCheck) -> Result<(), String> {
-    info!("[alert.send] sending alert for {:?}", result);
+// Suppresses alerts for targets blocked by a failing dependency.
+// A target whose failure was suppressed alerts once it is unblocked if it is still failing,
+// and does not send a resolution for a failure nobody was told about.
+pub fn check_blocked(
+    suppressed: &mut HashSet<String>,
+    previous_status: Option<&Status>,
+    result: &CanaryCheck,
+    notify: bool,
+) -> bool {
+    let was_suppressed = suppressed.remove(&result.target.name);
+
+    if !result.blocked_by.is_empty() && result.status != Status::Okay {
+        // Failures that were already alerted on have nothing new to suppress
+        if was_suppressed || matches!(previous_status, None | Some(Status::Okay)) {
+            suppressed.insert(result.target.name.clone());
+        }
+        return false;
+    }
+
+    if was_suppressed {
+        return result.status != Status::Okay && result.need_to_alert;
+    }
+
+    notify
+}
+
+// Only resolves failures which were alerted on, e.g. not ones which started while the
+// target was silenced or acknowledged.
+pub fn check_alerted(alerted: &mut HashSet<String>, result: &CanaryCheck, notify: bool) -> bool {
+    if result.status == Status::Okay {
+        return alerted.remove(&result.target.name) && notify;
+    }
 
-    if config.alert.email.is_some() {
-        let alerter: EmailAlerter = EmailAlerter { config: &config };
-        return alerter.alert(result);
+    if notify {
+        alerted.insert(result.target.name.clone());
     }
+    notify
+}
 
-    Ok(())
+// Tracks when each target started failing.
+// Returns how long the current outage has lasted, or how long it lasted if this result resolves it.
+pub fn track_outage(
+    outage_starts: &mut HashMap<String, Timespec>,
+    result: &CanaryCheck,
+) -> Option<u64> {
+    let now = parse_check_time(&result.time);
+    let seconds_since = |start: Timespec| (now - start).num_seconds().max(0) as u64;
+
+    match result.status {
+        Status::Okay => outage_starts.remove(&result.target.name).map(seconds_since),
+        _ => Some(seconds_since(
+            *outage_starts
+                .entry(result.target.name.clone())
+                .or_insert(now),
+        )),
+    }
+}
+
+pub fn parse_check_time(time: &str) -> Timespec {
+    time::strptime(time, "%Y-%m-%dT%H:%M:%SZ")
+        .map(|tm| tm.to_timespec())
+        .unwrap_or_else(|_| time::get_time())
+}
+
+// Builds a receiver for every alerter that has been configured
+pub fn receivers(config: &CanaryConfig) -> Vec<Receiver> {
+    let mut receivers = Vec::new();
+
+    if let Some(ref email_config) = config.alert.email {
+        receivers.push(Receiver {
+            name: "email".to_string(),
+            alerter: Arc::new(EmailAlerter {
+                config: email_config.clone(),
+            }),
+        });
+    }
+
+    receivers
 }
 
 #[cfg(test)]
@@ -45,6 +115,8 @@ mod tests {
     fn okay_result() -> CanaryCheck {
         CanaryCheck {
             alert: true,
+            blocked_by: vec![],
+            elements: vec![],
             latency_ms: 299,
             need_to_alert: true,
             status_code: "200 OK".to_string(),
@@ -52,12 +124,15 @@ mod tests {
             status_reason: "no reason".to_string(),
             target: target(),
             time: "2016-10-14T08:00:00Z".to_string(),
+            traceparent: None,
         }
     }
 
     fn fire_result() -> CanaryCheck {
         CanaryCheck {
             alert: true,
+            blocked_by: vec![],
+            elements: vec![],
             latency_ms: 499,
             need_to_alert: true,
             status_code: "401 Unauthorized".to_string(),
@@ -65,6 +140,7 @@ mod tests {
             status_reason: "no reason".to_string(),
             target: target(),
             time: "2016-10-14T08:00:00Z".to_string(),
+            traceparent: None,
         }
     }
 
@@ -80,7 +156,7 @@ mod tests {
     #[test]
     fn it_does_not_mark_as_spam_on_change_from_okay_to_fire() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Okay);
+        last_statuses.insert(target().name, Status::Okay);
 
         let actual = check_spam(&last_statuses, &fire_result());
 
@@ -90,7 +166,7 @@ mod tests {
     #[test]
     fn it_marks_as_spam_on_continued_okay() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Okay);
+        last_statuses.insert(target().name, Status::Okay);
 
         let actual = check_spam(&last_statuses, &okay_result());
 
@@ -100,17 +176,29 @@ mod tests {
     #[test]
     fn it_marks_as_spam_on_continued_fire() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Fire);
+        last_statuses.insert(target().name, Status::Fire);
 
         let actual = check_spam(&last_statuses, &fire_result());
 
         assert_eq!(true, actual);
     }
 
+    #[test]
+    fn it_marks_as_spam_after_the_target_changed() {
+        let mut last_statuses = HashMap::new();
+        last_statuses.insert(target().name, Status::Fire);
+
+        let mut changed = fire_result();
+        changed.target.interval_s += 10;
+        let actual = check_spam(&last_statuses, &changed);
+
+        assert_eq!(true, actual);
+    }
+
     #[test]
     fn it_does_not_mark_as_spam_on_change_from_fire_to_okay() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Fire);
+        last_statuses.insert(target().name, Status::Fire);
 
         let actual = check_spam(&last_statuses, &okay_result());
 
@@ -120,7 +208,7 @@ mod tests {
     #[test]
     fn it_marks_as_spam_on_change_from_unknown_to_fire() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Unknown);
+        last_statuses.insert(target().name, Status::Unknown);
 
         let actual = check_spam(&last_statuses, &fire_result());
 
@@ -130,7 +218,7 @@ mod tests {
     #[test]
     fn it_marks_as_fixed_on_change_from_unknown_to_okay() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Unknown);
+        last_statuses.insert(target().name, Status::Unknown);
 
         let actual = check_fixed(&last_statuses, &okay_result());
 
@@ -140,7 +228,7 @@ mod tests {
     #[test]
     fn it_marks_as_fixed_on_change_from_fire_to_okay() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Fire);
+        last_statuses.insert(target().name, Status::Fire);
 
         let actual = check_fixed(&last_statuses, &okay_result());
 
@@ -150,10 +238,93 @@ mod tests {
     #[test]
     fn it_marks_as_unfixed_on_change_from_fire_to_fire() {
         let mut last_statuses = HashMap::new();
-        last_statuses.insert(target(), Status::Fire);
+        last_statuses.insert(target().name, Status::Fire);
 
         let actual = check_fixed(&last_statuses, &fire_result());
 
         assert_eq!(false, actual);
     }
+
+    #[test]
+    fn it_tracks_outage_duration_until_fixed() {
+        let mut outage_starts = HashMap::new();
+
+        let mut fire = fire_result();
+        assert_eq!(Some(0), track_outage(&mut outage_starts, &fire));
+
+        fire.time = "2016-10-14T08:01:30Z".to_string();
+        assert_eq!(Some(90), track_outage(&mut outage_starts, &fire));
+
+        let mut okay = okay_result();
+        okay.time = "2016-10-14T08:05:00Z".to_string();
+        assert_eq!(Some(300), track_outage(&mut outage_starts, &okay));
+        assert!(outage_starts.is_empty());
+    }
+
+    #[test]
+    fn it_does_not_track_outage_duration_when_okay() {
+        let mut outage_starts = HashMap::new();
+
+        let actual = track_outage(&mut outage_starts, &okay_result());
+
+        assert_eq!(None, actual);
+    }
+
+    #[test]
+    fn it_suppresses_alerts_while_blocked_then_alerts_if_still_failing() {
+        let mut suppressed = HashSet::new();
+        let mut blocked = fire_result();
+        blocked.blocked_by = vec!["lb".to_string()];
+
+        assert_eq!(
+            false,
+            check_blocked(&mut suppressed, Some(&Status::Okay), &blocked, true)
+        );
+        assert_eq!(
+            false,
+            check_blocked(&mut suppressed, Some(&Status::Fire), &blocked, false)
+        );
+        assert_eq!(
+            true,
+            check_blocked(&mut suppressed, Some(&Status::Fire), &fire_result(), false)
+        );
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn it_does_not_resolve_suppressed_alerts() {
+        let mut suppressed = HashSet::new();
+        let mut blocked = fire_result();
+        blocked.blocked_by = vec!["lb".to_string()];
+
+        check_blocked(&mut suppressed, None, &blocked, true);
+        let actual = check_blocked(&mut suppressed, Some(&Status::Fire), &okay_result(), true);
+
+        assert_eq!(false, actual);
+    }
+
+    #[test]
+    fn it_resolves_alerts_sent_before_being_blocked() {
+        let mut suppressed = HashSet::new();
+        let mut blocked = fire_result();
+        blocked.blocked_by = vec!["lb".to_string()];
+
+        check_blocked(&mut suppressed, Some(&Status::Fire), &blocked, false);
+        let actual = check_blocked(&mut suppressed, Some(&Status::Fire), &okay_result(), true);
+
+        assert_eq!(true, actual);
+    }
+
+    #[test]
+    fn it_does_not_resolve_failures_which_were_not_alerted_on() {
+        let mut alerted = HashSet::new();
+
+        // Silenced when it started failing, the silence ended before it recovered
+        assert_eq!(false, check_alerted(&mut alerted, &fire_result(), false));
+        assert_eq!(false, check_alerted(&mut alerted, &okay_result(), true));
+
+        assert_eq!(true, check_alerted(&mut alerted, &fire_result(), true));
+        assert_eq!(true, check_alerted(&mut alerted, &okay_result(), true));
+        assert!(alerted.is_empty());
+    }
 }
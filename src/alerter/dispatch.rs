@@ -0,0 +1,703 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use librcanary::CanaryAlertDeliveryConfig;
+
+use super::{AlertGroup, Alerter};
+use crate::metrics::Metrics;
+
+/// A named destination for alerts, e.g. an email inbox
+#[derive(Clone)]
+pub struct Receiver {
+    pub name: String,
+    pub alerter: Arc<dyn Alerter + Send + Sync>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+struct PendingDelivery {
+    id: u64,
+    receiver: String,
+    group: AlertGroup,
+}
+
+// Deliveries which have been accepted but not yet sent.
+// Mirrored to disk when a spool path is configured so they survive a restart.
+struct Spool {
+    path: Option<PathBuf>,
+    next_id: u64,
+    pending: BTreeMap<u64, PendingDelivery>,
+}
+
+impl Spool {
+    fn load(path: Option<PathBuf>) -> Spool {
+        let mut pending = BTreeMap::new();
+
+        if let Some(ref path) = path {
+            match read_spool(path) {
+                Ok(deliveries) => {
+                    for delivery in deliveries {
+                        pending.insert(delivery.id, delivery);
+                    }
+                }
+                Err(err) => info!(
+                    "[alert.spool] failed to read spool at {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        let next_id = pending.keys().next_back().map_or(0, |id| id + 1);
+
+        Spool {
+            path,
+            next_id,
+            pending,
+        }
+    }
+
+    fn add(&mut self, receiver: &str, group: &AlertGroup) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut group = group.clone();
+        // Credentials are redacted on serialisation and are not needed to deliver alerts
+        for alert in &mut group.alerts {
+            alert.check.target.basic_auth = None;
+        }
+
+        self.pending.insert(
+            id,
+            PendingDelivery {
+                id,
+                receiver: receiver.to_string(),
+                group,
+            },
+        );
+        self.save();
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.pending.remove(&id);
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(ref path) = self.path {
+            let deliveries = self.pending.values().collect::<Vec<_>>();
+            if let Err(err) = write_spool(path, &deliveries) {
+                info!(
+                    "[alert.spool] failed to write spool at {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn read_spool(path: &PathBuf) -> Result<Vec<PendingDelivery>, String> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+fn write_spool(path: &PathBuf, deliveries: &[&PendingDelivery]) -> Result<(), String> {
+    let json = serde_json::to_string(deliveries).map_err(|e| e.to_string())?;
+
+    // Write then rename so a crash never leaves a truncated spool behind
+    let tmp_path = path.with_extension("tmp");
+    File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(json.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| e.to_string())
+}
+
+type WorkerKey = (String, String);
+type Workers = Arc<Mutex<HashMap<WorkerKey, Sender<(u64, AlertGroup)>>>>;
+
+// How long a worker waits for another group before it exits
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Delivers alerts to every receiver, retrying failures with exponential backoff.
+///
+/// Each (receiver, group key) pair has its own worker so a failing receiver or a
+/// backlog for one group does not hold up the rest, while alerts sharing a key
+/// (and therefore any one target) are always delivered in the order they were dispatched.
+/// Workers exit once they have been idle for a while and are started again on demand.
+pub struct AlertDispatcher {
+    config: CanaryAlertDeliveryConfig,
+    receivers: Vec<Receiver>,
+    metrics: Vec<Arc<dyn Metrics>>,
+    spool: Arc<Mutex<Spool>>,
+    workers: Workers,
+    idle_timeout: Duration,
+}
+
+impl AlertDispatcher {
+    pub fn start(
+        config: &CanaryAlertDeliveryConfig,
+        receivers: Vec<Receiver>,
+        metrics: Vec<Arc<dyn Metrics>>,
+    ) -> AlertDispatcher {
+        let spool = Spool::load(config.spool_path.as_ref().map(PathBuf::from));
+        let restored = spool.pending.values().cloned().collect::<Vec<_>>();
+
+        let dispatcher = AlertDispatcher {
+            config: config.clone(),
+            receivers,
+            metrics,
+            spool: Arc::new(Mutex::new(spool)),
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            idle_timeout: WORKER_IDLE_TIMEOUT,
+        };
+
+        if !restored.is_empty() {
+            info!(
+                "[alert.spool] restoring {} undelivered alerts",
+                restored.len()
+            );
+        }
+
+        for delivery in restored {
+            match dispatcher.receiver(&delivery.receiver) {
+                Some(receiver) => dispatcher.enqueue(&receiver, delivery.id, delivery.group),
+                None => {
+                    dead_letter(
+                        &dispatcher.config,
+                        &delivery.receiver,
+                        &delivery.group,
+                        "receiver is no longer configured",
+                    );
+                    dispatcher.lock_spool().remove(delivery.id);
+                }
+            }
+        }
+
+        dispatcher
+    }
+
+    /// Queues a group of alerts for delivery to every receiver.
+    /// Groups which do not fit in the queue are dead-lettered immediately.
+    pub fn dispatch(&self, group: AlertGroup) -> Result<(), String> {
+        let mut result = Ok(());
+
+        for receiver in &self.receivers {
+            let mut spool = self.lock_spool();
+
+            if spool.pending.len() >= self.config.queue_size {
+                drop(spool);
+                let reason = format!("queue is full ({} pending)", self.config.queue_size);
+                dead_letter(&self.config, &receiver.name, &group, &reason);
+                for metrics in &self.metrics {
+                    metrics.alert_delivery(&receiver.name, group.alerts.len(), false);
+                }
+                result = Err(reason);
+                continue;
+            }
+
+            let id = spool.add(&receiver.name, &group);
+            drop(spool);
+            self.enqueue(receiver, id, group.clone());
+        }
+
+        result
+    }
+
+    /// Number of deliveries that have not yet succeeded or been dead-lettered
+    pub fn pending(&self) -> usize {
+        self.lock_spool().pending.len()
+    }
+
+    fn receiver(&self, name: &str) -> Option<Receiver> {
+        self.receivers.iter().find(|r| r.name == name).cloned()
+    }
+
+    fn lock_spool(&self) -> MutexGuard<'_, Spool> {
+        self.spool.lock().expect("alert spool mutex is poisoned")
+    }
+
+    fn lock_workers(&self) -> MutexGuard<'_, HashMap<WorkerKey, Sender<(u64, AlertGroup)>>> {
+        self.workers
+            .lock()
+            .expect("alert workers mutex is poisoned")
+    }
+
+    fn enqueue(&self, receiver: &Receiver, id: u64, group: AlertGroup) {
+        let key = (receiver.name.clone(), group.key.clone());
+        let mut workers = self.lock_workers();
+
+        let worker = workers
+            .entry(key.clone())
+            .or_insert_with(|| self.spawn_worker(receiver, key.clone()));
+
+        // A worker which has died would otherwise swallow every group sent to it
+        if let Err(mpsc::SendError((id, group))) = worker.send((id, group)) {
+            info!(
+                "[alert.worker] {} worker for {} has stopped, restarting it",
+                receiver.name, group.key
+            );
+            let worker = self.spawn_worker(receiver, key.clone());
+            // The new worker holds its end of the channel until it has been idle for a while
+            let _ = worker.send((id, group));
+            workers.insert(key, worker);
+        }
+    }
+
+    fn spawn_worker(&self, receiver: &Receiver, key: WorkerKey) -> Sender<(u64, AlertGroup)> {
+        let (tx, rx) = mpsc::channel::<(u64, AlertGroup)>();
+        let config = self.config.clone();
+        let receiver = receiver.clone();
+        let metrics = self.metrics.clone();
+        let spool = self.spool.clone();
+        let workers = self.workers.clone();
+        let idle_timeout = self.idle_timeout;
+
+        thread::spawn(move || loop {
+            let (id, group) = match rx.recv_timeout(idle_timeout) {
+                Ok(next) => next,
+                Err(RecvTimeoutError::Timeout) => {
+                    // Groups are only sent while the map is locked, so once this
+                    // worker is removed nothing else can be queued for it
+                    let mut workers = workers.lock().expect("alert workers mutex is poisoned");
+                    match rx.try_recv() {
+                        Ok(next) => next,
+                        Err(_) => {
+                            workers.remove(&key);
+                            return;
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            let delivered = deliver(&config, &receiver, &group);
+            for metrics in &metrics {
+                metrics.alert_delivery(&receiver.name, group.alerts.len(), delivered);
+            }
+            spool
+                .lock()
+                .expect("alert spool mutex is poisoned")
+                .remove(id);
+        });
+
+        tx
+    }
+}
+
+// Blocks until the group has been delivered or has run out of retries.
+// Returns false if the group was dead-lettered.
+fn deliver(config: &CanaryAlertDeliveryConfig, receiver: &Receiver, group: &AlertGroup) -> bool {
+    let mut attempt = 0;
+    let mut sent = 0;
+
+    loop {
+        // An alerter which panics is retried like any other failure rather than taking
+        // down the worker, and with it every group queued behind this one
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            receiver.alerter.alert_group(group, &mut sent)
+        }))
+        .unwrap_or_else(|_| Err("alerter panicked".to_string()));
+
+        match result {
+            Ok(()) => {
+                info!(
+                    "[alert.delivered] {} alert delivered for {}",
+                    receiver.name, group.key
+                );
+                return true;
+            }
+            Err(err) if attempt < config.max_retries => {
+                attempt += 1;
+                let delay = backoff(config, attempt);
+                info!(
+                    "[alert.retry] {} alert for {} failed (attempt {} of {}), retrying in {:?}: {}",
+                    receiver.name,
+                    group.key,
+                    attempt,
+                    config.max_retries + 1,
+                    delay,
+                    err
+                );
+                thread::sleep(delay);
+            }
+            Err(err) => {
+                dead_letter(config, &receiver.name, group, &err);
+                return false;
+            }
+        }
+    }
+}
+
+fn backoff(config: &CanaryAlertDeliveryConfig, attempt: u32) -> Duration {
+    let multiplier = 2u64.saturating_pow(attempt.saturating_sub(1));
+    let delay_ms = config
+        .backoff_initial_ms
+        .saturating_mul(multiplier)
+        .min(config.backoff_max_ms);
+
+    Duration::from_millis(delay_ms)
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    receiver: &'a str,
+    reason: &'a str,
+    time: String,
+    group: &'a AlertGroup,
+}
+
+fn dead_letter(
+    config: &CanaryAlertDeliveryConfig,
+    receiver: &str,
+    group: &AlertGroup,
+    reason: &str,
+) {
+    info!(
+        "[alert.dead_letter] giving up on {} alert for {}: {}",
+        receiver, group.key, reason
+    );
+
+    if let Some(ref path) = config.dead_letter_path {
+        let entry = DeadLetter {
+            receiver,
+            reason,
+            time: format!("{}", time::now_utc().rfc3339()),
+            group,
+        };
+
+        let written = serde_json::to_string(&entry)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut f| writeln!(f, "{}", json))
+                    .map_err(|e| e.to_string())
+            });
+
+        if let Err(err) = written {
+            info!(
+                "[alert.dead_letter] failed to write dead letter to {}: {}",
+                path, err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerter::Alert;
+    use crate::tests::check;
+    use librcanary::Status;
+    use std::time::Instant;
+
+    // Fails the first `failures` attempts, then records what it was sent
+    struct FlakyAlerter {
+        failures: Mutex<u32>,
+        sent: Mutex<Vec<Status>>,
+    }
+
+    impl Alerter for FlakyAlerter {
+        fn alert(&self, alert: &Alert) -> Result<(), String> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err("SMTP server went away".to_string());
+            }
+
+            self.sent.lock().unwrap().push(alert.check.status.clone());
+            Ok(())
+        }
+    }
+
+    fn flaky(failures: u32) -> Arc<FlakyAlerter> {
+        Arc::new(FlakyAlerter {
+            failures: Mutex::new(failures),
+            sent: Mutex::new(vec![]),
+        })
+    }
+
+    fn alert(status: Status) -> AlertGroup {
+        AlertGroup::single(Alert {
+            check: check("foo", status),
+            previous_status: None,
+            outage_duration_s: None,
+        })
+    }
+
+    fn config() -> CanaryAlertDeliveryConfig {
+        CanaryAlertDeliveryConfig {
+            queue_size: 10,
+            max_retries: 3,
+            backoff_initial_ms: 1,
+            backoff_max_ms: 5,
+            spool_path: None,
+            dead_letter_path: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rcanary-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn wait_until_delivered(dispatcher: &AlertDispatcher) {
+        let started = Instant::now();
+        while dispatcher.pending() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn it_retries_and_preserves_order_per_target() {
+        let alerter = flaky(2);
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        dispatcher.dispatch(alert(Status::Okay)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(
+            vec![Status::Fire, Status::Okay],
+            *alerter.sent.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_does_not_resend_the_start_of_a_group() {
+        // Fails on the second alert of the group, then records what it was sent
+        struct FailsOnce(Mutex<Vec<Status>>);
+        impl Alerter for FailsOnce {
+            fn alert(&self, alert: &Alert) -> Result<(), String> {
+                let mut sent = self.0.lock().unwrap();
+                if sent.len() == 1 && alert.check.status == Status::Okay {
+                    sent.push(Status::Unknown);
+                    return Err("SMTP server went away".to_string());
+                }
+                sent.push(alert.check.status.clone());
+                Ok(())
+            }
+        }
+
+        let alerter = Arc::new(FailsOnce(Mutex::new(vec![])));
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+
+        let mut group = alert(Status::Fire);
+        group.alerts.extend(alert(Status::Okay).alerts);
+        dispatcher.dispatch(group).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(
+            vec![Status::Fire, Status::Unknown, Status::Okay],
+            *alerter.0.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_delivers_once_to_the_other_receivers_when_one_fails() {
+        let broken = flaky(10);
+        let working = flaky(0);
+        let receivers = vec![
+            Receiver {
+                name: "broken".to_string(),
+                alerter: broken.clone(),
+            },
+            Receiver {
+                name: "working".to_string(),
+                alerter: working.clone(),
+            },
+        ];
+        let dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert!(broken.sent.lock().unwrap().is_empty());
+        assert_eq!(6, *broken.failures.lock().unwrap());
+        assert_eq!(vec![Status::Fire], *working.sent.lock().unwrap());
+    }
+
+    #[test]
+    fn it_dead_letters_after_running_out_of_retries() {
+        let dead_letter_path = temp_path("dead-letter");
+        let alerter = flaky(10);
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let config = CanaryAlertDeliveryConfig {
+            dead_letter_path: Some(dead_letter_path.to_string_lossy().to_string()),
+            ..config()
+        };
+        let dispatcher = AlertDispatcher::start(&config, receivers, vec![]);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert!(alerter.sent.lock().unwrap().is_empty());
+        assert_eq!(6, *alerter.failures.lock().unwrap());
+        let dead_letters = fs::read_to_string(&dead_letter_path).unwrap();
+        assert_eq!(1, dead_letters.lines().count());
+        assert!(dead_letters.contains("SMTP server went away"));
+        let _ = fs::remove_file(&dead_letter_path);
+    }
+
+    #[test]
+    fn it_retries_alerters_which_panic() {
+        struct PanicsOnce(Mutex<bool>, Mutex<Vec<Status>>);
+        impl Alerter for PanicsOnce {
+            fn alert(&self, alert: &Alert) -> Result<(), String> {
+                if !std::mem::replace(&mut *self.0.lock().unwrap(), true) {
+                    panic!("failed to resolve SMTP server");
+                }
+                self.1.lock().unwrap().push(alert.check.status.clone());
+                Ok(())
+            }
+        }
+
+        let alerter = Arc::new(PanicsOnce(Mutex::new(false), Mutex::new(vec![])));
+        let receivers = vec![Receiver {
+            name: "panics".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        dispatcher.dispatch(alert(Status::Okay)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(vec![Status::Fire, Status::Okay], *alerter.1.lock().unwrap());
+    }
+
+    #[test]
+    fn it_restarts_workers_which_have_stopped() {
+        let alerter = flaky(0);
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+        let (stopped, _) = mpsc::channel();
+        dispatcher
+            .lock_workers()
+            .insert(("flaky".to_string(), "foo".to_string()), stopped);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(vec![Status::Fire], *alerter.sent.lock().unwrap());
+    }
+
+    #[test]
+    fn it_stops_idle_workers() {
+        let alerter = flaky(0);
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let mut dispatcher = AlertDispatcher::start(&config(), receivers, vec![]);
+        dispatcher.idle_timeout = Duration::from_millis(20);
+
+        dispatcher.dispatch(alert(Status::Fire)).unwrap();
+        wait_until_delivered(&dispatcher);
+        let started = Instant::now();
+        while !dispatcher.lock_workers().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        dispatcher.dispatch(alert(Status::Okay)).unwrap();
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(
+            vec![Status::Fire, Status::Okay],
+            *alerter.sent.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_rejects_alerts_when_the_queue_is_full() {
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: flaky(0),
+        }];
+        let config = CanaryAlertDeliveryConfig {
+            queue_size: 0,
+            ..config()
+        };
+        let dispatcher = AlertDispatcher::start(&config, receivers, vec![]);
+
+        assert!(dispatcher.dispatch(alert(Status::Fire)).is_err());
+    }
+
+    #[test]
+    fn it_restores_undelivered_alerts_from_the_spool() {
+        let spool_path = temp_path("spool");
+        let mut spool = Spool::load(Some(spool_path.clone()));
+        spool.add("flaky", &alert(Status::Fire));
+        spool.add("flaky", &alert(Status::Okay));
+
+        let alerter = flaky(0);
+        let receivers = vec![Receiver {
+            name: "flaky".to_string(),
+            alerter: alerter.clone(),
+        }];
+        let config = CanaryAlertDeliveryConfig {
+            spool_path: Some(spool_path.to_string_lossy().to_string()),
+            ..config()
+        };
+        let dispatcher = AlertDispatcher::start(&config, receivers, vec![]);
+        wait_until_delivered(&dispatcher);
+
+        assert_eq!(
+            vec![Status::Fire, Status::Okay],
+            *alerter.sent.lock().unwrap()
+        );
+        assert_eq!("[]", fs::read_to_string(&spool_path).unwrap());
+        let _ = fs::remove_file(&spool_path);
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let config = CanaryAlertDeliveryConfig {
+            backoff_initial_ms: 100,
+            backoff_max_ms: 350,
+            ..config()
+        };
+
+        assert_eq!(Duration::from_millis(100), backoff(&config, 1));
+        assert_eq!(Duration::from_millis(200), backoff(&config, 2));
+        assert_eq!(Duration::from_millis(350), backoff(&config, 3));
+        assert_eq!(Duration::from_millis(350), backoff(&config, 64));
+    }
+}
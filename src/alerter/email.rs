@@ -1,5 +1,6 @@
-use super::Alerter;
-use librcanary::{CanaryCheck, CanaryConfig, Status};
+use super::template::{render_email, render_group_email, RenderedEmail};
+use super::{Alert, AlertGroup, Alerter};
+use librcanary::CanaryEmailAlertConfig;
 
 use lettre::builder::Email;
 use lettre::smtp::authentication::{Credentials, Mechanism};
@@ -8,33 +9,29 @@ use lettre::smtp::ConnectionReuseParameters;
 use lettre::{SmtpClient, Transport};
 use log::info;
 
-pub struct EmailAlerter<'a> {
-    pub config: &'a CanaryConfig,
+pub struct EmailAlerter {
+    pub config: CanaryEmailAlertConfig,
 }
 
-impl<'a> Alerter for EmailAlerter<'a> {
-    fn alert(&self, result: &CanaryCheck) -> Result<(), String> {
-        let body = match result.status {
-            Status::Fire => format!("🔥 Something has gone terribly wrong:\n{:#?}", result),
-            Status::Unknown => format!("🚨 Something is probably wrong:\n{:#?}", result),
-            Status::Okay => format!("🙇 Everything is now okay:\n{:#?}", result),
-        };
-
-        let email_config = match self.config.alert.email {
-            Some(ref config) => config,
-            None => return Err("email alerts configuration missing".to_string()),
-        };
+impl EmailAlerter {
+    fn send(&self, rendered: RenderedEmail, description: &str) -> Result<(), String> {
+        let email_config = &self.config;
 
         let email = Email::builder()
             .to(&*email_config.alert_email)
             .from(&*email_config.smtp_username)
-            .subject(&format!("rcanary alert for {}", &result.target.host))
-            .text(&body)
+            .subject(&rendered.subject)
+            .alternative(&rendered.html, &rendered.text)
             .build()
             .map_err(|e| e.to_string())?;
 
         let mut mailer = SmtpClient::new_simple(&*email_config.smtp_server)
-            .unwrap()
+            .map_err(|err| {
+                format!(
+                    "[alert.failure] failed to connect to SMTP server {}: {}",
+                    email_config.smtp_server, err
+                )
+            })?
             .hello_name(ClientId::Domain("localhost".to_string()))
             .credentials(Credentials::new(
                 email_config.smtp_username.clone(),
@@ -49,7 +46,7 @@ impl<'a> Alerter for EmailAlerter<'a> {
             Ok(_) => {
                 info!(
                     "[alert.success] email alert sent to {} for {}",
-                    email_config.alert_email, &result.target.host
+                    email_config.alert_email, description
                 );
                 Ok(())
             }
@@ -61,3 +58,20 @@ impl<'a> Alerter for EmailAlerter<'a> {
         }
     }
 }
+
+impl Alerter for EmailAlerter {
+    fn alert(&self, alert: &Alert) -> Result<(), String> {
+        let rendered = render_email(alert, &self.config)?;
+        self.send(rendered, &alert.check.target.host)
+    }
+
+    // Groups are sent as a single email, so retries resend all of it
+    fn alert_group(&self, group: &AlertGroup, _sent: &mut usize) -> Result<(), String> {
+        if !group.digest && group.alerts.len() == 1 {
+            return self.alert(&group.alerts[0]);
+        }
+
+        let rendered = render_group_email(group, &self.config)?;
+        self.send(rendered, &group.key)
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use librcanary::{CanaryAlertGroupingConfig, CanaryTarget, Status};
+
+use super::{Alert, AlertGroup};
+
+struct PendingGroup {
+    opened_at: Instant,
+    alerts: Vec<Alert>,
+}
+
+/// Batches alerts raised within a window of each other into one notification per group,
+/// and periodically sends digests of targets which are still failing.
+pub struct AlertGrouper {
+    config: CanaryAlertGroupingConfig,
+    pending: BTreeMap<String, PendingGroup>,
+    // Latest alert for every failing target, keyed by target name
+    failing: BTreeMap<String, Alert>,
+    last_digest: Instant,
+}
+
+impl AlertGrouper {
+    pub fn new(config: &CanaryAlertGroupingConfig) -> AlertGrouper {
+        AlertGrouper {
+            config: config.clone(),
+            pending: BTreeMap::new(),
+            failing: BTreeMap::new(),
+            last_digest: Instant::now(),
+        }
+    }
+
+    /// Records the latest state of a target, queueing its alert if `notify` is set.
+    /// Failing targets are listed in digests while `alerted` is set, i.e. their failure was
+    /// alerted on and they are not blocked, silenced or acknowledged since.
+    /// Returns groups that are ready to be sent immediately.
+    pub fn record(
+        &mut self,
+        alert: Alert,
+        notify: bool,
+        alerted: bool,
+        now: Instant,
+    ) -> Vec<AlertGroup> {
+        let name = alert.check.target.name.clone();
+        if alerted && alert.check.status != Status::Okay {
+            self.failing.insert(name, alert.clone());
+        } else {
+            self.failing.remove(&name);
+        }
+
+        if !notify {
+            return vec![];
+        }
+
+        if self.config.window_s == 0 {
+            return vec![AlertGroup::single(alert)];
+        }
+
+        let key = group_key(&self.config.group_by, &alert.check.target);
+        self.pending
+            .entry(key)
+            .or_insert_with(|| PendingGroup {
+                opened_at: now,
+                alerts: vec![],
+            })
+            .alerts
+            .push(alert);
+
+        vec![]
+    }
+
+    /// Stops listing a deleted or paused target in digests
+    pub fn forget(&mut self, name: &str) {
+        self.failing.remove(name);
+    }
+
+    /// Returns groups whose window has closed, and a digest if one is due
+    pub fn due(&mut self, now: Instant) -> Vec<AlertGroup> {
+        let window = Duration::from_secs(self.config.window_s);
+        let closed = self
+            .pending
+            .iter()
+            .filter(|(_, group)| now.duration_since(group.opened_at) >= window)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let mut groups = closed
+            .into_iter()
+            .filter_map(|key| {
+                self.pending.remove(&key).map(|group| AlertGroup {
+                    key,
+                    digest: false,
+                    alerts: group.alerts,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let digest_interval = Duration::from_secs(self.config.digest_interval_s);
+        if self.config.digest_interval_s > 0
+            && now.duration_since(self.last_digest) >= digest_interval
+        {
+            self.last_digest = now;
+
+            if !self.failing.is_empty() {
+                groups.push(AlertGroup {
+                    key: "digest".to_string(),
+                    digest: true,
+                    alerts: self.failing.values().cloned().collect(),
+                });
+            }
+        }
+
+        groups
+    }
+}
+
+// Groups by tag, or by the value of a target label
+fn group_key(group_by: &str, target: &CanaryTarget) -> String {
+    let key = if group_by == "tag" {
+        target.tag.clone()
+    } else {
+        target.labels.get(group_by).cloned()
+    };
+
+    key.unwrap_or_else(|| "ungrouped".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check, target};
+    use librcanary::CanaryCheck;
+
+    fn alert(name: &str, tag: Option<&str>, status: Status) -> Alert {
+        let mut target = target();
+        target.name = name.to_string();
+        target.tag = tag.map(|t| t.to_string());
+        target
+            .labels
+            .insert("team".to_string(), "payments".to_string());
+
+        Alert {
+            check: CanaryCheck {
+                target,
+                ..check(name, status)
+            },
+            previous_status: None,
+            outage_duration_s: None,
+        }
+    }
+
+    fn config(window_s: u64, group_by: &str, digest_interval_s: u64) -> CanaryAlertGroupingConfig {
+        CanaryAlertGroupingConfig {
+            window_s,
+            group_by: group_by.to_string(),
+            digest_interval_s,
+        }
+    }
+
+    fn names(group: &AlertGroup) -> Vec<&str> {
+        group
+            .alerts
+            .iter()
+            .map(|a| a.check.target.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn it_sends_alerts_immediately_without_a_window() {
+        let mut grouper = AlertGrouper::new(&config(0, "tag", 0));
+
+        let actual = grouper.record(
+            alert("a", Some("db"), Status::Fire),
+            true,
+            true,
+            Instant::now(),
+        );
+
+        assert_eq!(1, actual.len());
+        assert_eq!("a", actual[0].key);
+    }
+
+    #[test]
+    fn it_batches_alerts_by_tag_within_the_window() {
+        let mut grouper = AlertGrouper::new(&config(30, "tag", 0));
+        let start = Instant::now();
+
+        assert!(grouper
+            .record(alert("a", Some("db"), Status::Fire), true, true, start)
+            .is_empty());
+        grouper.record(alert("b", Some("web"), Status::Fire), true, true, start);
+        grouper.record(alert("c", Some("db"), Status::Fire), true, true, start);
+        grouper.record(alert("d", Some("db"), Status::Fire), false, false, start);
+
+        assert!(grouper.due(start + Duration::from_secs(29)).is_empty());
+
+        let actual = grouper.due(start + Duration::from_secs(30));
+        assert_eq!(2, actual.len());
+        assert_eq!("db", actual[0].key);
+        assert_eq!(vec!["a", "c"], names(&actual[0]));
+        assert_eq!("web", actual[1].key);
+        assert!(grouper.due(start + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn it_groups_by_label() {
+        let mut grouper = AlertGrouper::new(&config(1, "team", 0));
+        let start = Instant::now();
+
+        grouper.record(alert("a", Some("db"), Status::Fire), true, true, start);
+        grouper.record(alert("b", None, Status::Fire), true, true, start);
+
+        let actual = grouper.due(start + Duration::from_secs(1));
+        assert_eq!(1, actual.len());
+        assert_eq!("payments", actual[0].key);
+    }
+
+    #[test]
+    fn it_sends_digests_of_failing_targets() {
+        let mut grouper = AlertGrouper::new(&config(0, "tag", 60));
+        let start = Instant::now();
+
+        grouper.record(alert("a", None, Status::Fire), true, true, start);
+        grouper.record(alert("b", None, Status::Fire), true, true, start);
+        grouper.record(alert("b", None, Status::Okay), true, false, start);
+        grouper.record(alert("c", None, Status::Unknown), false, true, start);
+        // Never alerted on, e.g. blocked by a failing dependency or acknowledged
+        grouper.record(alert("d", None, Status::Fire), false, false, start);
+
+        let actual = grouper.due(start + Duration::from_secs(60));
+        assert_eq!(1, actual.len());
+        assert!(actual[0].digest);
+        assert_eq!(vec!["a", "c"], names(&actual[0]));
+
+        assert!(grouper.due(start + Duration::from_secs(90)).is_empty());
+
+        // Deleted and paused targets are left out of later digests, as are muted ones
+        grouper.forget("a");
+        let actual = grouper.due(start + Duration::from_secs(120));
+        assert_eq!(vec!["c"], names(&actual[0]));
+
+        grouper.record(alert("c", None, Status::Unknown), false, false, start);
+        assert!(grouper.due(start + Duration::from_secs(180)).is_empty());
+    }
+}
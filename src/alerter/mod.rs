@@ -1,8 +1,53 @@
-use librcanary::CanaryCheck;
+use serde::{Deserialize, Serialize};
+
+use librcanary::{CanaryCheck, Status};
 
 pub mod alert;
+pub mod dispatch;
 pub mod email;
+pub mod group;
+pub mod silence;
+pub mod template;
+
+/// A check result worth alerting on, along with the state it moved away from
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Alert {
+    pub check: CanaryCheck,
+    pub previous_status: Option<Status>,
+    pub outage_duration_s: Option<u64>,
+}
+
+/// Alerts which are sent together as a single notification
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AlertGroup {
+    /// Alerts sharing a key are delivered in order
+    pub key: String,
+    /// Set for periodic reminders of targets which are still failing
+    pub digest: bool,
+    pub alerts: Vec<Alert>,
+}
+
+impl AlertGroup {
+    pub fn single(alert: Alert) -> AlertGroup {
+        AlertGroup {
+            key: alert.check.target.name.clone(),
+            digest: false,
+            alerts: vec![alert],
+        }
+    }
+}
 
 pub trait Alerter {
-    fn alert(&self, result: &CanaryCheck) -> Result<(), String>;
+    fn alert(&self, alert: &Alert) -> Result<(), String>;
+
+    /// Sends a group of alerts. Alerters which cannot combine alerts send them one at a time,
+    /// counting them in `sent` so that retries of a group which failed part way through skip
+    /// those which already went out.
+    fn alert_group(&self, group: &AlertGroup, sent: &mut usize) -> Result<(), String> {
+        for alert in &group.alerts[*sent..] {
+            self.alert(alert)?;
+            *sent += 1;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use log::info;
+use serde::Serialize;
+use time::Timespec;
+
+use crate::alerter::alert::parse_check_time;
+use crate::storage::Storage;
+use librcanary::{CanaryAcknowledgement, CanaryCheck, CanarySilence, CanaryTarget, Status};
+
+/// Longest a silence can last, so that its end always fits in a timestamp
+pub const MAX_SILENCE_S: u64 = 366 * 24 * 60 * 60;
+
+/// Acknowledgements and silences, which keep alerts for a target quiet.
+/// Both are saved to storage if it is enabled.
+#[derive(Default)]
+pub struct AlertControls {
+    acknowledgements: BTreeMap<String, CanaryAcknowledgement>,
+    silences: BTreeMap<u64, (Timespec, CanarySilence)>,
+    failing: HashSet<String>,
+    next_silence_id: u64,
+    storage: Option<Arc<Storage>>,
+}
+
+/// Everything websocket clients need to show who owns an outage
+#[derive(Serialize, Debug)]
+pub struct AlertControlsState {
+    pub acknowledgements: Vec<CanaryAcknowledgement>,
+    pub silences: Vec<CanarySilence>,
+}
+
+impl AlertControls {
+    /// Picks up acknowledgements and active silences from storage
+    pub fn new(storage: Option<Arc<Storage>>) -> Result<AlertControls, String> {
+        let mut controls = AlertControls::default();
+
+        if let Some(ref storage) = storage {
+            for acknowledgement in storage.acknowledgements()? {
+                controls
+                    .acknowledgements
+                    .insert(acknowledgement.target.clone(), acknowledgement);
+            }
+
+            // Ids of silences which have ended are not reused, they are only deleted later
+            controls.next_silence_id = storage.last_silence_id()?.map_or(0, |id| id + 1);
+            for silence in storage.silences(time::get_time())? {
+                controls
+                    .silences
+                    .insert(silence.id, (parse_check_time(&silence.ends_at), silence));
+            }
+        }
+
+        controls.storage = storage;
+        Ok(controls)
+    }
+
+    /// Records the latest status of a target. Acknowledgements end once the target recovers.
+    /// Returns true if an acknowledgement was removed.
+    pub fn update(&mut self, result: &CanaryCheck) -> bool {
+        let name = &result.target.name;
+
+        if result.status == Status::Okay {
+            self.failing.remove(name);
+            self.unacknowledge(name).is_some()
+        } else {
+            self.failing.insert(name.clone());
+            false
+        }
+    }
+
+    /// Drops the state of a deleted or paused target. Returns true if an acknowledgement
+    /// was removed.
+    pub fn forget(&mut self, name: &str) -> bool {
+        self.failing.remove(name);
+        self.unacknowledge(name).is_some()
+    }
+
+    /// True if alerts for the target should not be sent
+    pub fn is_quiet(&self, target: &CanaryTarget, now: Timespec) -> bool {
+        self.acknowledgements.contains_key(&target.name)
+            || self.silences.values().any(|(ends_at, silence)| {
+                now < *ends_at
+                    && (silence.target.as_ref() == Some(&target.name)
+                        || silence.tag.is_some() && silence.tag == target.tag)
+            })
+    }
+
+    pub fn acknowledge(
+        &mut self,
+        target: &str,
+        author: &str,
+        comment: &str,
+        now: Timespec,
+    ) -> Result<CanaryAcknowledgement, String> {
+        if !self.failing.contains(target) {
+            return Err(format!("target `{}` is not failing", target));
+        }
+
+        let acknowledgement = CanaryAcknowledgement {
+            author: author.to_string(),
+            comment: comment.to_string(),
+            target: target.to_string(),
+            time: format_time(now),
+        };
+        self.acknowledgements
+            .insert(target.to_string(), acknowledgement.clone());
+        self.save(|storage| storage.save_acknowledgement(&acknowledgement));
+
+        Ok(acknowledgement)
+    }
+
+    /// Returns the removed acknowledgement, if the target had one
+    pub fn unacknowledge(&mut self, target: &str) -> Option<CanaryAcknowledgement> {
+        let acknowledgement = self.acknowledgements.remove(target)?;
+        self.save(|storage| storage.remove_acknowledgement(target));
+        Some(acknowledgement)
+    }
+
+    pub fn silence(
+        &mut self,
+        target: Option<String>,
+        tag: Option<String>,
+        author: &str,
+        comment: &str,
+        duration_s: u64,
+        now: Timespec,
+    ) -> Result<CanarySilence, String> {
+        if duration_s == 0 || duration_s > MAX_SILENCE_S {
+            return Err(format!(
+                "`duration_s` must be between 1 and {}",
+                MAX_SILENCE_S
+            ));
+        }
+        let ends_at = i64::try_from(duration_s)
+            .ok()
+            .and_then(|duration_s| now.sec.checked_add(duration_s))
+            .map(|sec| Timespec::new(sec, now.nsec))
+            .ok_or_else(|| "`duration_s` is out of range".to_string())?;
+        let silence = CanarySilence {
+            author: author.to_string(),
+            comment: comment.to_string(),
+            ends_at: format_time(ends_at),
+            id: self.next_silence_id,
+            starts_at: format_time(now),
+            tag,
+            target,
+        };
+        self.next_silence_id += 1;
+
+        self.silences.insert(silence.id, (ends_at, silence.clone()));
+        self.save(|storage| storage.save_silence(&silence));
+        Ok(silence)
+    }
+
+    /// Ends a silence early. Returns the silence if it was still active.
+    pub fn expire(&mut self, id: u64) -> Option<CanarySilence> {
+        let (_, silence) = self.silences.remove(&id)?;
+        self.save(|storage| storage.remove_silence(id));
+        Some(silence)
+    }
+
+    pub fn state(&mut self, now: Timespec) -> AlertControlsState {
+        // Silences are only listed while they are active
+        self.silences.retain(|_, (ends_at, _)| now < *ends_at);
+
+        AlertControlsState {
+            acknowledgements: self.acknowledgements.values().cloned().collect(),
+            silences: self.silences.values().map(|(_, s)| s.clone()).collect(),
+        }
+    }
+
+    fn save<F>(&self, write: F)
+    where
+        F: FnOnce(&Storage) -> Result<(), String>,
+    {
+        if let Some(ref storage) = self.storage {
+            if let Err(err) = write(storage) {
+                info!("[storage.error] failed to save alert controls: {}", err);
+            }
+        }
+    }
+}
+
+fn format_time(t: Timespec) -> String {
+    format!("{}", time::at_utc(t).rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::target;
+    use librcanary::CanaryStorageConfig;
+
+    fn check(status: Status) -> CanaryCheck {
+        crate::tests::check("foo", status)
+    }
+
+    fn at(seconds: i64) -> Timespec {
+        Timespec::new(seconds, 0)
+    }
+
+    #[test]
+    fn it_only_acknowledges_failing_targets() {
+        let mut controls = AlertControls::new(None).unwrap();
+
+        assert!(controls.acknowledge("foo", "alice", "", at(0)).is_err());
+
+        controls.update(&check(Status::Fire));
+        let actual = controls
+            .acknowledge("foo", "alice", "on it", at(0))
+            .unwrap();
+
+        assert_eq!("alice", actual.author);
+        assert_eq!("1970-01-01T00:00:00Z", actual.time);
+        assert!(controls.is_quiet(&target(), at(0)));
+    }
+
+    #[test]
+    fn it_clears_acknowledgements_on_recovery() {
+        let mut controls = AlertControls::new(None).unwrap();
+        controls.update(&check(Status::Fire));
+        controls
+            .acknowledge("foo", "alice", "on it", at(0))
+            .unwrap();
+
+        assert_eq!(false, controls.update(&check(Status::Unknown)));
+        assert_eq!(true, controls.update(&check(Status::Okay)));
+        assert!(!controls.is_quiet(&target(), at(0)));
+    }
+
+    #[test]
+    fn it_forgets_removed_targets() {
+        let mut controls = AlertControls::new(None).unwrap();
+        controls.update(&check(Status::Fire));
+        controls
+            .acknowledge("foo", "alice", "on it", at(0))
+            .unwrap();
+
+        assert_eq!(true, controls.forget("foo"));
+        assert!(!controls.is_quiet(&target(), at(0)));
+        assert!(controls.acknowledge("foo", "alice", "", at(0)).is_err());
+    }
+
+    #[test]
+    fn it_silences_by_target_and_tag_until_expiry() {
+        let mut controls = AlertControls::new(None).unwrap();
+        let by_name = controls
+            .silence(Some("foo".to_string()), None, "bob", "", 60, at(0))
+            .unwrap();
+        controls
+            .silence(None, Some("tag".to_string()), "bob", "", 120, at(0))
+            .unwrap();
+
+        assert!(controls.is_quiet(&target(), at(90)));
+        assert!(!controls.is_quiet(&target(), at(120)));
+
+        let mut untagged = target();
+        untagged.tag = None;
+        assert!(controls.is_quiet(&untagged, at(30)));
+        assert_eq!(Some(by_name), controls.expire(0));
+        assert!(!controls.is_quiet(&untagged, at(30)));
+    }
+
+    #[test]
+    fn it_rejects_silences_which_are_too_long() {
+        let mut controls = AlertControls::new(None).unwrap();
+        let target = Some("foo".to_string());
+
+        assert!(controls
+            .silence(target.clone(), None, "bob", "", 0, at(0))
+            .is_err());
+        assert!(controls
+            .silence(target.clone(), None, "bob", "", MAX_SILENCE_S, at(0))
+            .is_ok());
+        assert!(controls
+            .silence(target.clone(), None, "bob", "", MAX_SILENCE_S + 1, at(0))
+            .is_err());
+        assert!(controls
+            .silence(target, None, "bob", "", u64::MAX, at(0))
+            .is_err());
+        assert!(controls
+            .silence(None, None, "bob", "", 60, at(i64::MAX))
+            .is_err());
+    }
+
+    #[test]
+    fn it_lists_active_silences() {
+        let mut controls = AlertControls::new(None).unwrap();
+        controls
+            .silence(Some("foo".to_string()), None, "bob", "", 60, at(0))
+            .unwrap();
+        controls
+            .silence(Some("bar".to_string()), None, "bob", "", 10, at(0))
+            .unwrap();
+
+        let actual = controls.state(at(30));
+
+        assert_eq!(1, actual.silences.len());
+        assert_eq!(Some("foo".to_string()), actual.silences[0].target);
+    }
+
+    #[test]
+    fn it_does_not_reuse_ids_of_ended_silences() {
+        let storage = Arc::new(
+            Storage::open(&CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+        let now = time::get_time();
+
+        let mut controls = AlertControls::new(Some(storage.clone())).unwrap();
+        controls
+            .silence(None, Some("tag".to_string()), "bob", "", 3600, now)
+            .unwrap();
+        let ended = Timespec::new(now.sec - 120, 0);
+        controls
+            .silence(None, Some("tag".to_string()), "bob", "", 60, ended)
+            .unwrap();
+
+        let mut restored = AlertControls::new(Some(storage.clone())).unwrap();
+        assert_eq!(1, restored.state(now).silences.len());
+        assert_eq!(
+            2,
+            restored.silence(None, None, "bob", "", 60, now).unwrap().id
+        );
+    }
+
+    #[test]
+    fn it_restores_acknowledgements_and_silences_from_storage() {
+        let storage = Arc::new(
+            Storage::open(&CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+        let now = time::get_time();
+
+        let mut controls = AlertControls::new(Some(storage.clone())).unwrap();
+        controls.update(&check(Status::Fire));
+        controls.acknowledge("foo", "alice", "on it", now).unwrap();
+        controls
+            .silence(None, Some("tag".to_string()), "bob", "", 60, now)
+            .unwrap();
+        controls
+            .silence(None, Some("tag".to_string()), "bob", "", 60, now)
+            .unwrap();
+        controls
+            .silence(Some("bar".to_string()), None, "bob", "", 60, now)
+            .unwrap();
+        controls.expire(1);
+
+        let mut restored = AlertControls::new(Some(storage.clone())).unwrap();
+        let actual = restored.state(now);
+
+        assert_eq!("alice", actual.acknowledgements[0].author);
+        assert_eq!(
+            vec![0, 2],
+            actual.silences.iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            3,
+            restored.silence(None, None, "bob", "", 60, now).unwrap().id
+        );
+
+        restored.update(&check(Status::Okay));
+        assert!(AlertControls::new(Some(storage))
+            .unwrap()
+            .state(now)
+            .acknowledgements
+            .is_empty());
+    }
+}
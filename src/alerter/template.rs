@@ -0,0 +1,355 @@
+use handlebars::{no_escape, Handlebars};
+use serde::Serialize;
+
+use librcanary::{CanaryCheck, CanaryCheckElement, CanaryEmailAlertConfig, CanaryTarget, Status};
+
+use super::{Alert, AlertGroup};
+
+pub const DEFAULT_SUBJECT_TEMPLATE: &str =
+    "[rcanary] {{icon}} {{target.name}} is {{status}}{{#if previous_status}} (was {{previous_status}}){{/if}}";
+
+pub const DEFAULT_TEXT_TEMPLATE: &str = "{{icon}} {{summary}}
+
+Target:  {{target.name}} ({{target.host}})
+Status:  {{status}}{{#if previous_status}} (was {{previous_status}}){{/if}}
+Code:    {{status_code}}
+Reason:  {{status_reason}}
+Latency: {{latency_ms}}ms
+Time:    {{time}}
+{{#if outage_duration}}Outage:  {{outage_duration}}
+{{/if}}{{#if elements}}
+Addresses:
+{{#each elements}}  {{address}}: {{status_code}} in {{latency_ms}}ms{{#if error}} ({{error}}){{/if}}
+{{/each}}{{/if}}";
+
+pub const DEFAULT_HTML_TEMPLATE: &str = "<html>
+<body style=\"font-family: sans-serif;\">
+<h2>{{icon}} {{summary}}</h2>
+<table cellpadding=\"4\">
+<tr><th align=\"left\">Target</th><td><a href=\"{{target.host}}\">{{target.name}}</a></td></tr>
+<tr><th align=\"left\">Status</th><td>{{status}}{{#if previous_status}} (was {{previous_status}}){{/if}}</td></tr>
+<tr><th align=\"left\">Code</th><td>{{status_code}}</td></tr>
+<tr><th align=\"left\">Reason</th><td>{{status_reason}}</td></tr>
+<tr><th align=\"left\">Latency</th><td>{{latency_ms}}ms</td></tr>
+<tr><th align=\"left\">Time</th><td>{{time}}</td></tr>
+{{#if outage_duration}}<tr><th align=\"left\">Outage</th><td>{{outage_duration}}</td></tr>
+{{/if}}</table>
+{{#if elements}}<h3>Addresses</h3>
+<table cellpadding=\"4\">
+<tr><th align=\"left\">Address</th><th align=\"left\">Code</th><th align=\"left\">Latency</th><th align=\"left\">Error</th></tr>
+{{#each elements}}<tr><td>{{address}}</td><td>{{status_code}}</td><td>{{latency_ms}}ms</td><td>{{error}}</td></tr>
+{{/each}}</table>
+{{/if}}</body>
+</html>
+";
+
+pub const DEFAULT_GROUP_SUBJECT_TEMPLATE: &str =
+    "[rcanary] {{#if digest}}{{count}} targets still failing{{else}}{{count}} alerts for {{key}}{{/if}}";
+
+pub const DEFAULT_GROUP_TEXT_TEMPLATE: &str = "{{#if digest}}These targets are still failing:{{else}}Alerts for {{key}}:{{/if}}
+
+{{#each alerts}}{{icon}} {{target.name}} ({{target.host}}) is {{status}}{{#if previous_status}} (was {{previous_status}}){{/if}}: {{status_code}}{{#if outage_duration}}, outage {{outage_duration}}{{/if}}
+{{/each}}";
+
+pub const DEFAULT_GROUP_HTML_TEMPLATE: &str = "<html>
+<body style=\"font-family: sans-serif;\">
+<h2>{{#if digest}}These targets are still failing{{else}}Alerts for {{key}}{{/if}}</h2>
+<ul>
+{{#each alerts}}<li>{{icon}} <a href=\"{{target.host}}\">{{target.name}}</a> is <b>{{status}}</b>{{#if previous_status}} (was {{previous_status}}){{/if}}: {{status_code}}{{#if outage_duration}}, outage {{outage_duration}}{{/if}}</li>
+{{/each}}</ul>
+</body>
+</html>
+";
+
+/// Values available to alert templates
+#[derive(Serialize)]
+struct AlertTemplateContext<'a> {
+    elements: &'a [CanaryCheckElement],
+    icon: &'static str,
+    latency_ms: u64,
+    outage_duration: Option<String>,
+    outage_duration_s: Option<u64>,
+    previous_status: Option<&'a Status>,
+    status_code: &'a str,
+    status_reason: &'a str,
+    status: &'a Status,
+    summary: &'static str,
+    target: &'a CanaryTarget,
+    time: &'a str,
+}
+
+/// Values available to group and digest templates
+#[derive(Serialize)]
+struct AlertGroupTemplateContext<'a> {
+    alerts: Vec<AlertTemplateContext<'a>>,
+    count: usize,
+    digest: bool,
+    key: &'a str,
+}
+
+impl<'a> AlertTemplateContext<'a> {
+    fn new(alert: &'a Alert) -> AlertTemplateContext<'a> {
+        let check = &alert.check;
+        let (icon, summary) = match check.status {
+            Status::Fire => ("🔥", "Something has gone terribly wrong"),
+            Status::Unknown => ("🚨", "Something is probably wrong"),
+            Status::Okay => ("🙇", "Everything is now okay"),
+        };
+
+        AlertTemplateContext {
+            elements: &check.elements,
+            icon,
+            latency_ms: check.latency_ms,
+            outage_duration: alert.outage_duration_s.map(format_duration),
+            outage_duration_s: alert.outage_duration_s,
+            previous_status: alert.previous_status.as_ref(),
+            status_code: &check.status_code,
+            status_reason: &check.status_reason,
+            status: &check.status,
+            summary,
+            target: &check.target,
+            time: &check.time,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+pub fn render_email(
+    alert: &Alert,
+    config: &CanaryEmailAlertConfig,
+) -> Result<RenderedEmail, String> {
+    render(
+        &AlertTemplateContext::new(alert),
+        (&config.subject_template, DEFAULT_SUBJECT_TEMPLATE),
+        (&config.text_template, DEFAULT_TEXT_TEMPLATE),
+        (&config.html_template, DEFAULT_HTML_TEMPLATE),
+    )
+}
+
+pub fn render_group_email(
+    group: &AlertGroup,
+    config: &CanaryEmailAlertConfig,
+) -> Result<RenderedEmail, String> {
+    let context = AlertGroupTemplateContext {
+        alerts: group.alerts.iter().map(AlertTemplateContext::new).collect(),
+        count: group.alerts.len(),
+        digest: group.digest,
+        key: &group.key,
+    };
+
+    render(
+        &context,
+        (
+            &config.group_subject_template,
+            DEFAULT_GROUP_SUBJECT_TEMPLATE,
+        ),
+        (&config.group_text_template, DEFAULT_GROUP_TEXT_TEMPLATE),
+        (&config.group_html_template, DEFAULT_GROUP_HTML_TEMPLATE),
+    )
+}
+
+/// Renders the configured templates against a sample alert, so that broken templates are
+/// caught at startup rather than when an alert has to go out
+pub fn validate(config: &CanaryEmailAlertConfig) -> Result<(), String> {
+    let alert = Alert {
+        check: CanaryCheck {
+            alert: true,
+            blocked_by: vec![],
+            elements: vec![],
+            latency_ms: 0,
+            need_to_alert: true,
+            status_code: "500 Internal Server Error".to_string(),
+            status_reason: "sample".to_string(),
+            status: Status::Fire,
+            target: CanaryTarget {
+                alert: true,
+                basic_auth: None,
+                depends_on: vec![],
+                host: "http://example.com".to_string(),
+                interval_s: 60,
+                labels: Default::default(),
+                name: "sample".to_string(),
+                paused: false,
+                tag_metric: None,
+                tag: None,
+            },
+            time: "2016-10-14T08:00:00Z".to_string(),
+            traceparent: None,
+        },
+        previous_status: Some(Status::Okay),
+        outage_duration_s: Some(60),
+    };
+
+    render_email(&alert, config)?;
+    for digest in &[false, true] {
+        let group = AlertGroup {
+            key: "sample".to_string(),
+            digest: *digest,
+            alerts: vec![alert.clone(), alert.clone()],
+        };
+        render_group_email(&group, config)?;
+    }
+
+    Ok(())
+}
+
+// Each template is a pair of (configured template, built-in default)
+fn render<T: Serialize>(
+    context: &T,
+    subject: (&Option<String>, &str),
+    text: (&Option<String>, &str),
+    html: (&Option<String>, &str),
+) -> Result<RenderedEmail, String> {
+    let mut plain = Handlebars::new();
+    plain.register_escape_fn(no_escape);
+    let escaped = Handlebars::new();
+
+    let render = |registry: &Handlebars, (template, default): (&Option<String>, &str)| {
+        registry
+            .render_template(template.as_ref().map_or(default, |t| t.as_str()), context)
+            .map_err(|err| format!("failed to render alert template: {}", err))
+    };
+
+    Ok(RenderedEmail {
+        // Subjects cannot span multiple lines
+        subject: render(&plain, subject)?.replace(['\r', '\n'], " "),
+        text: render(&plain, text)?,
+        html: render(&escaped, html)?,
+    })
+}
+
+// Formats a duration as `1h 2m 3s`, omitting leading zero units
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::check;
+
+    fn fire_alert() -> Alert {
+        Alert {
+            check: CanaryCheck {
+                elements: vec![CanaryCheckElement {
+                    address: "127.0.0.1".to_string(),
+                    error: Some("bad HTTP status 500 Internal Server Error".to_string()),
+                    latency_ms: 12,
+                    phases: vec![],
+                    status_code: 500,
+                }],
+                latency_ms: 15,
+                status_code: "500 Internal Server Error".to_string(),
+                status_reason: "<none>".to_string(),
+                ..check("foo", Status::Fire)
+            },
+            previous_status: Some(Status::Okay),
+            outage_duration_s: Some(3723),
+        }
+    }
+
+    #[test]
+    fn it_renders_default_templates() {
+        let actual = render_email(&fire_alert(), &CanaryEmailAlertConfig::default()).unwrap();
+
+        assert_eq!("[rcanary] 🔥 foo is Fire (was Okay)", actual.subject);
+        assert!(actual.text.contains("Outage:  1h 2m 3s\n"));
+        assert!(actual
+            .text
+            .contains("  127.0.0.1: 500 in 12ms (bad HTTP status 500 Internal Server Error)\n"));
+        assert!(actual.text.contains("Reason:  <none>\n"));
+        assert!(actual.html.contains("<td>&lt;none&gt;</td>"));
+    }
+
+    #[test]
+    fn it_renders_custom_templates() {
+        let config = CanaryEmailAlertConfig {
+            subject_template: Some("{{target.tag}}:\n{{status}}".to_string()),
+            text_template: Some("{{target.host}} {{outage_duration_s}}".to_string()),
+            ..CanaryEmailAlertConfig::default()
+        };
+
+        let actual = render_email(&fire_alert(), &config).unwrap();
+
+        assert_eq!("tag: Fire", actual.subject);
+        assert_eq!("invalid 3723", actual.text);
+    }
+
+    #[test]
+    fn it_rejects_invalid_templates() {
+        let config = CanaryEmailAlertConfig {
+            text_template: Some("{{#if}}".to_string()),
+            ..CanaryEmailAlertConfig::default()
+        };
+
+        assert!(render_email(&fire_alert(), &config).is_err());
+    }
+
+    #[test]
+    fn it_validates_templates() {
+        assert!(validate(&CanaryEmailAlertConfig::default()).is_ok());
+
+        let config = CanaryEmailAlertConfig {
+            group_html_template: Some("{{#each alerts}}".to_string()),
+            ..CanaryEmailAlertConfig::default()
+        };
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn it_renders_default_group_templates() {
+        let mut okay = fire_alert();
+        okay.check.status = Status::Okay;
+        okay.check.status_code = "200 OK".to_string();
+        let group = AlertGroup {
+            key: "tag".to_string(),
+            digest: false,
+            alerts: vec![fire_alert(), okay],
+        };
+
+        let actual = render_group_email(&group, &CanaryEmailAlertConfig::default()).unwrap();
+
+        assert_eq!("[rcanary] 2 alerts for tag", actual.subject);
+        assert_eq!(
+            "Alerts for tag:\n\n\
+             🔥 foo (invalid) is Fire (was Okay): 500 Internal Server Error, outage 1h 2m 3s\n\
+             🙇 foo (invalid) is Okay (was Okay): 200 OK, outage 1h 2m 3s\n",
+            actual.text
+        );
+    }
+
+    #[test]
+    fn it_renders_digests() {
+        let group = AlertGroup {
+            key: "digest".to_string(),
+            digest: true,
+            alerts: vec![fire_alert()],
+        };
+
+        let actual = render_group_email(&group, &CanaryEmailAlertConfig::default()).unwrap();
+
+        assert_eq!("[rcanary] 1 targets still failing", actual.subject);
+        assert!(actual.text.starts_with("These targets are still failing:"));
+    }
+
+    #[test]
+    fn it_formats_durations() {
+        assert_eq!("0s", format_duration(0));
+        assert_eq!("1m 0s", format_duration(60));
+        assert_eq!("2h 0m 5s", format_duration(7205));
+    }
+}
@@ -0,0 +1,936 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::info;
+use rouille::{Request, Response};
+use serde::Deserialize;
+
+use crate::alerter::silence::AlertControls;
+use crate::auth::{self, Authenticator};
+use crate::badge::{self, Badge, Metric};
+use crate::incidents::IncidentTracker;
+use crate::recent::RecentChecks;
+use crate::report::{self, Format};
+use crate::status_page::StatusPage;
+use crate::storage::{self, Storage};
+use crate::targets::{TargetError, Targets, Triggered};
+use crate::tls::{self, Tls};
+use crate::ws_handler::Message;
+use librcanary::{CanaryAuthScope, CanaryIncident, CanaryTarget, Status};
+
+/// Shared with the main loop, which feeds check results into `controls`
+#[derive(Clone)]
+pub struct ApiState {
+    /// Who may read and change things, if auth is enabled
+    pub auth: Option<Arc<Authenticator>>,
+    /// Badges only need an allowed address, not credentials
+    pub public_badges: bool,
+    pub controls: Arc<Mutex<AlertControls>>,
+    pub incidents: Arc<Mutex<IncidentTracker>>,
+    pub recent: Arc<Mutex<RecentChecks>>,
+    /// Public status page, refreshed when incident notes change
+    pub status_page: Option<Arc<StatusPage>>,
+    /// Check history, if storage is enabled
+    pub storage: Option<Arc<Storage>>,
+    pub targets: Arc<Targets>,
+    /// Sends a message to all websocket clients
+    pub broadcast: Arc<dyn Fn(&Message) + Send + Sync>,
+}
+
+impl ApiState {
+    /// Sends the current acknowledgements and silences to websocket clients
+    pub fn broadcast_controls(&self) {
+        let state = self.controls.lock().unwrap().state(time::get_time());
+        (self.broadcast)(&Message::Controls(state));
+    }
+
+    /// Sends an opened, updated or closed incident to websocket clients
+    pub fn broadcast_incident(&self, incident: &CanaryIncident) {
+        (self.broadcast)(&Message::Incident(incident));
+    }
+}
+
+const DEFAULT_INCIDENT_LIMIT: usize = 50;
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct AcknowledgeRequest {
+    target: String,
+    author: String,
+    #[serde(default)]
+    comment: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NoteRequest {
+    note: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SilenceRequest {
+    #[serde(default)]
+    target: Option<String>,
+    #[serde(default)]
+    tag: Option<String>,
+    author: String,
+    #[serde(default)]
+    comment: String,
+    duration_s: u64,
+}
+
+pub fn start_api_server(bind_to: &str, state: ApiState, tls: Option<Arc<Tls>>) {
+    let addr: SocketAddr = bind_to.parse().unwrap_or_else(|err| {
+        panic!("[status.startup] failed to start api server: {}", err);
+    });
+
+    info!("[status.startup] starting api server at {}...", &addr);
+
+    thread::spawn(move || {
+        tls::serve(addr, tls, move |request| handle(request, &state));
+    });
+}
+
+pub fn handle(request: &Request, state: &ApiState) -> Response {
+    let url = request.url();
+
+    // Reading is fine for viewers, anything else changes alerts, incidents or targets. Badges
+    // can be made public, as pages embedding them cannot send credentials.
+    let scope = match request.method() {
+        "GET" | "HEAD" => CanaryAuthScope::Read,
+        _ => CanaryAuthScope::Admin,
+    };
+    let checked =
+        if state.public_badges && request.method() == "GET" && url.starts_with("/api/badges/") {
+            auth::check_address(state.auth.as_deref(), request)
+        } else {
+            auth::check(state.auth.as_deref(), request, scope)
+        };
+    if let Err(response) = checked {
+        return response;
+    }
+
+    let segments = url
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+
+    match (request.method(), segments.as_slice()) {
+        ("GET", ["api", "targets"]) => {
+            let tag = request.get_param("tag");
+            let mut targets = state.targets.list();
+            targets.retain(|t| has_tag(t, &tag));
+
+            Response::json(&targets)
+        }
+        ("POST", ["api", "targets"]) => match rouille::input::json_input(request) {
+            Ok(target) => changed_target(state.targets.create(target), 201),
+            Err(err) => error(400, &format!("invalid request body: {}", err)),
+        },
+        ("GET", ["api", "targets", name]) => match state.targets.get(name) {
+            Some(target) => Response::json(&target),
+            None => error(404, &format!("unknown target `{}`", name)),
+        },
+        ("PUT", ["api", "targets", name]) => match rouille::input::json_input(request) {
+            Ok(target) => changed_target(state.targets.update(name, target), 200),
+            Err(err) => error(400, &format!("invalid request body: {}", err)),
+        },
+        ("DELETE", ["api", "targets", name]) => match state.targets.delete(name) {
+            Ok(_) => Response::text("").with_status_code(204),
+            Err(err) => error(err.status_code(), err.message()),
+        },
+        ("POST", ["api", "targets", name, "pause"]) => {
+            changed_target(state.targets.set_paused(name, true), 200)
+        }
+        ("POST", ["api", "targets", name, "resume"]) => {
+            changed_target(state.targets.set_paused(name, false), 200)
+        }
+        ("POST", ["api", "targets", name, "check"]) => {
+            match state.targets.check_now(name).and_then(Triggered::wait) {
+                Ok(checks) => match checks.first() {
+                    Some(check) => Response::json(check),
+                    None => error(409, "the target stopped being checked"),
+                },
+                Err(err) => error(err.status_code(), err.message()),
+            }
+        }
+        ("POST", ["api", "tags", tag, "check"]) => {
+            match state.targets.check_tag_now(tag).and_then(Triggered::wait) {
+                Ok(checks) => Response::json(&checks),
+                Err(err) => error(err.status_code(), err.message()),
+            }
+        }
+        ("GET", ["api", "targets", name, "history"]) => history(request, state, name),
+        ("GET", ["api", "status"]) => {
+            let tag = request.get_param("tag");
+            let status = match status_param(request) {
+                Ok(status) => status,
+                Err(err) => return error(400, &err),
+            };
+
+            let recent = state.recent.lock().unwrap();
+            let checks = recent
+                .latest()
+                .filter(|c| has_tag(&c.target, &tag))
+                .filter(|c| status.as_ref().is_none_or(|s| &c.status == s))
+                .collect::<Vec<_>>();
+
+            Response::json(&checks)
+        }
+        ("GET", ["api", "acknowledgements"]) => {
+            let controls = state.controls.lock().unwrap().state(time::get_time());
+            Response::json(&controls.acknowledgements)
+        }
+        ("POST", ["api", "acknowledgements"]) => acknowledge(request, state),
+        ("DELETE", ["api", "acknowledgements", target]) => {
+            let removed = state.controls.lock().unwrap().unacknowledge(target);
+            match removed {
+                Some(_) => {
+                    info!("[alert.unacknowledge] {}", target);
+                    state.broadcast_controls();
+                    Response::text("").with_status_code(204)
+                }
+                None => error(404, &format!("target `{}` is not acknowledged", target)),
+            }
+        }
+        ("GET", ["api", "silences"]) => {
+            let controls = state.controls.lock().unwrap().state(time::get_time());
+            Response::json(&controls.silences)
+        }
+        ("POST", ["api", "silences"]) => silence(request, state),
+        ("DELETE", ["api", "silences", id]) => {
+            let id = match id.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return error(400, &format!("invalid silence id `{}`", id)),
+            };
+
+            let expired = state.controls.lock().unwrap().expire(id);
+            match expired {
+                Some(_) => {
+                    info!("[alert.unsilence] {}", id);
+                    state.broadcast_controls();
+                    Response::text("").with_status_code(204)
+                }
+                None => error(404, &format!("silence {} does not exist", id)),
+            }
+        }
+        ("GET", ["api", "incidents"]) => {
+            let limit = match request.get_param("limit").map(|l| l.parse::<usize>()) {
+                Some(Ok(limit)) => limit,
+                Some(Err(_)) => return error(400, "`limit` must be a number"),
+                None => DEFAULT_INCIDENT_LIMIT,
+            };
+            let target = request.get_param("target");
+
+            let incidents = state.incidents.lock().unwrap();
+            Response::json(&incidents.list(target.as_deref(), limit))
+        }
+        ("GET", ["api", "incidents", id]) => {
+            let id = match id.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return error(400, &format!("invalid incident id `{}`", id)),
+            };
+
+            let incident = state.incidents.lock().unwrap().get(id);
+            match incident {
+                Ok(Some(incident)) => Response::json(&incident),
+                Ok(None) => error(404, &format!("incident {} does not exist", id)),
+                Err(err) => error(500, &format!("failed to load incident: {}", err)),
+            }
+        }
+        ("PUT", ["api", "incidents", id, "note"]) => public_note(request, state, id),
+        ("GET", ["api", "report"]) => uptime_report(request, state),
+        ("GET", ["api", "badges", kind, name, file]) => {
+            status_badge(request, state, kind, name, file)
+        }
+        _ => Response::empty_404(),
+    }
+}
+
+fn acknowledge(request: &Request, state: &ApiState) -> Response {
+    let body: AcknowledgeRequest = match rouille::input::json_input(request) {
+        Ok(body) => body,
+        Err(err) => return error(400, &format!("invalid request body: {}", err)),
+    };
+
+    if state.targets.get(&body.target).is_none() {
+        return error(404, &format!("unknown target `{}`", body.target));
+    }
+
+    let result = state.controls.lock().unwrap().acknowledge(
+        &body.target,
+        &body.author,
+        &body.comment,
+        time::get_time(),
+    );
+
+    match result {
+        Ok(acknowledgement) => {
+            info!("[alert.acknowledge] {:?}", &acknowledgement);
+            state.broadcast_controls();
+
+            let incident = state
+                .incidents
+                .lock()
+                .unwrap()
+                .acknowledged(&acknowledgement);
+            if let Some(incident) = incident {
+                state.broadcast_incident(&incident);
+            }
+            Response::json(&acknowledgement).with_status_code(201)
+        }
+        Err(err) => error(409, &err),
+    }
+}
+
+fn public_note(request: &Request, state: &ApiState, id: &str) -> Response {
+    let id = match id.parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => return error(400, &format!("invalid incident id `{}`", id)),
+    };
+    let body: NoteRequest = match rouille::input::json_input(request) {
+        Ok(body) => body,
+        Err(err) => return error(400, &format!("invalid request body: {}", err)),
+    };
+
+    // An empty note takes it off the status page
+    let note = body.note.filter(|n| !n.trim().is_empty());
+    let result = state.incidents.lock().unwrap().set_public_note(id, note);
+
+    match result {
+        Ok(Some(incident)) => {
+            info!("[incident.note] {}: {:?}", id, &incident.public_note);
+            state.broadcast_incident(&incident);
+            if let Some(ref status_page) = state.status_page {
+                status_page.changed();
+            }
+            Response::json(&incident)
+        }
+        Ok(None) => error(404, &format!("incident {} does not exist", id)),
+        Err(err) => error(500, &format!("failed to load incident: {}", err)),
+    }
+}
+
+fn silence(request: &Request, state: &ApiState) -> Response {
+    let body: SilenceRequest = match rouille::input::json_input(request) {
+        Ok(body) => body,
+        Err(err) => return error(400, &format!("invalid request body: {}", err)),
+    };
+
+    if body.target.is_none() && body.tag.is_none() {
+        return error(400, "a silence needs a `target` or a `tag`");
+    }
+    if let Some(ref target) = body.target {
+        if state.targets.get(target).is_none() {
+            return error(404, &format!("unknown target `{}`", target));
+        }
+    }
+
+    let silence = match state.controls.lock().unwrap().silence(
+        body.target,
+        body.tag,
+        &body.author,
+        &body.comment,
+        body.duration_s,
+        time::get_time(),
+    ) {
+        Ok(silence) => silence,
+        Err(err) => return error(400, &err),
+    };
+
+    info!("[alert.silence] {:?}", &silence);
+    state.broadcast_controls();
+    Response::json(&silence).with_status_code(201)
+}
+
+fn history(request: &Request, state: &ApiState, name: &str) -> Response {
+    if state.targets.get(name).is_none() {
+        return error(404, &format!("unknown target `{}`", name));
+    }
+
+    let storage = match state.storage {
+        Some(ref storage) => storage,
+        None => return error(404, "storage is not enabled"),
+    };
+
+    let status = match status_param(request) {
+        Ok(status) => status,
+        Err(err) => return error(400, &err),
+    };
+    let limit = match request.get_param("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error(400, "`limit` must be a number"),
+        None => DEFAULT_HISTORY_LIMIT,
+    };
+    let from = request.get_param("from");
+    let to = request.get_param("to");
+    let (from, to) = match report::parse_range(from.as_deref(), to.as_deref(), time::get_time()) {
+        Ok(range) => range,
+        Err(err) => return error(400, &err),
+    };
+
+    match storage.checks(name, from.sec, to.sec, status.as_ref(), limit) {
+        Ok(checks) => Response::json(&checks),
+        Err(err) => error(500, &format!("failed to load history: {}", err)),
+    }
+}
+
+fn status_badge(
+    request: &Request,
+    state: &ApiState,
+    kind: &str,
+    name: &str,
+    file: &str,
+) -> Response {
+    let (metric, format) = match Metric::parse(file) {
+        Ok(parsed) => parsed,
+        Err(err) => return error(404, &err),
+    };
+
+    let targets = state.targets.list();
+    let targets = targets
+        .iter()
+        .filter(|t| match kind {
+            "targets" => t.name == name,
+            "tags" => t.tag.as_deref() == Some(name),
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return error(
+            404,
+            &format!("unknown {} `{}`", kind.trim_end_matches('s'), name),
+        );
+    }
+
+    let from = request.get_param("from");
+    let to = request.get_param("to");
+    let range = match report::parse_range(from.as_deref(), to.as_deref(), time::get_time()) {
+        Ok(range) => range,
+        Err(err) => return error(400, &err),
+    };
+    let label = request
+        .get_param("label")
+        .unwrap_or_else(|| badge::label(name, metric));
+
+    // Copied so the checks are not locked while uptime is read from storage
+    let latest = state
+        .recent
+        .lock()
+        .unwrap()
+        .latest()
+        .cloned()
+        .collect::<Vec<_>>();
+    let built = Badge::build(
+        metric,
+        &label,
+        &targets,
+        &latest,
+        state.storage.as_deref(),
+        range,
+    );
+    match built {
+        Ok(badge) => badge.response(format),
+        Err(err) => error(404, &err),
+    }
+}
+
+fn changed_target(result: Result<CanaryTarget, TargetError>, status_code: u16) -> Response {
+    match result {
+        Ok(target) => Response::json(&target).with_status_code(status_code),
+        Err(err) => error(err.status_code(), err.message()),
+    }
+}
+
+fn has_tag(target: &CanaryTarget, tag: &Option<String>) -> bool {
+    tag.is_none() || &target.tag == tag
+}
+
+fn status_param(request: &Request) -> Result<Option<Status>, String> {
+    request
+        .get_param("status")
+        .map(|s| storage::parse_status(&s))
+        .transpose()
+}
+
+fn uptime_report(request: &Request, state: &ApiState) -> Response {
+    let storage = match state.storage {
+        Some(ref storage) => storage,
+        None => return error(404, "storage is not enabled"),
+    };
+
+    let format = request
+        .get_param("format")
+        .unwrap_or_else(|| "json".to_string());
+    let format = match Format::parse(&format) {
+        Ok(format) => format,
+        Err(err) => return error(400, &err),
+    };
+
+    let from = request.get_param("from");
+    let to = request.get_param("to");
+    let (from, to) = match report::parse_range(from.as_deref(), to.as_deref(), time::get_time()) {
+        Ok(range) => range,
+        Err(err) => return error(400, &err),
+    };
+
+    let rendered = report::build(storage, &state.targets.list(), from, to)
+        .and_then(|r| report::render(&r, format));
+    match rendered {
+        Ok(body) => Response::from_data(format.content_type(), body),
+        Err(err) => error(500, &format!("failed to build report: {}", err)),
+    }
+}
+
+fn error(status_code: u16, message: &str) -> Response {
+    Response::json(&serde_json::json!({ "error": message })).with_status_code(status_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::target;
+    use librcanary::CanaryCheck;
+    use std::io::Read;
+
+    fn state() -> (ApiState, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let (sent_clone, configs_sent) = (sent.clone(), sent.clone());
+        let targets = Targets::new(
+            vec![target()],
+            None,
+            Box::new(|_, _| {}),
+            Box::new(move |targets| {
+                let message = Message::Config(targets).to_json();
+                configs_sent.lock().unwrap().push(message);
+            }),
+        )
+        .unwrap();
+
+        let state = ApiState {
+            auth: None,
+            public_badges: false,
+            controls: Arc::new(Mutex::new(AlertControls::new(None).unwrap())),
+            incidents: Arc::new(Mutex::new(IncidentTracker::new(None).unwrap())),
+            recent: Arc::new(Mutex::new(RecentChecks::new())),
+            status_page: None,
+            storage: None,
+            targets: Arc::new(targets),
+            broadcast: Arc::new(move |m| sent_clone.lock().unwrap().push(m.to_json())),
+        };
+
+        (state, sent)
+    }
+
+    fn check(status: Status, time: &str) -> CanaryCheck {
+        CanaryCheck {
+            time: time.to_string(),
+            ..crate::tests::check("foo", status)
+        }
+    }
+
+    fn fail(state: &ApiState) {
+        let check = check(Status::Fire, "2016-10-14T08:00:00Z");
+        state.controls.lock().unwrap().update(&check);
+        state.incidents.lock().unwrap().update(&check);
+    }
+
+    fn with_storage(state: &mut ApiState) -> Arc<Storage> {
+        let storage = Arc::new(
+            Storage::open(&librcanary::CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..librcanary::CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+        state.storage = Some(storage.clone());
+        storage
+    }
+
+    fn request(state: &ApiState, method: &str, url: &str, body: &str) -> (u16, String) {
+        let request = Request::fake_http(
+            method,
+            url,
+            vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            body.as_bytes().to_vec(),
+        );
+        let response = handle(&request, state);
+
+        let mut data = String::new();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        reader.read_to_string(&mut data).unwrap();
+
+        (response.status_code, data)
+    }
+
+    #[test]
+    fn it_acknowledges_failing_targets() {
+        let (state, sent) = state();
+        let body = r#"{"target": "foo", "author": "alice", "comment": "on it"}"#;
+
+        assert_eq!(
+            409,
+            request(&state, "POST", "/api/acknowledgements", body).0
+        );
+
+        fail(&state);
+        assert_eq!(
+            201,
+            request(&state, "POST", "/api/acknowledgements", body).0
+        );
+        assert_eq!(2, sent.lock().unwrap().len());
+        assert!(sent.lock().unwrap()[1].contains(r#""type":"incident""#));
+
+        let (status_code, listed) = request(&state, "GET", "/api/acknowledgements", "");
+        assert_eq!(200, status_code);
+        assert!(listed.contains("alice"));
+
+        assert_eq!(
+            204,
+            request(&state, "DELETE", "/api/acknowledgements/foo", "").0
+        );
+        assert_eq!(
+            404,
+            request(&state, "DELETE", "/api/acknowledgements/foo", "").0
+        );
+    }
+
+    #[test]
+    fn it_requires_the_admin_scope_to_change_things() {
+        let (mut state, _) = state();
+        state.auth = Some(Arc::new(
+            Authenticator::new(&librcanary::CanaryAuthConfig {
+                enabled: true,
+                users: vec![librcanary::CanaryAuthUser {
+                    name: "viewer".to_string(),
+                    password: None,
+                    scope: CanaryAuthScope::Read,
+                    token: Some("secret".to_string()),
+                }],
+                ..librcanary::CanaryAuthConfig::default()
+            })
+            .unwrap(),
+        ));
+        let authorized = |method, url| {
+            let headers = vec![("Authorization".to_owned(), "Bearer secret".to_owned())];
+            handle(&Request::fake_http(method, url, headers, vec![]), &state).status_code
+        };
+
+        assert_eq!(401, request(&state, "GET", "/api/targets", "").0);
+        assert_eq!(200, authorized("GET", "/api/targets"));
+        assert_eq!(403, authorized("DELETE", "/api/acknowledgements/foo"));
+        assert_eq!(
+            401,
+            request(&state, "GET", "/api/badges/targets/foo/status.svg", "").0
+        );
+    }
+
+    #[test]
+    fn it_serves_public_badges_to_allowed_addresses() {
+        let (mut state, _) = state();
+        state.public_badges = true;
+        state.auth = Some(Arc::new(
+            Authenticator::new(&librcanary::CanaryAuthConfig {
+                enabled: true,
+                allowed_ips: vec!["10.0.0.0/8".to_string()],
+                users: vec![librcanary::CanaryAuthUser {
+                    name: "viewer".to_string(),
+                    password: None,
+                    scope: CanaryAuthScope::Read,
+                    token: Some("secret".to_string()),
+                }],
+            })
+            .unwrap(),
+        ));
+        let from = |ip: &str, url| {
+            let client = format!("{}:4000", ip).parse().unwrap();
+            handle(
+                &Request::fake_http_from(client, "GET", url, vec![], vec![]),
+                &state,
+            )
+            .status_code
+        };
+
+        assert_eq!(200, from("10.0.0.1", "/api/badges/targets/foo/status.svg"));
+        assert_eq!(401, from("10.0.0.1", "/api/targets"));
+        assert_eq!(403, from("192.0.2.1", "/api/badges/targets/foo/status.svg"));
+        assert_eq!(403, from("192.0.2.1", "/api/badges/targets/bar/status.svg"));
+    }
+
+    #[test]
+    fn it_rejects_unknown_targets() {
+        let (state, _) = state();
+        let body = r#"{"target": "bar", "author": "alice"}"#;
+
+        assert_eq!(
+            404,
+            request(&state, "POST", "/api/acknowledgements", body).0
+        );
+        assert_eq!(400, request(&state, "POST", "/api/acknowledgements", "{").0);
+    }
+
+    #[test]
+    fn it_creates_and_expires_silences() {
+        let (state, sent) = state();
+
+        let missing = r#"{"author": "bob", "duration_s": 60}"#;
+        assert_eq!(400, request(&state, "POST", "/api/silences", missing).0);
+        let forever = r#"{"tag": "tag", "author": "bob", "duration_s": 18446744073709551615}"#;
+        assert_eq!(400, request(&state, "POST", "/api/silences", forever).0);
+
+        let body = r#"{"tag": "tag", "author": "bob", "duration_s": 60}"#;
+        let (status_code, created) = request(&state, "POST", "/api/silences", body);
+        assert_eq!(201, status_code);
+        assert!(created.contains(r#""id":0"#));
+        assert!(state
+            .controls
+            .lock()
+            .unwrap()
+            .is_quiet(&target(), time::get_time()));
+
+        assert!(request(&state, "GET", "/api/silences", "")
+            .1
+            .contains("bob"));
+        assert_eq!(204, request(&state, "DELETE", "/api/silences/0", "").0);
+        assert_eq!(404, request(&state, "DELETE", "/api/silences/0", "").0);
+        assert_eq!(400, request(&state, "DELETE", "/api/silences/x", "").0);
+        assert_eq!(2, sent.lock().unwrap().len());
+    }
+
+    #[test]
+    fn it_serves_uptime_reports() {
+        let (mut state, _) = state();
+        assert_eq!(404, request(&state, "GET", "/api/report", "").0);
+
+        with_storage(&mut state);
+
+        let (status_code, body) = request(&state, "GET", "/api/report?format=csv", "");
+        assert_eq!(200, status_code);
+        assert!(body.starts_with("kind,name,checks,"));
+        assert!(body.contains("target,foo,0,"));
+
+        assert_eq!(
+            400,
+            request(&state, "GET", "/api/report?from=yesterday", "").0
+        );
+        assert_eq!(400, request(&state, "GET", "/api/report?format=xml", "").0);
+    }
+
+    #[test]
+    fn it_lists_incidents() {
+        let (state, _) = state();
+        fail(&state);
+
+        let (status_code, listed) = request(&state, "GET", "/api/incidents?target=foo", "");
+        assert_eq!(200, status_code);
+        assert!(listed.contains(r#""peak_status":"Fire""#));
+        assert_eq!(
+            "[]",
+            request(&state, "GET", "/api/incidents?target=bar", "").1
+        );
+        assert_eq!(400, request(&state, "GET", "/api/incidents?limit=x", "").0);
+
+        assert_eq!(200, request(&state, "GET", "/api/incidents/0", "").0);
+        assert_eq!(404, request(&state, "GET", "/api/incidents/1", "").0);
+        assert_eq!(400, request(&state, "GET", "/api/incidents/x", "").0);
+    }
+
+    #[test]
+    fn it_serves_badges() {
+        let (mut state, _) = state();
+        state
+            .recent
+            .lock()
+            .unwrap()
+            .push(check(Status::Fire, "2016-10-14T08:00:00Z"));
+
+        let (status_code, svg) = request(&state, "GET", "/api/badges/targets/foo/status.svg", "");
+        assert_eq!(200, status_code);
+        assert!(svg.contains("foo status: fire"));
+
+        let (_, json) = request(
+            &state,
+            "GET",
+            "/api/badges/tags/tag/status.json?label=web",
+            "",
+        );
+        assert!(json.contains(r#""label":"web","message":"fire""#));
+
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/badges/targets/foo/uptime.svg", "").0
+        );
+        let storage = with_storage(&mut state);
+        storage
+            .record(&check(Status::Okay, "2016-10-14T09:00:00Z"), None)
+            .unwrap();
+        let url =
+            "/api/badges/targets/foo/uptime.json?from=2016-10-14T00:00:00Z&to=2016-10-15T00:00:00Z";
+        assert!(request(&state, "GET", url, "")
+            .1
+            .contains(r#""message":"100%""#));
+
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/badges/targets/bar/status.svg", "").0
+        );
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/badges/tags/foo/status.svg", "").0
+        );
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/badges/targets/foo/status.png", "").0
+        );
+    }
+
+    #[test]
+    fn it_sets_public_notes_on_incidents() {
+        let (state, sent) = state();
+        fail(&state);
+
+        let body = r#"{"note": "Our provider is down"}"#;
+        let (status_code, incident) = request(&state, "PUT", "/api/incidents/0/note", body);
+        assert_eq!(200, status_code);
+        assert!(incident.contains(r#""public_note":"Our provider is down""#));
+        assert!(sent.lock().unwrap()[0].contains("Our provider is down"));
+
+        let (_, incident) = request(&state, "PUT", "/api/incidents/0/note", r#"{"note": " "}"#);
+        assert!(incident.contains(r#""public_note":null"#));
+
+        assert_eq!(404, request(&state, "PUT", "/api/incidents/1/note", body).0);
+        assert_eq!(400, request(&state, "PUT", "/api/incidents/0/note", "{").0);
+    }
+
+    #[test]
+    fn it_lists_targets_and_their_status() {
+        let (state, _) = state();
+        state
+            .recent
+            .lock()
+            .unwrap()
+            .push(check(Status::Okay, "2016-10-14T08:00:00Z"));
+
+        assert!(request(&state, "GET", "/api/targets", "")
+            .1
+            .contains(r#""name":"foo""#));
+        assert_eq!("[]", request(&state, "GET", "/api/targets?tag=web", "").1);
+
+        assert!(
+            request(&state, "GET", "/api/status?tag=tag&status=Okay", "")
+                .1
+                .contains(r#""status":"Okay""#)
+        );
+        assert_eq!(
+            "[]",
+            request(&state, "GET", "/api/status?status=Fire", "").1
+        );
+        assert_eq!(400, request(&state, "GET", "/api/status?status=Bad", "").0);
+    }
+
+    #[test]
+    fn it_manages_targets() {
+        let (state, sent) = state();
+        let body = r#"{"name": "bar", "host": "https://example.com", "tag": "web",
+            "interval_s": 60, "alert": false, "basic_auth": null}"#;
+
+        let (status_code, created) = request(&state, "POST", "/api/targets", body);
+        assert_eq!(201, status_code);
+        assert!(created.contains(r#""paused":false"#));
+        assert_eq!(409, request(&state, "POST", "/api/targets", body).0);
+        assert_eq!(400, request(&state, "POST", "/api/targets", "{}").0);
+        assert!(sent.lock().unwrap()[0].contains(r#""type":"config""#));
+        assert!(sent.lock().unwrap()[0].contains(r#""name":"bar""#));
+
+        let (status_code, paused) = request(&state, "POST", "/api/targets/bar/pause", "");
+        assert_eq!(200, status_code);
+        assert!(paused.contains(r#""paused":true"#));
+        assert!(request(&state, "GET", "/api/targets/bar", "")
+            .1
+            .contains(r#""paused":true"#));
+        assert_eq!(
+            404,
+            request(&state, "POST", "/api/targets/baz/resume", "").0
+        );
+
+        let renamed = body.replace("bar", "baz");
+        assert_eq!(400, request(&state, "PUT", "/api/targets/bar", &renamed).0);
+        let slower = body.replace("60", "600");
+        let (status_code, updated) = request(&state, "PUT", "/api/targets/bar", &slower);
+        assert_eq!(200, status_code);
+        assert!(updated.contains(r#""interval_s":600"#));
+
+        assert_eq!(204, request(&state, "DELETE", "/api/targets/bar", "").0);
+        assert_eq!(404, request(&state, "GET", "/api/targets/bar", "").0);
+        assert_eq!(1, state.targets.list().len());
+    }
+
+    #[test]
+    fn it_checks_targets_now() {
+        let (mut state, _) = state();
+        // Answers every trigger straight away, as the main loop does once it handled the check
+        let targets = Targets::new(
+            vec![target()],
+            None,
+            Box::new(|_, triggered| {
+                thread::spawn(move || {
+                    for trigger in triggered {
+                        let _ = trigger.send(check(Status::Okay, "2016-10-14T08:00:00Z"));
+                    }
+                });
+            }),
+            Box::new(|_| {}),
+        );
+        state.targets = Arc::new(targets.unwrap());
+
+        let (status_code, checked) = request(&state, "POST", "/api/targets/foo/check", "");
+        assert_eq!(200, status_code);
+        assert!(checked.starts_with(r#"{"alert":"#));
+        assert!(checked.contains(r#""status":"Okay""#));
+
+        let (status_code, checked) = request(&state, "POST", "/api/tags/tag/check", "");
+        assert_eq!(200, status_code);
+        assert!(checked.starts_with(r#"[{"alert":"#));
+
+        assert_eq!(404, request(&state, "POST", "/api/targets/bar/check", "").0);
+        assert_eq!(404, request(&state, "POST", "/api/tags/bar/check", "").0);
+    }
+
+    #[test]
+    fn it_serves_target_history() {
+        let (mut state, _) = state();
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/targets/foo/history", "").0
+        );
+
+        let storage = with_storage(&mut state);
+        storage
+            .record(&check(Status::Okay, "2016-10-14T08:00:00Z"), None)
+            .unwrap();
+        storage
+            .record(
+                &check(Status::Fire, "2016-10-14T08:01:00Z"),
+                Some(&Status::Okay),
+            )
+            .unwrap();
+
+        let url = "/api/targets/foo/history?from=2016-10-14T00:00:00Z";
+        let (status_code, history) = request(&state, "GET", url, "");
+        assert_eq!(200, status_code);
+        assert!(history.find("Fire").unwrap() < history.find("Okay").unwrap());
+
+        let (_, fire) = request(&state, "GET", &format!("{}&status=Fire&limit=5", url), "");
+        assert!(!fire.contains(r#""status":"Okay""#));
+        assert_eq!(
+            "[]",
+            request(&state, "GET", &format!("{}&limit=0", url), "").1
+        );
+        assert_eq!(
+            404,
+            request(&state, "GET", "/api/targets/bar/history", "").0
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use std::net::IpAddr;
+
+use log::info;
+use rouille::{Request, Response};
+
+use librcanary::{CanaryAuthConfig, CanaryAuthScope, CanaryAuthUser};
+
+/// Why a client was turned away
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// Missing or wrong credentials
+    Unauthenticated,
+    /// Not connecting from an allowed address, or missing the scope needed
+    Forbidden,
+}
+
+impl Denied {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Denied::Unauthenticated => 401,
+            Denied::Forbidden => 403,
+        }
+    }
+
+    /// Answer to HTTP requests and websocket handshakes
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Denied::Unauthenticated => "Unauthorized",
+            Denied::Forbidden => "Forbidden",
+        }
+    }
+}
+
+/// An IP address or CIDR range, eg. `10.0.0.0/8`
+#[derive(Debug, PartialEq)]
+struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let address = parts
+            .next()
+            .unwrap_or("")
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid address `{}`: {}", s, err))?;
+        let address = canonical(address);
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in `{}`", s))?,
+            None => max,
+        };
+
+        Ok(Network { address, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv4 clients of dual-stack listeners show up as `::ffff:a.b.c.d`
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// Decides who may use the websocket, API, health check and metrics servers
+pub struct Authenticator {
+    networks: Vec<Network>,
+    users: Vec<CanaryAuthUser>,
+}
+
+impl Authenticator {
+    pub fn new(config: &CanaryAuthConfig) -> Result<Authenticator, String> {
+        let networks = config
+            .allowed_ips
+            .iter()
+            .map(|s| Network::parse(s))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for user in &config.users {
+            if user.password.is_none() && user.token.is_none() {
+                return Err(format!("user `{}` has no password or token", user.name));
+            }
+        }
+
+        // Either one restricts access, without both everyone would be let in
+        if networks.is_empty() && config.users.is_empty() {
+            return Err("auth is enabled without `users` or `allowed_ips`".to_string());
+        }
+
+        Ok(Authenticator {
+            networks,
+            users: config.users.clone(),
+        })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.networks.is_empty() || self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    /// Checks an `Authorization` header, `Basic` or `Bearer`, or a token passed some other way
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        token: Option<&str>,
+        scope: CanaryAuthScope,
+    ) -> Result<(), Denied> {
+        if self.users.is_empty() {
+            return Ok(());
+        }
+
+        let user = match (token, authorization) {
+            (Some(token), _) => self.user_with_token(token),
+            (None, Some(authorization)) => self.user_with_header(authorization),
+            (None, None) => None,
+        }
+        .ok_or(Denied::Unauthenticated)?;
+
+        if user.scope >= scope {
+            Ok(())
+        } else {
+            Err(Denied::Forbidden)
+        }
+    }
+
+    pub fn authorize(
+        &self,
+        ip: IpAddr,
+        authorization: Option<&str>,
+        token: Option<&str>,
+        scope: CanaryAuthScope,
+    ) -> Result<(), Denied> {
+        if !self.allows(ip) {
+            return Err(Denied::Forbidden);
+        }
+
+        self.authenticate(authorization, token, scope)
+    }
+
+    fn user_with_token(&self, token: &str) -> Option<&CanaryAuthUser> {
+        self.users
+            .iter()
+            .find(|u| u.token.as_ref().is_some_and(|t| secure_eq(t, token)))
+    }
+
+    fn user_with_header(&self, authorization: &str) -> Option<&CanaryAuthUser> {
+        let mut parts = authorization.trim().splitn(2, ' ');
+        let scheme = parts.next()?.to_ascii_lowercase();
+        let credentials = parts.next()?.trim();
+
+        match scheme.as_str() {
+            "bearer" => self.user_with_token(credentials),
+            "basic" => {
+                let decoded = base64::decode(credentials).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                let mut parts = decoded.splitn(2, ':');
+                let (name, password) = (parts.next()?, parts.next()?);
+
+                self.users.iter().find(|u| {
+                    u.name == name && u.password.as_ref().is_some_and(|p| secure_eq(p, password))
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Compares secrets without bailing out at the first differing byte
+fn secure_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Turns away rouille requests which do not have `scope`, with the response to send them
+pub fn check(
+    auth: Option<&Authenticator>,
+    request: &Request,
+    scope: CanaryAuthScope,
+) -> Result<(), Response> {
+    check_with_token(auth, request, None, scope)
+}
+
+/// Like `check`, also accepting a token passed some other way than the `Authorization` header
+pub fn check_with_token(
+    auth: Option<&Authenticator>,
+    request: &Request,
+    token: Option<&str>,
+    scope: CanaryAuthScope,
+) -> Result<(), Response> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Ok(()),
+    };
+
+    let ip = request.remote_addr().ip();
+    auth.authorize(ip, request.header("Authorization"), token, scope)
+        .map_err(|denied| deny(request, denied))
+}
+
+/// Like `check` for things served without credentials, which still need an allowed address
+pub fn check_address(auth: Option<&Authenticator>, request: &Request) -> Result<(), Response> {
+    match auth {
+        Some(auth) if !auth.allows(request.remote_addr().ip()) => {
+            Err(deny(request, Denied::Forbidden))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn deny(request: &Request, denied: Denied) -> Response {
+    info!(
+        "[auth.denied] {} {} from {}: {:?}",
+        request.method(),
+        request.url(),
+        request.remote_addr().ip(),
+        denied
+    );
+
+    let response = Response::text(denied.reason()).with_status_code(denied.status_code());
+    match denied {
+        Denied::Unauthenticated => {
+            response.with_additional_header("WWW-Authenticate", "Basic realm=\"rcanary\"")
+        }
+        Denied::Forbidden => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, scope: CanaryAuthScope) -> CanaryAuthUser {
+        CanaryAuthUser {
+            name: name.to_string(),
+            password: Some("hunter2".to_string()),
+            scope,
+            token: Some(format!("{}-token", name)),
+        }
+    }
+
+    fn authenticator(allowed_ips: &[&str]) -> Authenticator {
+        Authenticator::new(&CanaryAuthConfig {
+            allowed_ips: allowed_ips.iter().map(|s| s.to_string()).collect(),
+            enabled: true,
+            users: vec![
+                user("viewer", CanaryAuthScope::Read),
+                user("oncall", CanaryAuthScope::Admin),
+            ],
+        })
+        .unwrap()
+    }
+
+    fn basic(name: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", name, password))
+        )
+    }
+
+    #[test]
+    fn it_matches_networks() {
+        let network = Network::parse("10.1.0.0/16").unwrap();
+        assert!(network.contains("10.1.200.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+
+        let single = Network::parse("fd00::1").unwrap();
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+
+        assert!(Network::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("192.0.2.1".parse().unwrap()));
+        assert!(Network::parse("10.0.0.0/33").is_err());
+        assert!(Network::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn it_authenticates_users_by_scope() {
+        let auth = authenticator(&[]);
+        let read = CanaryAuthScope::Read;
+        let admin = CanaryAuthScope::Admin;
+
+        assert_eq!(
+            Ok(()),
+            auth.authenticate(Some(&basic("viewer", "hunter2")), None, read)
+        );
+        assert_eq!(
+            Err(Denied::Forbidden),
+            auth.authenticate(Some(&basic("viewer", "hunter2")), None, admin)
+        );
+        assert_eq!(
+            Ok(()),
+            auth.authenticate(Some("Bearer oncall-token"), None, admin)
+        );
+        assert_eq!(Ok(()), auth.authenticate(None, Some("viewer-token"), read));
+
+        assert_eq!(
+            Err(Denied::Unauthenticated),
+            auth.authenticate(Some(&basic("viewer", "hunter3")), None, read)
+        );
+        assert_eq!(
+            Err(Denied::Unauthenticated),
+            auth.authenticate(Some("Bearer nope"), None, read)
+        );
+        assert_eq!(
+            Err(Denied::Unauthenticated),
+            auth.authenticate(None, None, read)
+        );
+    }
+
+    #[test]
+    fn it_checks_addresses_before_credentials() {
+        let auth = authenticator(&["127.0.0.1", "10.0.0.0/8"]);
+        let authorization = Some("Bearer viewer-token");
+
+        assert_eq!(
+            Ok(()),
+            auth.authorize(
+                "10.3.2.1".parse().unwrap(),
+                authorization,
+                None,
+                CanaryAuthScope::Read
+            )
+        );
+        assert_eq!(
+            Err(Denied::Forbidden),
+            auth.authorize(
+                "192.0.2.1".parse().unwrap(),
+                authorization,
+                None,
+                CanaryAuthScope::Read
+            )
+        );
+
+        let open = Authenticator::new(&CanaryAuthConfig {
+            allowed_ips: vec!["127.0.0.1".to_string()],
+            enabled: true,
+            users: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            Ok(()),
+            open.authorize(
+                "127.0.0.1".parse().unwrap(),
+                None,
+                None,
+                CanaryAuthScope::Admin
+            )
+        );
+    }
+
+    #[test]
+    fn it_answers_denied_requests() {
+        let auth = authenticator(&[]);
+        let request = |headers| Request::fake_http("GET", "/metrics", headers, vec![]);
+
+        let response = check(Some(&auth), &request(vec![]), CanaryAuthScope::Read).unwrap_err();
+        assert_eq!(401, response.status_code);
+
+        let headers = vec![("Authorization".to_string(), basic("viewer", "hunter2"))];
+        assert!(check(
+            Some(&auth),
+            &request(headers.clone()),
+            CanaryAuthScope::Read
+        )
+        .is_ok());
+        let response = check(Some(&auth), &request(headers), CanaryAuthScope::Admin).unwrap_err();
+        assert_eq!(403, response.status_code);
+
+        assert!(check(None, &request(vec![]), CanaryAuthScope::Admin).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_users_without_credentials() {
+        let mut user = user("nobody", CanaryAuthScope::Read);
+        user.password = None;
+        user.token = None;
+
+        let config = CanaryAuthConfig {
+            enabled: true,
+            users: vec![user],
+            ..CanaryAuthConfig::default()
+        };
+
+        assert!(Authenticator::new(&config).is_err());
+    }
+
+    #[test]
+    fn it_rejects_configs_which_let_everyone_in() {
+        let config = CanaryAuthConfig {
+            enabled: true,
+            ..CanaryAuthConfig::default()
+        };
+
+        assert!(Authenticator::new(&config).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use rouille::Response;
+use serde::Serialize;
+use time::Timespec;
+
+use crate::status_page::escape;
+use crate::storage::Storage;
+use librcanary::{CanaryCheck, CanaryTarget, Status};
+
+/// Roughly the width of a character of 11px Verdana, which badges are drawn with
+const CHAR_WIDTH: usize = 7;
+const PADDING: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    Status,
+    /// Percentage of okay checks in a range, needs storage
+    Uptime,
+    /// Latency of the latest check, the slowest target's for tags
+    Latency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Svg,
+    /// The shields.io endpoint schema, see https://shields.io/endpoint
+    Json,
+}
+
+impl Metric {
+    /// Splits a file name such as `uptime.svg`
+    pub fn parse(file: &str) -> Result<(Metric, Format), String> {
+        let mut parts = file.rsplitn(2, '.');
+        let format = match parts.next() {
+            Some("svg") => Format::Svg,
+            Some("json") => Format::Json,
+            _ => return Err(format!("unknown badge format `{}`", file)),
+        };
+        let metric = match parts.next() {
+            Some("status") => Metric::Status,
+            Some("uptime") => Metric::Uptime,
+            Some("latency") => Metric::Latency,
+            _ => return Err(format!("unknown badge `{}`", file)),
+        };
+
+        Ok((metric, format))
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Metric::Status => "status",
+            Metric::Uptime => "uptime",
+            Metric::Latency => "latency",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Badge {
+    schema_version: u8,
+    pub label: String,
+    pub message: String,
+    pub color: &'static str,
+}
+
+impl Badge {
+    fn new(label: &str, message: String, color: &'static str) -> Badge {
+        Badge {
+            schema_version: 1,
+            label: label.to_string(),
+            message,
+            color,
+        }
+    }
+
+    /// Badge of a target, or of all the targets in `targets` for tags. `latest` is the latest
+    /// check of every target.
+    pub fn build(
+        metric: Metric,
+        label: &str,
+        targets: &[&CanaryTarget],
+        latest: &[CanaryCheck],
+        storage: Option<&Storage>,
+        (from, to): (Timespec, Timespec),
+    ) -> Result<Badge, String> {
+        let latest = latest
+            .iter()
+            .filter(|c| targets.iter().any(|t| t.name == c.target.name))
+            .collect::<Vec<_>>();
+
+        match metric {
+            Metric::Status => Ok(status(label, &latest)),
+            Metric::Latency => Ok(latency(label, &latest)),
+            Metric::Uptime => {
+                let storage = storage.ok_or_else(|| "storage is not enabled".to_string())?;
+                let (mut checks, mut okay) = (0, 0);
+                for target in targets {
+                    let (c, o) = storage.availability(&target.name, from.sec, to.sec)?;
+                    checks += c;
+                    okay += o;
+                }
+
+                Ok(uptime(label, checks, okay))
+            }
+        }
+    }
+
+    pub fn response(&self, format: Format) -> Response {
+        let response = match format {
+            Format::Svg => Response::from_data("image/svg+xml; charset=utf-8", self.to_svg()),
+            Format::Json => Response::json(self),
+        };
+
+        // Image proxies such as GitHub's would otherwise keep showing stale statuses
+        response.with_additional_header("Cache-Control", "no-cache, max-age=0")
+    }
+
+    /// Flat shields.io style badge
+    pub fn to_svg(&self) -> String {
+        let label_width = self.label.chars().count() * CHAR_WIDTH + PADDING;
+        let message_width = self.message.chars().count() * CHAR_WIDTH + PADDING;
+        let width = label_width + message_width;
+        let (label, message) = (escape(&self.label), escape(&self.message));
+
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">
+<title>{label}: {message}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+<text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text>
+<text x="{message_x}" y="15" fill="#010101" fill-opacity=".3">{message}</text><text x="{message_x}" y="14">{message}</text>
+</g>
+</svg>
+"##,
+            width = width,
+            label_width = label_width,
+            message_width = message_width,
+            label = label,
+            message = message,
+            color = hex(self.color),
+            label_x = label_width / 2,
+            message_x = label_width + message_width / 2,
+        )
+    }
+}
+
+fn status(label: &str, latest: &[&CanaryCheck]) -> Badge {
+    let count = |status: Status| latest.iter().filter(|c| c.status == status).count();
+    let (fire, unknown) = (count(Status::Fire), count(Status::Unknown));
+
+    // Tags count their failing targets, single targets just show their status
+    let message = |n: usize, status: &str| match latest.len() {
+        1 => status.to_string(),
+        _ => format!("{} {}", n, status),
+    };
+
+    if latest.is_empty() {
+        Badge::new(label, "no data".to_string(), "lightgrey")
+    } else if fire > 0 {
+        Badge::new(label, message(fire, "fire"), "red")
+    } else if unknown > 0 {
+        Badge::new(label, message(unknown, "unknown"), "orange")
+    } else {
+        Badge::new(label, "okay".to_string(), "brightgreen")
+    }
+}
+
+fn latency(label: &str, latest: &[&CanaryCheck]) -> Badge {
+    match latest.iter().map(|c| c.latency_ms).max() {
+        None => Badge::new(label, "no data".to_string(), "lightgrey"),
+        Some(ms) => {
+            let color = match ms {
+                ms if ms < 300 => "brightgreen",
+                ms if ms < 1000 => "yellow",
+                _ => "red",
+            };
+            Badge::new(label, format!("{}ms", ms), color)
+        }
+    }
+}
+
+fn uptime(label: &str, checks: u64, okay: u64) -> Badge {
+    if checks == 0 {
+        return Badge::new(label, "no data".to_string(), "lightgrey");
+    }
+
+    let percent = okay as f64 * 100.0 / checks as f64;
+    let color = match percent {
+        p if p >= 99.9 => "brightgreen",
+        p if p >= 99.0 => "green",
+        p if p >= 95.0 => "yellow",
+        _ => "red",
+    };
+    let message = if okay == checks {
+        "100%".to_string()
+    } else {
+        format!("{:.2}%", percent)
+    };
+
+    Badge::new(label, message, color)
+}
+
+/// Default label, eg. `api uptime`
+pub fn label(name: &str, metric: Metric) -> String {
+    format!("{} {}", name, metric.label())
+}
+
+fn hex(color: &str) -> &'static str {
+    match color {
+        "brightgreen" => "#4c1",
+        "green" => "#97ca00",
+        "yellow" => "#dfb317",
+        "orange" => "#fe7d37",
+        "red" => "#e05d44",
+        _ => "#9f9f9f",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(name: &str, status: Status, latency_ms: u64) -> CanaryCheck {
+        CanaryCheck {
+            latency_ms,
+            ..crate::tests::check(name, status)
+        }
+    }
+
+    #[test]
+    fn it_parses_badge_files() {
+        assert_eq!(
+            Ok((Metric::Uptime, Format::Svg)),
+            Metric::parse("uptime.svg")
+        );
+        assert_eq!(
+            Ok((Metric::Status, Format::Json)),
+            Metric::parse("status.json")
+        );
+        assert!(Metric::parse("status.png").is_err());
+        assert!(Metric::parse("speed.svg").is_err());
+        assert!(Metric::parse("svg").is_err());
+    }
+
+    #[test]
+    fn it_summarises_targets() {
+        let okay = check("a", Status::Okay, 120);
+        let fire = check("b", Status::Fire, 1500);
+        let unknown = check("c", Status::Unknown, 400);
+
+        assert_eq!("okay", status("x", &[&okay]).message);
+        assert_eq!("fire", status("x", &[&fire]).message);
+        assert_eq!(
+            Badge::new("x", "1 fire".to_string(), "red"),
+            status("x", &[&okay, &fire, &unknown])
+        );
+        assert_eq!("1 unknown", status("x", &[&okay, &unknown]).message);
+        assert_eq!("no data", status("x", &[]).message);
+
+        assert_eq!(
+            Badge::new("x", "1500ms".to_string(), "red"),
+            latency("x", &[&okay, &fire])
+        );
+        assert_eq!("brightgreen", latency("x", &[&okay]).color);
+
+        assert_eq!(
+            Badge::new("x", "100%".to_string(), "brightgreen"),
+            uptime("x", 10, 10)
+        );
+        assert_eq!(
+            Badge::new("x", "99.50%".to_string(), "green"),
+            uptime("x", 200, 199)
+        );
+        assert_eq!("no data", uptime("x", 0, 0).message);
+    }
+
+    #[test]
+    fn it_renders_svg_and_json() {
+        let badge = Badge::new("a<b> status", "okay".to_string(), "brightgreen");
+
+        let svg = badge.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("a&lt;b&gt; status: okay"));
+        assert!(svg.contains("#4c1"));
+
+        assert_eq!(
+            r#"{"schemaVersion":1,"label":"a<b> status","message":"okay","color":"brightgreen"}"#,
+            serde_json::to_string(&badge).unwrap()
+        );
+    }
+}
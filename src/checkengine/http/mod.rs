@@ -95,13 +95,15 @@ async fn check_impl(
     // Do DNS lookup.  Check fails if DNS fails.
     // FIXME/XXX: this is blocking.
     let netloc: (&str, u16) = (&netloc.0, netloc.1);
+    let resolve_started_at = Instant::now();
     let addrs: Vec<_> = netloc.to_socket_addrs()?.map(|s| s.ip()).collect();
+    let resolved = (resolve_started_at, Instant::now());
     debug!("check_impl: addrs={:?}", addrs);
 
     let results: Vec<CheckResult> = join_all(
         addrs
             .into_iter()
-            .map(|s| connect_and_request(&check, s, target.clone())),
+            .map(|s| connect_and_request(&check, s, target.clone(), resolved)),
     )
     .await;
 
@@ -112,6 +114,7 @@ async fn connect_and_request(
     check: &HttpCheck,
     ip_addr: IpAddr,
     target: HttpTarget,
+    resolved: (Instant, Instant),
 ) -> CheckResult {
     use hyper::body::Body;
     use hyper::client::{Client, HttpConnector};
@@ -139,7 +142,7 @@ async fn connect_and_request(
     let resp: hyper::Response<_> = match client.request(request).compat().await {
         Ok(r) => r,
         Err(err) => {
-            let mut timeline = Vec::new();
+            let mut timeline = vec![dns_span(resolved)];
             if let Some(s) = conn_summary_handle.summary() {
                 if let Some(e) = s.connected_time() {
                     timeline.push(CheckTimeSpan {
@@ -164,7 +167,7 @@ async fn connect_and_request(
     };
 
     let finish_time = Instant::now();
-    let mut timeline = Vec::new();
+    let mut timeline = vec![dns_span(resolved)];
 
     let conn_summary = conn_summary_handle.summary().unwrap();
     let http_conn_time = conn_summary.connected_time().unwrap();
@@ -220,3 +223,12 @@ async fn connect_and_request(
         },
     )
 }
+
+// Every resolved address shares the one lookup
+fn dns_span((started_at, ended_at): (Instant, Instant)) -> CheckTimeSpan {
+    CheckTimeSpan {
+        name: "dns",
+        started_at,
+        ended_at,
+    }
+}
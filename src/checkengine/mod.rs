@@ -1,7 +1,7 @@
 use std::fmt;
 use std::io;
 use std::net::IpAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use futures::future::Future;
 
@@ -29,6 +29,20 @@ pub struct CheckTimeSpan {
     ended_at: Instant,
 }
 
+impl CheckTimeSpan {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.ended_at - self.started_at
+    }
+}
+
 impl fmt::Debug for CheckTimeSpan {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("CheckTimeSpan")
@@ -56,6 +70,27 @@ impl CheckResultElement {
     pub fn status_code(&self) -> u16 {
         self.status_code
     }
+
+    pub fn target(&self) -> IpAddr {
+        self.target
+    }
+
+    pub fn err_msg(&self) -> Option<&str> {
+        self.err_msg.as_deref()
+    }
+
+    /// Each step of the check, in order
+    pub fn timeline(&self) -> &[CheckTimeSpan] {
+        &self.timeline
+    }
+
+    /// Time from the start of the first span to the end of the last span
+    pub fn latency(&self) -> Duration {
+        match (self.timeline.first(), self.timeline.last()) {
+            (Some(first), Some(last)) => last.ended_at - first.started_at,
+            _ => Duration::new(0, 0),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -49,14 +49,19 @@
 
   var customServerAddress = getParameter('server');
   var customFilter = getParameter('filter');
+  var token = getParameter('token');
   var notifications = getParameter('notifications');
 
   var hostname = window.location.hostname;
-  var protocol = window.location.protocol == 'https' ? 'wss' : 'ws';
+  var protocol = window.location.protocol === 'https:' ? 'wss' : 'ws';
   var defaultPort = '8099';
   var defaultServerAddress = protocol + '://' + hostname + ':' + defaultPort;
 
-  var serverAddress = customServerAddress || defaultServerAddress;
+  // Set when the dashboard is served by rcanary itself
+  var injectedServerAddress = window.rcanaryServerAddress;
+  var serverAddress = customServerAddress || injectedServerAddress || defaultServerAddress;
+  // Browsers cannot send an Authorization header with websockets
+  var connectAddress = token ? serverAddress + '?token=' + encodeURIComponent(token) : serverAddress;
   var filter = customFilter && new RegExp(customFilter) || /.*/;
 
   if (notifications === "true") {
@@ -71,21 +76,135 @@
   }
 
   console.log('rcanary server address: ' + serverAddress);
-  console.log(customServerAddress ? 'set from URL hash' : 'set to default address as hash is empty');
+  console.log(customServerAddress ? 'set from URL parameter' :
+    injectedServerAddress ? 'set by rcanary' : 'set to default address as there is no server parameter');
   console.log('using tag filter: ' + filter);
 
+  var PROTOCOL_VERSION = 1;
   var targets = null;
   var retryHandlerID = null;
   var staleTimers = {};
+  var incidents = {};
+  var acknowledgements = [];
+
+  function formatDuration (seconds) {
+    if (seconds < 60) return seconds + 's';
+    if (seconds < 3600) return Math.round(seconds / 60) + 'm';
+    return Math.round(seconds / 360) / 10 + 'h';
+  }
+
+  function renderIncidents () {
+    document.querySelectorAll('.probe-target').forEach(function (targetEl) {
+      var name = targetEl.querySelector('.probe-name').textContent.trim();
+      var listEl = targetEl.querySelector('.probe-incidents');
+      listEl.innerHTML = '';
+
+      Object.keys(incidents)
+        .map(function (id) { return incidents[id]; })
+        .filter(function (i) { return i.target === name; })
+        .sort(function (a, b) { return b.id - a.id; })
+        .slice(0, 3)
+        .forEach(function (i) {
+          var item = document.createElement('li');
+          item.textContent = i.ended_at
+            ? i.peak_status + ' at ' + formatDatetime(i.started_at) + ' for ' + formatDuration(i.duration_s)
+            : i.peak_status + ' since ' + formatDatetime(i.started_at);
+          item.title = i.status_reasons.join('\n');
+          item.dataset.ongoing = !i.ended_at;
+          listEl.appendChild(item);
+        });
+    });
+  }
+
+  function showTargets (config) {
+    targets = config;
+    var template = document.querySelector('#probe-target');
+    var root = document.querySelector('#root');
+    root.innerHTML = '';
+
+    targets.http
+      .filter(function (t) {
+        return filter.test(t.tag);
+      })
+      .forEach(function (t) {
+        template.content.querySelector('.probe-name').textContent = t.name;
+        template.content.querySelector('.probe-target').dataset.host = t.host;
+        var clone = document.importNode(template.content, true);
+        root.appendChild(clone);
+      });
+  }
+
+  function showAcknowledgements () {
+    var acknowledged = {};
+    acknowledgements.forEach(function (a) {
+      acknowledged[a.target] = a;
+    });
+
+    document.querySelectorAll('.probe-target').forEach(function (targetEl) {
+      var name = targetEl.querySelector('.probe-name').textContent.trim();
+      var ack = acknowledged[name];
+
+      targetEl.dataset.acknowledged = ack != null;
+      targetEl.querySelector('.probe-acknowledgement').textContent = ack
+        ? 'Acked by ' + ack.author + (ack.comment ? ': ' + ack.comment : '')
+        : '';
+    });
+  }
+
+  function updateTarget (payload, shouldNotify) {
+    if (!filter.test(payload.target.tag)) {
+      return;
+    }
+
+    var selector = '.probe-target[data-host="' + payload.target.host + '"]';
+    var targetEl = document.querySelector(selector);
+    if (targetEl === null) {
+      return;
+    }
+
+    var time = formatDatetime(payload.time);
+    var timeout_s = 30000; // Rust timeout
+
+    if (shouldNotify && targetEl.dataset.updated != null && targetEl.dataset.status !== payload.status) {
+      notify(payload, targetEl.dataset);
+    }
+
+    targetEl.dataset.status = payload.status;
+    targetEl.dataset.updated = payload.time;
+
+    targetEl.dataset.stale = false;
+    clearTimeout(staleTimers[payload.target.host]);
+    staleTimers[payload.target.host] = setTimeout(function () {
+      targetEl.dataset.stale = true;
+    }, payload.target.interval_s * 1000 * 2 + timeout_s);
+
+    targetEl.querySelector('.probe-status').textContent = payload.status_code;
+    targetEl.querySelector('.probe-time').textContent = time;
+    targetEl.querySelector('.probe-latency').textContent =
+      payload.latency_ms != null
+        ? `${payload.latency_ms}ms`
+        : '?';
+    targetEl.querySelector('.probe-link').href = payload.target.host;
+    if (payload.status === 'Okay') {
+      targetEl.querySelector('.probe-last-okay').textContent = 'Last OK: ' + formatDatetime(payload.time);
+    }
+  }
 
   function makeConnection (ws) {
     ws.onopen = function () {
       console.log('Connection to ' + serverAddress + ' established');
 
       targets = null;
+      incidents = {};
+      acknowledgements = [];
       document.querySelector('#root').innerHTML = '';
       clearInterval(retryHandlerID);
       retryHandlerID = null;
+
+      // Have the server drop results for targets which are filtered out anyway
+      if (customFilter) {
+        ws.send(JSON.stringify({ type: 'subscribe', tag: customFilter }));
+      }
     };
 
     ws.onerror = function (e) {
@@ -99,7 +218,7 @@
         console.log('Starting reconnect process...');
         retryHandlerID = setInterval(function () {
           console.log('Attempting reconnect...');
-          makeConnection(new WebSocket(serverAddress));
+          makeConnection(new WebSocket(connectAddress));
         }, 10000);
       }
     };
@@ -116,61 +235,56 @@
         return;
       }
 
-      if (targets === null) {
-        // First message is the target list
-        targets = payload;
-        var template = document.querySelector('#probe-target');
-        var root = document.querySelector('#root');
-
-        targets.http
-          .filter(function (t) {
-            return filter.test(t.tag);
-          })
-          .forEach(function (t) {
-            template.content.querySelector('.probe-name').textContent = t.name;
-            template.content.querySelector('.probe-target').dataset.host = t.host;
-            var clone = document.importNode(template.content, true);
-            root.appendChild(clone);
+      if (payload.version !== PROTOCOL_VERSION) {
+        console.log('Unsupported message version ' + payload.version + ', expected ' + PROTOCOL_VERSION);
+        return;
+      }
+
+      switch (payload.type) {
+        case 'config':
+          // Sent again whenever targets are changed, which redraws them empty
+          var changed = targets !== null;
+          showTargets(payload.data);
+          if (changed) {
+            ws.send(JSON.stringify({ type: 'snapshot' }));
+            showAcknowledgements();
+            renderIncidents();
+          }
+          break;
+        case 'snapshot':
+          // Latest checks, so targets show up before their next check
+          payload.data.latest.forEach(function (check) {
+            updateTarget(check, false);
+          });
+          break;
+        case 'result':
+          updateTarget(payload.data, true);
+          break;
+        case 'controls':
+          acknowledgements = payload.data.acknowledgements;
+          showAcknowledgements();
+          break;
+        case 'incidents':
+        case 'incident':
+          // Recent incidents on connect, then each incident as it changes
+          [].concat(payload.data).forEach(function (i) {
+            incidents[i.id] = i;
           });
-      } else {
-        // Update to targets
-        if (!filter.test(payload.target.tag)) {
-          return;
-        }
-
-        var selector = '.probe-target[data-host="' + payload.target.host + '"]';
-        var targetEl = document.querySelector(selector);
-        var time = formatDatetime(payload.time);
-        var timeout_s = 30000; // Rust timeout
-
-        if (targetEl.dataset.updated != null && targetEl.dataset.status !== payload.status) {
-          notify(payload, targetEl.dataset);
-        }
-
-        targetEl.dataset.status = payload.status;
-        targetEl.dataset.updated = payload.time;
-
-        targetEl.dataset.stale = false;
-        clearTimeout(staleTimers[payload.target.host]);
-        staleTimers[payload.target.host] = setTimeout(function () {
-          targetEl.dataset.stale = true;
-        }, payload.target.interval_s * 1000 * 2 + timeout_s);
-
-        targetEl.querySelector('.probe-status').textContent = payload.status_code;
-        targetEl.querySelector('.probe-time').textContent = time;
-        targetEl.querySelector('.probe-latency').textContent =
-          payload.latency_ms != null
-            ? `${payload.latency_ms}ms`
-            : '?';
-        targetEl.querySelector('.probe-link').href = payload.target.host;
-        if (payload.status === 'Okay') {
-          targetEl.querySelector('.probe-last-okay').textContent = 'Last OK: ' + formatDatetime(payload.time);
-        }
+          renderIncidents();
+          break;
+        case 'subscription':
+        case 'pong':
+          break;
+        case 'error':
+          console.log('Server error: ' + payload.data);
+          break;
+        default:
+          console.log('Unknown message type ' + payload.type);
       }
     };
 
     return ws;
   }
 
-  makeConnection(new WebSocket(serverAddress));
+  makeConnection(new WebSocket(connectAddress));
 }());
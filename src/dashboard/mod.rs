@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use log::info;
+use rouille::{Request, Response};
+
+use crate::auth::{self, Authenticator};
+use crate::tls::{self, Tls};
+use librcanary::{CanaryAuthScope, CanaryConfig};
+
+const INDEX: &str = include_str!("index.html");
+const SCRIPT: &str = include_str!("js/rcanary.js");
+const STYLE: &str = include_str!("css/rcanary.css");
+
+/// Where the dashboard loads its script, the websocket URL is injected before it
+const SCRIPT_TAG: &str = r#"<script src="js/rcanary.js"></script>"#;
+
+/// Tells browsers where to find the websocket server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Websocket {
+    /// Configured URL, used as is
+    url: Option<String>,
+    port: Option<u16>,
+    secure: bool,
+}
+
+impl Websocket {
+    pub fn new(config: &CanaryConfig) -> Websocket {
+        Websocket {
+            url: config
+                .dashboard
+                .as_ref()
+                .and_then(|d| d.websocket_url.clone()),
+            port: config
+                .server_listen_address
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|addr| addr.port()),
+            secure: config.tls.as_ref().is_some_and(|t| t.enabled),
+        }
+    }
+
+    /// The websocket server on the host the dashboard was loaded from, taken from its
+    /// `Host` header
+    fn url(&self, host: Option<&str>) -> Option<String> {
+        if self.url.is_some() {
+            return self.url.clone();
+        }
+
+        let host = host?;
+        // Strip the dashboard's port, keeping IPv6 addresses in brackets
+        let hostname = match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host.split(':').next().unwrap_or(host),
+        };
+        let scheme = if self.secure { "wss" } else { "ws" };
+
+        match self.port {
+            Some(port) => Some(format!("{}://{}:{}", scheme, hostname, port)),
+            None => Some(format!("{}://{}", scheme, hostname)),
+        }
+    }
+}
+
+pub fn start_dashboard_server(
+    bind_to: &str,
+    websocket: Websocket,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<Arc<Tls>>,
+) {
+    let addr: SocketAddr = bind_to.parse().unwrap_or_else(|err| {
+        panic!("[status.startup] failed to start dashboard server: {}", err);
+    });
+
+    info!("[status.startup] starting dashboard server at {}...", &addr);
+
+    thread::spawn(move || {
+        tls::serve(addr, tls, move |request| {
+            match auth::check(auth.as_deref(), request, CanaryAuthScope::Read) {
+                Ok(()) => handle(request, &websocket),
+                Err(response) => response,
+            }
+        });
+    });
+}
+
+pub fn handle(request: &Request, websocket: &Websocket) -> Response {
+    if request.method() != "GET" {
+        return Response::empty_404();
+    }
+
+    match request.url().as_str() {
+        "/" | "/index.html" => Response::html(index(websocket.url(request.header("Host")))),
+        "/js/rcanary.js" => Response::from_data("application/javascript; charset=utf-8", SCRIPT),
+        "/css/rcanary.css" => Response::from_data("text/css; charset=utf-8", STYLE),
+        _ => Response::empty_404(),
+    }
+}
+
+// The dashboard prefers a `server` query parameter over the injected URL, and guesses
+// without either
+fn index(websocket_url: Option<String>) -> String {
+    let websocket_url = match websocket_url {
+        Some(url) => url,
+        None => return INDEX.to_string(),
+    };
+
+    // Keep `</script>` in the URL from ending the script early
+    let url = serde_json::to_string(&websocket_url)
+        .expect("strings are always serializable")
+        .replace('<', "\\u003c");
+
+    INDEX.replace(
+        SCRIPT_TAG,
+        &format!(
+            "<script>window.rcanaryServerAddress = {};</script>\n    {}",
+            url, SCRIPT_TAG
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn websocket(url: Option<&str>, secure: bool) -> Websocket {
+        Websocket {
+            url: url.map(|u| u.to_string()),
+            port: Some(8099),
+            secure,
+        }
+    }
+
+    fn get(url: &str, websocket: &Websocket) -> (u16, String) {
+        let headers = vec![("Host".to_string(), "status.example.com:8098".to_string())];
+        let response = handle(&Request::fake_http("GET", url, headers, vec![]), websocket);
+
+        let mut data = String::new();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        reader.read_to_string(&mut data).unwrap();
+
+        (response.status_code, data)
+    }
+
+    #[test]
+    fn it_points_browsers_at_the_websocket_server() {
+        let plain = websocket(None, false);
+        assert_eq!(
+            Some("ws://status.example.com:8099".to_string()),
+            plain.url(Some("status.example.com:8098"))
+        );
+        assert_eq!(
+            Some("ws://[::1]:8099".to_string()),
+            plain.url(Some("[::1]:8098"))
+        );
+        assert_eq!(None, plain.url(None));
+
+        assert_eq!(
+            Some("wss://status.example.com:8099".to_string()),
+            websocket(None, true).url(Some("status.example.com"))
+        );
+        assert_eq!(
+            Some("wss://example.com/ws".to_string()),
+            websocket(Some("wss://example.com/ws"), false).url(Some("localhost"))
+        );
+    }
+
+    #[test]
+    fn it_serves_the_dashboard() {
+        let (status_code, index) = get("/", &websocket(None, false));
+        assert_eq!(200, status_code);
+        assert!(index.contains(
+            r#"<script>window.rcanaryServerAddress = "ws://status.example.com:8099";</script>"#
+        ));
+        assert!(index.contains(SCRIPT_TAG));
+
+        assert_eq!(SCRIPT, get("/js/rcanary.js", &websocket(None, false)).1);
+        assert_eq!(STYLE, get("/css/rcanary.css", &websocket(None, false)).1);
+        assert_eq!(404, get("/secrets", &websocket(None, false)).0);
+    }
+
+    #[test]
+    fn it_escapes_the_injected_url() {
+        let index = index(Some("ws://x/</script><script>alert(1)".to_string()));
+
+        assert!(!index.contains("</script><script>alert"));
+    }
+}
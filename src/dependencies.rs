@@ -0,0 +1,181 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use librcanary::CanaryTarget;
+
+/// Targets' `depends_on` relationships, keyed by target name
+pub struct DependencyGraph {
+    parents: HashMap<String, Vec<String>>,
+}
+
+impl DependencyGraph {
+    /// Builds the graph, rejecting unknown dependencies and dependency cycles
+    pub fn new(targets: &[CanaryTarget]) -> Result<DependencyGraph, String> {
+        let mut parents: HashMap<String, Vec<String>> = HashMap::new();
+        for target in targets {
+            parents
+                .entry(target.name.clone())
+                .or_default()
+                .extend(target.depends_on.iter().cloned());
+        }
+
+        for (name, dependencies) in &parents {
+            for dependency in dependencies {
+                if !parents.contains_key(dependency) {
+                    return Err(format!(
+                        "target `{}` depends on unknown target `{}`",
+                        name, dependency
+                    ));
+                }
+            }
+        }
+
+        let graph = DependencyGraph { parents };
+        if let Some(cycle) = graph.find_cycle() {
+            return Err(format!("dependency cycle: {}", cycle.join(" -> ")));
+        }
+
+        Ok(graph)
+    }
+
+    /// Returns the shortest chain of dependencies from `name` to an ancestor for which
+    /// `is_failing` holds, nearest first. Empty if no ancestor is failing.
+    pub fn blocked_by<F>(&self, name: &str, is_failing: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(vec![name.to_string()]);
+
+        while let Some(chain) = queue.pop_front() {
+            let last = chain.last().unwrap();
+            for parent in self.parents.get(last).into_iter().flatten() {
+                if !visited.insert(parent.clone()) {
+                    continue;
+                }
+
+                let mut next = chain.clone();
+                next.push(parent.clone());
+
+                if is_failing(parent) {
+                    next.remove(0);
+                    return next;
+                }
+                queue.push_back(next);
+            }
+        }
+
+        vec![]
+    }
+
+    fn find_cycle(&self) -> Option<Vec<String>> {
+        let mut done = HashSet::new();
+        let mut names = self.parents.keys().collect::<Vec<_>>();
+        names.sort();
+
+        for name in names {
+            let mut path = vec![];
+            if let Some(cycle) = self.visit(name, &mut path, &mut done) {
+                return Some(cycle);
+            }
+        }
+
+        None
+    }
+
+    // Depth-first search, `path` holds the names currently being visited
+    fn visit<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle = path[start..]
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+
+        if done.contains(name) {
+            return None;
+        }
+
+        path.push(name);
+        for parent in self.parents.get(name).into_iter().flatten() {
+            if let Some(cycle) = self.visit(parent, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(name);
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::target;
+
+    fn targets(dependencies: &[(&str, &[&str])]) -> Vec<CanaryTarget> {
+        dependencies
+            .iter()
+            .map(|(name, depends_on)| {
+                let mut t = target();
+                t.name = name.to_string();
+                t.depends_on = depends_on.iter().map(|d| d.to_string()).collect();
+                t
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_finds_the_chain_to_a_failing_ancestor() {
+        let graph = DependencyGraph::new(&targets(&[
+            ("lb", &[]),
+            ("api", &["lb"]),
+            ("web", &["api", "cdn"]),
+            ("cdn", &[]),
+        ]))
+        .unwrap();
+
+        assert_eq!(vec!["api", "lb"], graph.blocked_by("web", |n| n == "lb"));
+        assert_eq!(vec!["cdn"], graph.blocked_by("web", |n| n == "cdn"));
+        assert!(graph.blocked_by("lb", |_| true).is_empty());
+        assert!(graph.blocked_by("web", |_| false).is_empty());
+    }
+
+    #[test]
+    fn it_rejects_unknown_dependencies() {
+        let actual = DependencyGraph::new(&targets(&[("api", &["lb"])]));
+
+        assert_eq!(
+            Some("target `api` depends on unknown target `lb`".to_string()),
+            actual.err()
+        );
+    }
+
+    #[test]
+    fn it_rejects_dependency_cycles() {
+        let actual = DependencyGraph::new(&targets(&[
+            ("a", &["b"]),
+            ("b", &["c"]),
+            ("c", &["a"]),
+            ("d", &[]),
+        ]));
+
+        assert_eq!(
+            Some("dependency cycle: a -> b -> c -> a".to_string()),
+            actual.err()
+        );
+    }
+
+    #[test]
+    fn it_rejects_self_dependencies() {
+        assert!(DependencyGraph::new(&targets(&[("a", &["a"])])).is_err());
+    }
+}
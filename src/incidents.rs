@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+use log::info;
+
+use crate::alerter::alert::parse_check_time;
+use crate::storage::Storage;
+use librcanary::{CanaryAcknowledgement, CanaryCheck, CanaryIncident, Status};
+
+/// Closed incidents kept in memory, older ones are only in storage
+const RECENT_INCIDENTS: usize = 100;
+
+/// Turns status transitions into incidents, saving them to storage if it is enabled
+pub struct IncidentTracker {
+    open: BTreeMap<String, CanaryIncident>,
+    /// Closed incidents, oldest first
+    recent: VecDeque<CanaryIncident>,
+    next_id: u64,
+    storage: Option<Arc<Storage>>,
+}
+
+impl IncidentTracker {
+    /// Picks up ongoing and recent incidents from storage
+    pub fn new(storage: Option<Arc<Storage>>) -> Result<IncidentTracker, String> {
+        let mut tracker = IncidentTracker {
+            open: BTreeMap::new(),
+            recent: VecDeque::new(),
+            next_id: 0,
+            storage,
+        };
+
+        if let Some(ref storage) = tracker.storage {
+            for mut incident in storage.open_incidents()? {
+                tracker.next_id = tracker.next_id.max(incident.id + 1);
+                // Ongoing incidents are only saved when they change, not on every check
+                incident.duration_s = (time::get_time() - parse_check_time(&incident.started_at))
+                    .num_seconds()
+                    .max(0) as u64;
+                tracker.open.insert(incident.target.clone(), incident);
+            }
+
+            for incident in storage
+                .recent_incidents(RECENT_INCIDENTS)?
+                .into_iter()
+                .rev()
+            {
+                tracker.next_id = tracker.next_id.max(incident.id + 1);
+                tracker.recent.push_back(incident);
+            }
+        }
+
+        Ok(tracker)
+    }
+
+    /// Opens, updates or closes the target's incident. Returns the incident if anything
+    /// worth telling clients about changed.
+    pub fn update(&mut self, result: &CanaryCheck) -> Option<CanaryIncident> {
+        let name = &result.target.name;
+
+        if result.status == Status::Okay {
+            return self.close(name, &result.time);
+        }
+
+        let next_id = &mut self.next_id;
+        let mut changed = false;
+        let incident = self.open.entry(name.clone()).or_insert_with(|| {
+            changed = true;
+            *next_id += 1;
+
+            CanaryIncident {
+                acknowledgements: vec![],
+                alerts_sent: 0,
+                duration_s: 0,
+                ended_at: None,
+                id: *next_id - 1,
+                peak_status: result.status.clone(),
+                public_note: None,
+                started_at: result.time.clone(),
+                status_reasons: vec![],
+                target: name.clone(),
+            }
+        });
+
+        incident.duration_s = seconds_between(&incident.started_at, &result.time);
+        if result.status == Status::Fire && incident.peak_status != Status::Fire {
+            incident.peak_status = Status::Fire;
+            changed = true;
+        }
+        if !result.status_reason.is_empty()
+            && !incident.status_reasons.contains(&result.status_reason)
+        {
+            incident.status_reasons.push(result.status_reason.clone());
+            changed = true;
+        }
+
+        // The duration alone is not worth a write, it follows from `started_at`
+        if changed {
+            let incident = incident.clone();
+            self.save(&incident);
+            Some(incident)
+        } else {
+            None
+        }
+    }
+
+    /// Ends the target's ongoing incident, for recoveries and targets which are no longer
+    /// checked
+    pub fn close(&mut self, target: &str, time: &str) -> Option<CanaryIncident> {
+        let mut incident = self.open.remove(target)?;
+        incident.duration_s = seconds_between(&incident.started_at, time);
+        incident.ended_at = Some(time.to_string());
+
+        self.save(&incident);
+        self.recent.push_back(incident.clone());
+        if self.recent.len() > RECENT_INCIDENTS {
+            self.recent.pop_front();
+        }
+
+        Some(incident)
+    }
+
+    /// Counts an alert for the target's ongoing incident, or the one which just ended
+    /// for recovery alerts
+    pub fn alert_sent(&mut self, target: &str) -> Option<CanaryIncident> {
+        let incident = match self.open.get_mut(target) {
+            Some(incident) => incident,
+            None => self.recent.iter_mut().rev().find(|i| i.target == target)?,
+        };
+        incident.alerts_sent += 1;
+
+        let incident = incident.clone();
+        self.save(&incident);
+        Some(incident)
+    }
+
+    pub fn acknowledged(
+        &mut self,
+        acknowledgement: &CanaryAcknowledgement,
+    ) -> Option<CanaryIncident> {
+        let incident = self.open.get_mut(&acknowledgement.target)?;
+        incident.acknowledgements.push(acknowledgement.clone());
+
+        let incident = incident.clone();
+        self.save(&incident);
+        Some(incident)
+    }
+
+    /// Sets or clears the note shown on the public status page
+    pub fn set_public_note(
+        &mut self,
+        id: u64,
+        note: Option<String>,
+    ) -> Result<Option<CanaryIncident>, String> {
+        let found = self
+            .open
+            .values_mut()
+            .chain(self.recent.iter_mut())
+            .find(|i| i.id == id);
+
+        let incident = match found {
+            Some(incident) => {
+                incident.public_note = note;
+                incident.clone()
+            }
+            // Older incidents are only in storage
+            None => match self.get(id)? {
+                Some(mut incident) => {
+                    incident.public_note = note;
+                    incident
+                }
+                None => return Ok(None),
+            },
+        };
+
+        self.save(&incident);
+        Ok(Some(incident))
+    }
+
+    /// Ongoing incidents, then recently closed ones, newest first
+    pub fn list(&self, target: Option<&str>, limit: usize) -> Vec<CanaryIncident> {
+        let mut open = self.open.values().collect::<Vec<_>>();
+        open.sort_by_key(|i| std::cmp::Reverse(i.id));
+
+        open.into_iter()
+            .chain(self.recent.iter().rev())
+            .filter(|i| target.is_none_or(|t| i.target == t))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<CanaryIncident>, String> {
+        let found = self
+            .open
+            .values()
+            .chain(self.recent.iter())
+            .find(|i| i.id == id);
+
+        match (found, &self.storage) {
+            (Some(incident), _) => Ok(Some(incident.clone())),
+            (None, Some(storage)) => storage.incident(id),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn save(&self, incident: &CanaryIncident) {
+        if let Some(ref storage) = self.storage {
+            if let Err(err) = storage.save_incident(incident) {
+                info!("[storage.error] failed to save incident: {}", err);
+            }
+        }
+    }
+}
+
+fn seconds_between(start: &str, end: &str) -> u64 {
+    (parse_check_time(end) - parse_check_time(start))
+        .num_seconds()
+        .max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librcanary::CanaryStorageConfig;
+
+    fn check(status: Status, reason: &str, minute: u32) -> CanaryCheck {
+        CanaryCheck {
+            status_reason: reason.to_string(),
+            time: format!("2016-10-14T08:{:02}:00Z", minute),
+            ..crate::tests::check("foo", status)
+        }
+    }
+
+    fn acknowledgement() -> CanaryAcknowledgement {
+        CanaryAcknowledgement {
+            author: "alice".to_string(),
+            comment: "on it".to_string(),
+            target: "foo".to_string(),
+            time: "2016-10-14T08:02:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn it_tracks_incidents_from_transitions() {
+        let mut tracker = IncidentTracker::new(None).unwrap();
+
+        assert_eq!(None, tracker.update(&check(Status::Okay, "", 0)));
+
+        let opened = tracker
+            .update(&check(Status::Unknown, "timeout", 1))
+            .unwrap();
+        assert_eq!(None, opened.ended_at);
+        assert_eq!(Status::Unknown, opened.peak_status);
+
+        assert_eq!(None, tracker.update(&check(Status::Unknown, "timeout", 2)));
+        let escalated = tracker.update(&check(Status::Fire, "500", 3)).unwrap();
+        assert_eq!(Status::Fire, escalated.peak_status);
+        assert_eq!(vec!["timeout", "500"], escalated.status_reasons);
+
+        tracker.alert_sent("foo");
+        tracker.acknowledged(&acknowledgement());
+
+        let closed = tracker.update(&check(Status::Okay, "", 11)).unwrap();
+        assert_eq!(Some("2016-10-14T08:11:00Z".to_string()), closed.ended_at);
+        assert_eq!(600, closed.duration_s);
+        assert_eq!(1, closed.acknowledgements.len());
+
+        // The recovery alert goes to the incident which just closed
+        assert_eq!(2, tracker.alert_sent("foo").unwrap().alerts_sent);
+        assert_eq!(None, tracker.acknowledged(&acknowledgement()));
+    }
+
+    #[test]
+    fn it_lists_ongoing_incidents_first() {
+        let mut tracker = IncidentTracker::new(None).unwrap();
+        tracker.update(&check(Status::Fire, "", 0));
+        tracker.update(&check(Status::Okay, "", 1));
+        tracker.update(&check(Status::Fire, "", 2));
+
+        let actual = tracker.list(Some("foo"), 10);
+
+        assert_eq!(vec![1, 0], actual.iter().map(|i| i.id).collect::<Vec<_>>());
+        assert!(tracker.list(Some("bar"), 10).is_empty());
+        assert_eq!(1, tracker.list(None, 1).len());
+        assert_eq!(None, tracker.get(5).unwrap());
+    }
+
+    #[test]
+    fn it_closes_incidents_of_removed_targets() {
+        let mut tracker = IncidentTracker::new(None).unwrap();
+        tracker.update(&check(Status::Fire, "", 0));
+
+        let closed = tracker.close("foo", "2016-10-14T08:05:00Z").unwrap();
+        assert_eq!(Some("2016-10-14T08:05:00Z".to_string()), closed.ended_at);
+        assert_eq!(300, closed.duration_s);
+        assert_eq!(None, tracker.close("foo", "2016-10-14T08:06:00Z"));
+        assert!(tracker.list(Some("foo"), 10)[0].ended_at.is_some());
+    }
+
+    #[test]
+    fn it_restores_incidents_from_storage() {
+        let storage = Arc::new(
+            Storage::open(&CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+
+        let mut tracker = IncidentTracker::new(Some(storage.clone())).unwrap();
+        tracker.update(&check(Status::Fire, "", 0));
+        tracker.update(&check(Status::Okay, "", 1));
+        tracker.update(&check(Status::Fire, "500", 2));
+        tracker.update(&check(Status::Fire, "500", 3));
+        assert_eq!(0, storage.incident(1).unwrap().unwrap().duration_s);
+
+        let mut restored = IncidentTracker::new(Some(storage.clone())).unwrap();
+
+        assert_eq!(2, restored.list(None, 10).len());
+        assert!(restored.get(0).unwrap().unwrap().ended_at.is_some());
+        assert!(restored.get(1).unwrap().unwrap().duration_s > 60);
+        let closed = restored.update(&check(Status::Okay, "", 4)).unwrap();
+        assert_eq!(1, closed.id);
+        assert_eq!(vec!["500"], closed.status_reasons);
+        assert_eq!(2, restored.update(&check(Status::Fire, "", 5)).unwrap().id);
+    }
+
+    #[test]
+    fn it_keeps_ongoing_incidents_however_old() {
+        let storage = Arc::new(
+            Storage::open(&CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+
+        let mut tracker = IncidentTracker::new(Some(storage.clone())).unwrap();
+        tracker.update(&check(Status::Fire, "", 0));
+        for minute in 1..=RECENT_INCIDENTS + 1 {
+            for status in &[Status::Fire, Status::Okay] {
+                tracker.update(&CanaryCheck {
+                    time: format!("2016-10-14T{:02}:{:02}:00Z", 9 + minute / 60, minute % 60),
+                    ..crate::tests::check("bar", status.clone())
+                });
+            }
+        }
+
+        let restored = IncidentTracker::new(Some(storage.clone())).unwrap();
+        assert_eq!(0, restored.list(Some("foo"), 1)[0].id);
+        assert_eq!(None, restored.list(Some("foo"), 1)[0].ended_at);
+
+        storage.compact(time::now_utc().to_timespec()).unwrap();
+        let restored = IncidentTracker::new(Some(storage)).unwrap();
+        assert_eq!(1, restored.list(None, usize::MAX).len());
+    }
+
+    #[test]
+    fn it_sets_public_notes() {
+        let mut tracker = IncidentTracker::new(None).unwrap();
+        tracker.update(&check(Status::Fire, "", 0));
+
+        let noted = tracker
+            .set_public_note(0, Some("Investigating".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("Investigating".to_string()), noted.public_note);
+
+        let closed = tracker.update(&check(Status::Okay, "", 1)).unwrap();
+        assert_eq!(Some("Investigating".to_string()), closed.public_note);
+
+        assert_eq!(
+            None,
+            tracker
+                .set_public_note(0, None)
+                .unwrap()
+                .unwrap()
+                .public_note
+        );
+        assert_eq!(None, tracker.set_public_note(7, None).unwrap());
+    }
+}
@@ -1,17 +1,37 @@
-// Crate is not updated properly for Rust 2018
-#[macro_use]
-extern crate prometheus;
-
 mod alerter;
+mod api;
+mod auth;
+mod badge;
 mod checkengine;
+mod dashboard;
+mod dependencies;
+mod incidents;
 mod metrics;
+mod recent;
+mod report;
+mod sse;
+mod status_page;
+mod storage;
+mod targets;
+mod tls;
 mod ws_handler;
 
+use alerter::AlertGroup;
+use auth::Authenticator;
 use checkengine::{Check, CheckResultElement, CheckStatus, HttpCheck, HttpTarget};
+use dependencies::DependencyGraph;
+use incidents::IncidentTracker;
+use metrics::otlp::OtlpMetrics;
 use metrics::prometheus::PrometheusMetrics;
+use metrics::push::PushMetrics;
+use metrics::statsd::StatsdMetrics;
 use metrics::Metrics;
+use status_page::StatusPage;
+use storage::Storage;
+use targets::Targets;
+use tls::Tls;
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::fs::File;
@@ -19,14 +39,14 @@ use std::io;
 use std::io::Read;
 use std::net::SocketAddr;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use docopt::Docopt;
 use futures::compat::Compat;
 use futures01::future::Future;
-use hyper::header::AUTHORIZATION;
+use hyper::header::{HeaderName, AUTHORIZATION};
 use hyper::StatusCode;
 use librcanary::*;
 use log::info;
@@ -34,6 +54,11 @@ use log::info;
 use serde::Deserialize;
 
 const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// `status_reason` of checks of targets whose host is not a valid URL
+const STATUS_REASON_BAD_URL: &str = "bad url";
+const STORAGE_COMPACT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Checks waiting to be saved, beyond which checks are dropped rather than held in memory
+const STORAGE_QUEUE_SIZE: usize = 1000;
 
 const USAGE: &str = "
 rcanary
@@ -41,15 +66,23 @@ A minimal monitoring program with email alerts
 
 Usage:
   rcanary <configuration-file>
+  rcanary report <configuration-file> [--from=<time>] [--to=<time>] [--format=<format>]
   rcanary (-h | --help)
 
 Options:
-  -h --help     Show this screen.
+  -h --help          Show this screen.
+  --from=<time>      Start of the report, eg. 2019-01-01T00:00:00Z. Defaults to 30 days before --to.
+  --to=<time>        End of the report. Defaults to now.
+  --format=<format>  json, csv or markdown [default: json].
 ";
 
 #[derive(Deserialize, Debug)]
 struct Args {
     arg_configuration_file: String,
+    cmd_report: bool,
+    flag_from: Option<String>,
+    flag_to: Option<String>,
+    flag_format: String,
 }
 
 fn main() {
@@ -69,102 +102,554 @@ fn main() {
         })
         .unwrap();
 
-    let metrics_handler = if config.metrics.is_some() && config.clone().metrics.unwrap().enabled {
-        // TODO: handle multiple types of metrics handlers
-        Arc::new(Some(metrics::prometheus::PrometheusMetrics::new(
-            &config.targets,
-        )))
-    } else {
-        Arc::new(None)
-    };
+    if args.cmd_report {
+        if let Err(err) = print_report(&config, &args) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let initial_targets = initial_targets(&config)
+        .unwrap_or_else(|err| panic!("[status.startup] failed to read targets: {}", err));
+
+    // Every backend is updated after each check, Prometheus is also scraped
+    let mut metrics_handlers: Vec<Arc<dyn Metrics>> = vec![];
+    let mut prometheus_handler: Option<Arc<dyn Metrics>> = None;
+    if let Some(ref metrics_config) = config.metrics {
+        if metrics_config.enabled {
+            let handler: Arc<dyn Metrics> = Arc::new(PrometheusMetrics::new(
+                metrics_config,
+                &CanaryTargetTypes {
+                    http: initial_targets.clone(),
+                },
+            ));
+            prometheus_handler = Some(handler.clone());
+            metrics_handlers.push(handler);
+        }
+
+        if let Some(ref push_config) = metrics_config.push {
+            if push_config.enabled {
+                let handler = PushMetrics::new(push_config).unwrap_or_else(|err| {
+                    panic!("[status.startup] failed to start push metrics: {}", err)
+                });
+                metrics_handlers.push(Arc::new(handler));
+            }
+        }
+
+        if let Some(ref statsd_config) = metrics_config.statsd {
+            if statsd_config.enabled {
+                let handler = StatsdMetrics::new(statsd_config).unwrap_or_else(|err| {
+                    panic!("[status.startup] failed to start statsd metrics: {}", err)
+                });
+                metrics_handlers.push(Arc::new(handler));
+            }
+        }
+    }
+
+    let mut send_traceparent = false;
+    if let Some(ref otlp_config) = config.otlp {
+        if otlp_config.enabled {
+            let handler = OtlpMetrics::new(otlp_config).unwrap_or_else(|err| {
+                panic!("[status.startup] failed to start OTLP exporter: {}", err)
+            });
+            metrics_handlers.push(Arc::new(handler));
+            send_traceparent = otlp_config.traceparent;
+        }
+    }
+
+    let alert_dispatcher = alerter::dispatch::AlertDispatcher::start(
+        &config.alert.delivery,
+        alerter::alert::receivers(&config),
+        metrics_handlers.clone(),
+    );
+
+    let mut alert_grouper = alerter::group::AlertGrouper::new(&config.alert.grouping);
 
-    // Setup map to save results
+    let recent_checks = Arc::new(Mutex::new(recent::RecentChecks::new()));
+
+    // Setup map to save results, keyed by target name so they survive changes to a target
     let mut last_statuses = HashMap::new();
+    let mut suppressed = HashSet::new();
+    // Targets whose current failure was alerted on, the only ones to send a resolution for
+    let mut alerted = HashSet::new();
+    let mut outage_starts = HashMap::new();
+
+    let storage = match config.storage {
+        Some(ref storage_config) if storage_config.enabled => Some(Arc::new(
+            Storage::open(storage_config)
+                .unwrap_or_else(|err| panic!("[status.startup] failed to open storage: {}", err)),
+        )),
+        _ => None,
+    };
 
-    // Start polling
-    let (poll_tx, poll_rx) = mpsc::channel();
+    let alert_controls = Arc::new(Mutex::new(
+        alerter::silence::AlertControls::new(storage.clone()).unwrap_or_else(|err| {
+            panic!("[status.startup] failed to restore alert controls: {}", err)
+        }),
+    ));
+
+    if let Some(ref storage) = storage {
+        // Restore statuses from before a restart so ongoing outages are not alerted again
+        let last_checks = storage
+            .last_checks()
+            .unwrap_or_else(|err| panic!("[status.startup] failed to restore statuses: {}", err));
+        for check in last_checks {
+            if initial_targets.iter().any(|t| t.name == check.target.name) {
+                alert_controls.lock().unwrap().update(&check);
+                if check.status != Status::Okay {
+                    let failing_since =
+                        storage
+                            .failing_since(&check.target.name)
+                            .unwrap_or_else(|err| {
+                                panic!("[status.startup] failed to restore outages: {}", err)
+                            });
+                    if let Some(failing_since) = failing_since {
+                        outage_starts.insert(check.target.name.clone(), failing_since);
+                    }
+                }
+                last_statuses.insert(check.target.name.clone(), check.status.clone());
+                recent_checks.lock().unwrap().push(check);
+            }
+        }
+        info!(
+            "[status.startup] restored {} target statuses",
+            last_statuses.len()
+        );
 
-    for http_target in config.targets.http.clone() {
-        let child_poll_tx = poll_tx.clone();
-        let child_metrics = metrics_handler.clone();
+        info!(
+            "[status.startup] restored {} ongoing outages",
+            outage_starts.len()
+        );
 
+        let compacting = storage.clone();
         thread::spawn(move || loop {
-            let result = check_host(&http_target);
-
-            if let Ok(Some(handler)) = Arc::try_unwrap(child_metrics.clone()) {
-                // It's okay if metrics fail to update (maybe?)
-                let _ = handler.update(&http_target.tag_metric.clone().unwrap(), &result);
+            match compacting.compact(time::get_time()) {
+                Ok(compaction) => info!("[storage.compact] {:?}", compaction),
+                Err(err) => info!("[storage.error] failed to compact: {}", err),
             }
+            thread::sleep(STORAGE_COMPACT_INTERVAL);
+        });
+    }
 
-            let _ = child_poll_tx.send(result);
-            thread::sleep(Duration::new(http_target.interval_s, 0));
+    // Checks are saved on their own thread so a slow disk does not hold up alerts
+    let storage_writer = storage.clone().map(|storage| {
+        let (writer_tx, writer_rx) =
+            mpsc::sync_channel::<(CanaryCheck, Option<Status>)>(STORAGE_QUEUE_SIZE);
+        thread::spawn(move || {
+            for (result, previous_status) in writer_rx {
+                if let Err(err) = storage.record(&result, previous_status.as_ref()) {
+                    info!("[storage.error] failed to save check: {}", err);
+                }
+            }
         });
+        writer_tx
+    });
+
+    let incidents = Arc::new(Mutex::new(
+        IncidentTracker::new(storage.clone())
+            .unwrap_or_else(|err| panic!("[status.startup] failed to restore incidents: {}", err)),
+    ));
+    for incident in incidents.lock().unwrap().list(None, usize::MAX) {
+        if incident.ended_at.is_none() && incident.alerts_sent > 0 {
+            alerted.insert(incident.target);
+        }
     }
 
+    let clients = ws_handler::Clients::new(&CanaryTargetTypes {
+        http: initial_targets.clone(),
+    });
+
+    // Start polling, targets added later through the API are polled the same way
+    let (poll_tx, poll_rx) = mpsc::channel();
+    let poll_tx = Mutex::new(poll_tx);
+    let poller: targets::Poller = Box::new(move |http_target, triggered| {
+        let child_poll_tx = poll_tx.lock().unwrap().clone();
+
+        thread::spawn(move || {
+            // Checks asked for through the API or websocket, answered with the next result
+            let mut triggers = vec![];
+
+            loop {
+                let result = check_host(&http_target, send_traceparent);
+                let _ = child_poll_tx.send((result, std::mem::take(&mut triggers)));
+                match triggered.recv_timeout(Duration::new(http_target.interval_s, 0)) {
+                    Ok(trigger) => {
+                        triggers.push(trigger);
+                        triggers.extend(triggered.try_iter());
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+    });
+
+    // Targets which are no longer checked are forgotten by the main loop
+    let mut checked = checked_names(&initial_targets);
+    let (changed_tx, changed_rx) = mpsc::channel();
+    let changed_tx = Mutex::new(changed_tx);
+    let changed_clients = clients.clone();
+    let changed_recent = recent_checks.clone();
+    let changed_metrics = metrics_handlers.clone();
+    let targets = Arc::new(
+        Targets::new(
+            initial_targets,
+            config.api.as_ref().and_then(|api| api.targets_file.clone()),
+            poller,
+            Box::new(move |targets| {
+                changed_recent
+                    .lock()
+                    .unwrap()
+                    .retain(|name| targets.http.iter().any(|t| t.name == name));
+                changed_clients.targets_changed(targets);
+                for handler in &changed_metrics {
+                    handler.targets_changed(targets);
+                }
+                let _ = changed_tx
+                    .lock()
+                    .unwrap()
+                    .send(checked_names(&targets.http));
+            }),
+        )
+        .unwrap_or_else(|err| panic!("[status.startup] invalid targets: {}", err)),
+    );
+
+    let auth = match config.auth {
+        Some(ref auth_config) if auth_config.enabled => Some(Arc::new(
+            Authenticator::new(auth_config)
+                .unwrap_or_else(|err| panic!("[status.startup] invalid auth config: {}", err)),
+        )),
+        _ => None,
+    };
+
+    let tls = match config.tls {
+        Some(ref tls_config) if tls_config.enabled => {
+            let tls = Arc::new(Tls::new(tls_config).unwrap_or_else(|err| {
+                panic!("[status.startup] failed to load certificate: {}", err)
+            }));
+            tls::reload_on_sighup(tls.clone());
+            Some(tls)
+        }
+        _ => None,
+    };
+
     if let Some(ref health_check_config) = config.health_check {
         if health_check_config.enabled {
-            start_healthcheck_server(&health_check_config.address);
+            start_healthcheck_server(&health_check_config.address, auth.clone(), tls.clone());
         }
     }
 
-    if let Some(ref metrics_config) = config.metrics {
-        if metrics_config.enabled {
-            start_metrics_server(&metrics_config.address, metrics_handler);
-        }
+    if let (Some(ref metrics_config), Some(ref handler)) = (&config.metrics, &prometheus_handler) {
+        start_metrics_server(
+            &metrics_config.address,
+            handler.clone(),
+            auth.clone(),
+            tls.clone(),
+        );
     }
 
     // Start up websocket server
     info!("[status.startup] starting websocker server...");
-    let me = ws::WebSocket::new(ws_handler::ClientFactory {
-        config: config.clone(),
-    })
-    .unwrap_or_else(|err| {
-        panic!("[status.startup] failed to start websocket server {}", err);
-    });
-    info!("[status.startup] started websocker server.");
-    let broadcaster = me.broadcaster();
-    let config_clone = config.clone();
-    thread::spawn(move || {
-        me.listen(&*config_clone.server_listen_address)
-            .unwrap_or_else(|err| {
-                panic!(
-                    "[status.startup] failed to start websocket listener {}",
-                    err
-                );
-            });
-    });
+    ws_handler::start_ws_server(
+        &config.server_listen_address,
+        ws_handler::ClientFactory {
+            auth: auth.clone(),
+            clients: clients.clone(),
+            controls: alert_controls.clone(),
+            incidents: incidents.clone(),
+            recent: recent_checks.clone(),
+            targets: targets.clone(),
+        },
+        tls.clone(),
+    );
     info!("[status.startup] started websocket listener.");
 
+    let status_page = match config.status_page {
+        Some(ref status_page_config) if status_page_config.enabled => {
+            let status_page = Arc::new(
+                StatusPage::new(
+                    status_page_config,
+                    &targets.list(),
+                    incidents.clone(),
+                    recent_checks.clone(),
+                    storage.clone(),
+                )
+                .unwrap_or_else(|err| {
+                    panic!("[status.startup] invalid status page config: {}", err)
+                }),
+            );
+            if let Some(ref address) = status_page_config.address {
+                status_page::start_status_page_server(address, status_page.clone(), tls.clone());
+            }
+            Some(status_page)
+        }
+        _ => None,
+    };
+
+    let events = match config.sse {
+        Some(ref sse_config) if sse_config.enabled => {
+            let events = Arc::new(sse::Events::new(sse_config.buffer_size));
+            sse::start_sse_server(
+                &sse_config.address,
+                events.clone(),
+                auth.clone(),
+                tls.clone(),
+            );
+            Some(events)
+        }
+        _ => None,
+    };
+
+    let api_state = api::ApiState {
+        auth,
+        public_badges: config.api.as_ref().is_some_and(|api| api.public_badges),
+        controls: alert_controls.clone(),
+        incidents: incidents.clone(),
+        recent: recent_checks.clone(),
+        status_page: status_page.clone(),
+        storage: storage.clone(),
+        targets: targets.clone(),
+        broadcast: {
+            let clients = clients.clone();
+            Arc::new(move |message| clients.broadcast(message))
+        },
+    };
+
+    if let Some(ref api_config) = config.api {
+        if api_config.enabled {
+            api::start_api_server(&api_config.address, api_state.clone(), tls.clone());
+        }
+    }
+
+    if let Some(ref dashboard_config) = config.dashboard {
+        if dashboard_config.enabled {
+            dashboard::start_dashboard_server(
+                &dashboard_config.address,
+                dashboard::Websocket::new(&config),
+                api_state.auth.clone(),
+                tls.clone(),
+            );
+        }
+    }
+
     // Broadcast to all clients
     loop {
-        let result = match poll_rx.recv() {
-            Ok(result) => result,
+        for now_checked in changed_rx.try_iter() {
+            if let Some(ref status_page) = status_page {
+                status_page.targets_changed(&targets.list());
+            }
+            for name in checked.difference(&now_checked) {
+                info!("[targets.forget] {}", name);
+                last_statuses.remove(name);
+                outage_starts.remove(name);
+                suppressed.remove(name);
+                alerted.remove(name);
+                alert_grouper.forget(name);
+
+                if alert_controls.lock().unwrap().forget(name) {
+                    api_state.broadcast_controls();
+                }
+                let now = format!("{}", time::now_utc().rfc3339());
+                let incident = incidents.lock().unwrap().close(name, &now);
+                if let Some(incident) = incident {
+                    api_state.broadcast_incident(&incident);
+                    if let Some(ref status_page) = status_page {
+                        status_page.changed();
+                    }
+                }
+            }
+            checked = now_checked;
+        }
+
+        for mut group in alert_grouper.due(Instant::now()) {
+            // Digests are sent long after alerts are raised, so drop targets muted since
+            let controls = alert_controls.lock().unwrap();
+            group.alerts.retain(|a| {
+                let is_quiet = controls.is_quiet(&a.check.target, time::get_time());
+                if is_quiet {
+                    alerted.remove(&a.check.target.name);
+                }
+                !is_quiet
+            });
+            drop(controls);
+
+            if !group.alerts.is_empty() {
+                dispatch_alert_group(&alert_dispatcher, group);
+            }
+        }
+
+        let (mut result, triggers) = match poll_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(polled) => polled,
             Err(_) => continue,
         };
 
+        // Checks which were running when their target was paused, changed or deleted
+        if !targets.is_checked(&result.target) {
+            continue;
+        }
+
+        for handler in &metrics_handlers {
+            // It's okay if metrics fail to update (maybe?)
+            let _ = handler.update(&result);
+        }
+        result.blocked_by = targets.blocked_by(&result.target.name, |name| {
+            last_statuses.get(name) == Some(&Status::Fire)
+        });
+
         info!("[probe.result] {:?}", &result);
+        recent_checks.lock().unwrap().push(result.clone());
 
         let is_spam = alerter::alert::check_spam(&last_statuses, &result);
         let is_fixed = alerter::alert::check_fixed(&last_statuses, &result);
-        last_statuses.insert(result.target.clone(), result.status.clone());
+        let outage_duration_s = alerter::alert::track_outage(&mut outage_starts, &result);
+        let previous_status =
+            last_statuses.insert(result.target.name.clone(), result.status.clone());
+
+        if let Some(ref storage_writer) = storage_writer {
+            if storage_writer
+                .try_send((result.clone(), previous_status.clone()))
+                .is_err()
+            {
+                info!(
+                    "[storage.error] failed to save check: {} checks are waiting to be saved",
+                    STORAGE_QUEUE_SIZE
+                );
+            }
+        }
 
-        if config.alert.enabled && result.alert && (is_fixed || result.need_to_alert && !is_spam) {
-            let child_config = config.clone();
-            let child_result = result.clone();
-            thread::spawn(move || alerter::alert::send_alert(&child_config, &child_result));
+        let (unacknowledged, is_quiet) = {
+            let mut controls = alert_controls.lock().unwrap();
+            let unacknowledged = controls.update(&result);
+            (
+                unacknowledged,
+                controls.is_quiet(&result.target, time::get_time()),
+            )
+        };
+        if unacknowledged {
+            api_state.broadcast_controls();
         }
 
-        if let Ok(json) = serde_json::to_string(&result) {
-            let _ = broadcaster.send(json);
-        } else {
-            panic!(
-                "[status.startup] failed to encode result into json {:?}",
-                &result
-            );
+        let incident = incidents.lock().unwrap().update(&result);
+        let incident_changed = incident.is_some();
+        if let Some(incident) = incident {
+            api_state.broadcast_incident(&incident);
+        }
+        if let Some(ref status_page) = status_page {
+            if incident_changed || previous_status.as_ref() != Some(&result.status) {
+                status_page.changed();
+            }
+        }
+
+        if config.alert.enabled && result.alert {
+            let alert = alerter::Alert {
+                check: result.clone(),
+                previous_status: previous_status.clone(),
+                outage_duration_s,
+            };
+            let notify = alerter::alert::check_blocked(
+                &mut suppressed,
+                alert.previous_status.as_ref(),
+                &result,
+                is_fixed || result.need_to_alert && !is_spam,
+            ) && !is_quiet;
+            let notify = alerter::alert::check_alerted(&mut alerted, &result, notify);
+
+            if notify {
+                let incident = incidents.lock().unwrap().alert_sent(&result.target.name);
+                if let Some(incident) = incident {
+                    api_state.broadcast_incident(&incident);
+                }
+            }
+
+            // Digests remind people of alerts they were sent, not ones that were held back
+            let in_digest =
+                alerted.contains(&result.target.name) && !is_quiet && result.blocked_by.is_empty();
+            for group in alert_grouper.record(alert, notify, in_digest, Instant::now()) {
+                dispatch_alert_group(&alert_dispatcher, group);
+            }
+        }
+
+        clients.broadcast_result(&result, previous_status.as_ref());
+        if let Some(ref events) = events {
+            events.publish(&result, previous_status.as_ref());
+        }
+        for trigger in triggers {
+            let _ = trigger.send(result.clone());
         }
     }
 }
 
+fn print_report(config: &CanaryConfig, args: &Args) -> Result<(), String> {
+    let storage_config = match config.storage {
+        Some(ref storage_config) if storage_config.enabled => storage_config,
+        _ => return Err("storage is not enabled in the configuration file".to_string()),
+    };
+
+    let storage = Storage::open(storage_config)?;
+    let format = report::Format::parse(&args.flag_format)?;
+    let (from, to) = report::parse_range(
+        args.flag_from.as_deref(),
+        args.flag_to.as_deref(),
+        time::get_time(),
+    )?;
+
+    let report = report::build(&storage, &initial_targets(config)?, from, to)?;
+    println!("{}", report::render(&report, format)?);
+    Ok(())
+}
+
+// Targets saved through the API replace the configured ones
+fn initial_targets(config: &CanaryConfig) -> Result<Vec<CanaryTarget>, String> {
+    let path = match config
+        .api
+        .as_ref()
+        .and_then(|api| api.targets_file.as_ref())
+    {
+        Some(path) => path,
+        None => return Ok(config.targets.http.clone()),
+    };
+
+    match targets::load(path)? {
+        Some(saved) => {
+            if !config.targets.http.is_empty() && saved != config.targets.http {
+                info!(
+                    "[status.startup] using the targets saved in {}, the targets in the config differ and are ignored",
+                    path
+                );
+            }
+            Ok(saved)
+        }
+        None => Ok(config.targets.http.clone()),
+    }
+}
+
+// Names of the targets which are not paused
+fn checked_names(targets: &[CanaryTarget]) -> HashSet<String> {
+    targets
+        .iter()
+        .filter(|t| !t.paused)
+        .map(|t| t.name.clone())
+        .collect()
+}
+
+fn dispatch_alert_group(dispatcher: &alerter::dispatch::AlertDispatcher, group: AlertGroup) {
+    let key = group.key.clone();
+    let count = group.alerts.len();
+    match dispatcher.dispatch(group) {
+        Ok(()) => info!(
+            "[alert.send] queued {} alerts for {} ({} pending)",
+            count,
+            key,
+            dispatcher.pending()
+        ),
+        Err(err) => info!(
+            "[alert.send] failed to queue {} alerts for {}: {}",
+            count, key, err
+        ),
+    }
+}
+
 fn async_blocking_run<F, I, E>(f: F) -> Result<F::Item, F::Error>
 where
     F: Future<Item = I, Error = E> + Send + 'static,
@@ -227,6 +712,29 @@ fn format_status_codes(e: &[CheckResultElement]) -> String {
     String::from_utf8(out_buf).unwrap()
 }
 
+fn check_elements(e: &[CheckResultElement], started_at: Instant) -> Vec<CanaryCheckElement> {
+    e.iter()
+        .map(|e| CanaryCheckElement {
+            address: e.target().to_string(),
+            error: e.err_msg().map(|s| s.to_string()),
+            latency_ms: e.latency().as_millis() as u64,
+            phases: e
+                .timeline()
+                .iter()
+                .map(|span| CanaryCheckPhase {
+                    latency_ms: span.duration().as_millis() as u64,
+                    name: span.name().to_string(),
+                    offset_ms: span
+                        .started_at()
+                        .saturating_duration_since(started_at)
+                        .as_millis() as u64,
+                })
+                .collect(),
+            status_code: e.status_code(),
+        })
+        .collect()
+}
+
 pub fn header_from_basic_auth(auth: &Auth) -> String {
     let mut raw_pair = auth.username.clone();
     if let Some(ref pass) = auth.password {
@@ -237,7 +745,7 @@ pub fn header_from_basic_auth(auth: &Auth) -> String {
     format!("Basic {}", base64::encode(&raw_pair))
 }
 
-fn check_host(target: &CanaryTarget) -> CanaryCheck {
+fn check_host(target: &CanaryTarget, send_traceparent: bool) -> CanaryCheck {
     let latency_timer = Instant::now();
 
     let mut headers = Vec::new();
@@ -245,6 +753,14 @@ fn check_host(target: &CanaryTarget) -> CanaryCheck {
         headers.push((AUTHORIZATION, header_from_basic_auth(a)));
     };
 
+    let traceparent = if send_traceparent {
+        let traceparent = metrics::otlp::new_traceparent();
+        headers.push((HeaderName::from_static("traceparent"), traceparent.clone()));
+        Some(traceparent)
+    } else {
+        None
+    };
+
     let http_check = HttpCheck {
         latency_requirement: Duration::new(1, 0),
         allow_client_error: true,
@@ -261,10 +777,13 @@ fn check_host(target: &CanaryTarget) -> CanaryCheck {
             time: format!("{}", time::now_utc().rfc3339()),
             status: Status::Unknown,
             status_code: "unknown".to_string(),
-            status_reason: "bad url".to_string(),
+            status_reason: STATUS_REASON_BAD_URL.to_string(),
             latency_ms: 0,
             alert: target.alert,
+            blocked_by: vec![],
+            elements: vec![],
             need_to_alert: target.alert,
+            traceparent: traceparent.clone(),
         };
     }
 
@@ -294,7 +813,10 @@ fn check_host(target: &CanaryTarget) -> CanaryCheck {
                 status_reason: "unimplemented".to_string(),
                 latency_ms,
                 alert: target.alert,
+                blocked_by: vec![],
+                elements: vec![],
                 need_to_alert,
+                traceparent: traceparent.clone(),
             };
         }
     };
@@ -322,7 +844,10 @@ fn check_host(target: &CanaryTarget) -> CanaryCheck {
         status_reason: "unimplemented".to_string(),
         latency_ms,
         alert: target.alert,
+        blocked_by: vec![],
+        elements: check_elements(ok.elements(), latency_timer),
         need_to_alert,
+        traceparent,
     }
 }
 
@@ -333,10 +858,30 @@ fn read_config(path: &str) -> Result<CanaryConfig, Box<dyn Error>> {
     file.read_to_string(&mut config_toml)?;
     info!("[status.startup] read configuration file.");
 
-    Ok(toml::from_str(&config_toml)?)
+    parse_config(&config_toml)
+}
+
+fn parse_config(config_toml: &str) -> Result<CanaryConfig, Box<dyn Error>> {
+    let config: CanaryConfig = toml::from_str(config_toml)?;
+    DependencyGraph::new(&config.targets.http)?;
+    if let Some(ref email_config) = config.alert.email {
+        alerter::template::validate(email_config)?;
+    }
+    if let Some(ref metrics_config) = config.metrics {
+        if metrics_config.enabled && metrics_config.address.is_empty() {
+            return Err("`metrics.address` is required when `metrics.enabled` is set".into());
+        }
+    }
+
+    Ok(config)
 }
 
-fn start_metrics_server(bind_to: &str, metrics_handler: Arc<Option<PrometheusMetrics>>) {
+fn start_metrics_server(
+    bind_to: &str,
+    metrics_handler: Arc<dyn Metrics>,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<Arc<Tls>>,
+) {
     let addr: SocketAddr = bind_to.parse().unwrap_or_else(|err| {
         panic!("[status.startup] failed to start metrics endpoint: {}", err);
     });
@@ -344,22 +889,24 @@ fn start_metrics_server(bind_to: &str, metrics_handler: Arc<Option<PrometheusMet
     info!("[status.startup] starting metrics server at {}...", &addr);
 
     thread::spawn(move || {
-        let child_metrics = metrics_handler.clone();
-
-        rouille::start_server(addr, move |_req| {
-            let child_arc = child_metrics.clone();
-            let body = if let Ok(Some(handler)) = Arc::try_unwrap(child_arc) {
-                handler.print().unwrap()
-            } else {
-                "None".to_string()
-            };
+        tls::serve(addr, tls, move |request| {
+            if let Err(response) = auth::check(auth.as_deref(), request, CanaryAuthScope::Read) {
+                return response;
+            }
 
-            rouille::Response::text(body)
+            match metrics_handler.print() {
+                Ok(body) => rouille::Response::text(body),
+                Err(err) => rouille::Response::text(err).with_status_code(500),
+            }
         });
     });
 }
 
-fn start_healthcheck_server(bind_to: &str) {
+fn start_healthcheck_server(
+    bind_to: &str,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<Arc<Tls>>,
+) {
     let addr: SocketAddr = bind_to.parse().unwrap_or_else(|err| {
         panic!(
             "[status.startup] failed to start health check endpoint: {}",
@@ -373,13 +920,19 @@ fn start_healthcheck_server(bind_to: &str) {
     );
 
     thread::spawn(move || {
-        rouille::start_server(addr, move |_req| rouille::Response::text("OK"));
+        tls::serve(addr, tls, move |request| {
+            match auth::check(auth.as_deref(), request, CanaryAuthScope::Read) {
+                Ok(()) => rouille::Response::text("OK"),
+                Err(response) => response,
+            }
+        });
     });
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
     use std::{thread, time};
 
     fn sleep() {
@@ -390,12 +943,35 @@ mod tests {
     pub fn target() -> CanaryTarget {
         CanaryTarget {
             name: "foo".to_string(),
+            paused: false,
             host: "invalid".to_string(),
             tag: Some("tag".to_string()),
             tag_metric: None,
             interval_s: 1,
+            labels: BTreeMap::new(),
             alert: false,
             basic_auth: None,
+            depends_on: vec![],
+        }
+    }
+
+    /// A check of `target()` renamed to `name`, other fields are set by callers as needed
+    pub fn check(name: &str, status: Status) -> CanaryCheck {
+        let mut target = target();
+        target.name = name.to_string();
+
+        CanaryCheck {
+            alert: false,
+            blocked_by: vec![],
+            elements: vec![],
+            latency_ms: 0,
+            need_to_alert: false,
+            status_code: "".to_string(),
+            status_reason: "".to_string(),
+            status,
+            target,
+            time: "2016-10-14T08:00:00Z".to_string(),
+            traceparent: None,
         }
     }
 
@@ -404,65 +980,94 @@ mod tests {
         let expected = CanaryConfig {
             alert: CanaryAlertConfig {
                 enabled: true,
+                delivery: CanaryAlertDeliveryConfig::default(),
+                grouping: CanaryAlertGroupingConfig::default(),
                 email: Some(CanaryEmailAlertConfig {
                     alert_email: "rcanary.alert.inbox@gmail.com".to_string(),
                     smtp_server: "smtp.googlemail.com".to_string(),
                     smtp_username: "example@gmail.com".to_string(),
                     smtp_password: "hunter2".to_string(),
+                    subject_template: None,
+                    text_template: None,
+                    html_template: None,
+                    group_subject_template: None,
+                    group_text_template: None,
+                    group_html_template: None,
                 }),
             },
+            api: None,
+            auth: None,
+            dashboard: None,
             metrics: Some(CanaryMetricsConfig {
                 enabled: false,
                 address: "127.0.0.1:9809".to_string(),
+                ..CanaryMetricsConfig::default()
             }),
+            otlp: None,
             health_check: Some(CanaryHealthCheckConfig {
                 enabled: true,
                 address: "127.0.0.1:8100".to_string(),
             }),
             server_listen_address: "127.0.0.1:8099".to_string(),
+            sse: None,
+            status_page: None,
+            storage: None,
             targets: CanaryTargetTypes {
                 http: vec![
                     CanaryTarget {
                         name: "Invalid".to_string(),
+                        paused: false,
                         host: "Hello, world!".to_string(),
                         tag: None,
                         tag_metric: Some("hello".to_string()),
                         interval_s: 60,
+                        labels: BTreeMap::new(),
                         alert: false,
                         basic_auth: None,
+                        depends_on: vec![],
                     },
                     CanaryTarget {
                         name: "404".to_string(),
+                        paused: false,
                         host: "http://www.google.com/404".to_string(),
                         tag: Some("example-tag".to_string()),
                         tag_metric: Some("http_404".to_string()),
                         interval_s: 5,
+                        labels: BTreeMap::new(),
                         alert: false,
                         basic_auth: None,
+                        depends_on: vec![],
                     },
                     CanaryTarget {
                         name: "localhost:8080".to_string(),
+                        paused: false,
                         host: "http://localhost:8080".to_string(),
                         tag: None,
                         tag_metric: Some("local_8080".to_string()),
                         interval_s: 5,
+                        labels: BTreeMap::new(),
                         alert: false,
                         basic_auth: None,
+                        depends_on: vec![],
                     },
                     CanaryTarget {
                         name: "Google".to_string(),
+                        paused: false,
                         host: "https://www.google.com".to_string(),
                         tag: None,
                         tag_metric: Some("google".to_string()),
                         interval_s: 5,
+                        labels: BTreeMap::new(),
                         alert: false,
                         basic_auth: Some(Auth {
                             username: "AzureDiamond".to_string(),
                             password: Some("hunter2".to_string()),
                         }),
+                        depends_on: vec![],
                     },
                 ],
             },
+            tls: None,
         };
 
         let actual = read_config("tests/fixtures/config.toml").unwrap();
@@ -470,12 +1075,41 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn it_requires_a_metrics_address() {
+        let metrics = |toml: &str| {
+            parse_config(&format!(
+                "server_listen_address = \"127.0.0.1:8099\"\n\
+                 [targets]\n\
+                 http = []\n\
+                 [metrics]\n\
+                 {}",
+                toml
+            ))
+            .map(|config| config.metrics.unwrap())
+        };
+
+        assert!(metrics("enabled = true").is_err());
+        let actual = metrics("enabled = true\naddress = \"127.0.0.1:9809\"").unwrap();
+        assert_eq!(
+            CanaryMetricsConfig::default().latency_buckets_ms,
+            actual.latency_buckets_ms
+        );
+
+        // StatsD and push metrics work without the Prometheus endpoint
+        let actual = metrics("[metrics.statsd]\nenabled = true").unwrap();
+        assert!(!actual.enabled);
+        assert!(actual.statsd.unwrap().enabled);
+    }
+
     #[test]
     fn it_checks_invalid_target_hosts() {
-        let actual = check_host(&target());
+        let actual = check_host(&target(), false);
 
         let expected = CanaryCheck {
             alert: false,
+            blocked_by: vec![],
+            elements: vec![],
             latency_ms: actual.latency_ms,
             need_to_alert: false,
             status_code: "failed to poll server: invalid target: URL invalid is missing a scheme"
@@ -484,6 +1118,7 @@ mod tests {
             status_reason: "unimplemented".to_string(),
             target: target(),
             time: actual.time.clone(),
+            traceparent: None,
         };
 
         assert_eq!(expected, actual);
@@ -499,18 +1134,26 @@ mod tests {
 
         let ok_target = CanaryTarget {
             name: "foo".to_string(),
+            paused: false,
             host: "http://127.0.0.1:56473".to_string(),
             tag: Some("bar".to_string()),
             tag_metric: None,
             interval_s: 1,
+            labels: BTreeMap::new(),
             alert: false,
             basic_auth: None,
+            depends_on: vec![],
         };
 
-        let ok_actual = check_host(&ok_target);
+        let ok_actual = check_host(&ok_target, false);
+        assert_eq!(1, ok_actual.elements.len());
+        assert_eq!("127.0.0.1", ok_actual.elements[0].address);
+        assert_eq!(200, ok_actual.elements[0].status_code);
 
         let ok_expected = CanaryCheck {
             alert: false,
+            blocked_by: vec![],
+            elements: ok_actual.elements.clone(),
             latency_ms: ok_actual.latency_ms,
             need_to_alert: false,
             status_code: "200 OK".to_string(),
@@ -518,6 +1161,7 @@ mod tests {
             status_reason: "unimplemented".to_string(),
             target: ok_target,
             time: ok_actual.time.clone(),
+            traceparent: None,
         };
 
         assert_eq!(ok_expected, ok_actual);
@@ -538,21 +1182,26 @@ mod tests {
 
         let ok_target = CanaryTarget {
             name: "foo".to_string(),
+            paused: false,
             host: "http://127.0.0.1:56474".to_string(),
             tag: Some("bar".to_string()),
             tag_metric: None,
             interval_s: 1,
+            labels: BTreeMap::new(),
             alert: false,
             basic_auth: Some(Auth {
                 username: "AzureDiamond".to_string(),
                 password: Some("hunter2".to_string()),
             }),
+            depends_on: vec![],
         };
 
-        let ok_actual = check_host(&ok_target);
+        let ok_actual = check_host(&ok_target, false);
 
         let ok_expected = CanaryCheck {
             alert: false,
+            blocked_by: vec![],
+            elements: ok_actual.elements.clone(),
             latency_ms: ok_actual.latency_ms,
             need_to_alert: false,
             status_code: "200 OK".to_string(),
@@ -560,8 +1209,63 @@ mod tests {
             status_reason: "unimplemented".to_string(),
             target: ok_target,
             time: ok_actual.time.clone(),
+            traceparent: None,
         };
 
         assert_eq!(ok_expected, ok_actual);
     }
+
+    #[test]
+    fn it_serves_metrics_updated_by_a_check() {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        thread::spawn(move || {
+            rouille::start_server("127.0.0.1:56475", move |_req| rouille::Response::text("OK"));
+        });
+
+        let ok_target = CanaryTarget {
+            name: "foo".to_string(),
+            paused: false,
+            host: "http://127.0.0.1:56475".to_string(),
+            tag: Some("bar".to_string()),
+            tag_metric: None,
+            interval_s: 1,
+            labels: BTreeMap::new(),
+            alert: false,
+            basic_auth: None,
+            depends_on: vec![],
+        };
+
+        let metrics: Arc<dyn Metrics> = Arc::new(PrometheusMetrics::new(
+            &CanaryMetricsConfig::default(),
+            &CanaryTargetTypes {
+                http: vec![ok_target.clone()],
+            },
+        ));
+        start_metrics_server("127.0.0.1:56476", metrics.clone(), None, None);
+        sleep();
+
+        // Pollers share the handler with the metrics server
+        let poller_metrics = metrics.clone();
+        thread::spawn(move || poller_metrics.update(&check_host(&ok_target, false)))
+            .join()
+            .unwrap()
+            .unwrap();
+
+        let mut stream = TcpStream::connect("127.0.0.1:56476").unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains(
+            "rcanary_target_up{host=\"http://127.0.0.1:56475\",name=\"foo\",tag=\"bar\"} 1\n"
+        ));
+        assert!(response.contains(
+            "rcanary_http_status_code{host=\"http://127.0.0.1:56475\",name=\"foo\",tag=\"bar\"} 200\n"
+        ));
+    }
 }
@@ -1,9 +1,64 @@
+use std::time::Duration;
+
+use hyper::{header, Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use tokio::prelude::FutureExt;
+
 use librcanary::{CanaryCheck, CanaryTargetTypes};
 
+pub mod otlp;
+mod process;
 pub mod prometheus;
+pub mod push;
+pub mod statsd;
+
+/// Push backends give up on a request after this long, rather than hold up later points
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A metrics backend, shared between pollers and the metrics server
+pub trait Metrics: Send + Sync {
+    fn update(&self, result: &CanaryCheck) -> Result<(), String>;
+
+    /// Called with every target after targets are created, changed, paused or deleted
+    fn targets_changed(&self, _targets: &CanaryTargetTypes) {}
+
+    /// Records the outcome of delivering a group of alerts to a receiver
+    fn alert_delivery(&self, _receiver: &str, _alerts: usize, _delivered: bool) {}
+
+    /// Renders the metrics for scraping. Backends which push metrics cannot be scraped.
+    fn print(&self) -> Result<String, String> {
+        Err("metrics backend does not support scraping".to_string())
+    }
+}
+
+// Metric path segments (StatsD, Graphite) cannot contain separators
+fn sanitize_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Posts a body to a push backend, failing unless it responds with a success status
+fn post(url: &Uri, content_type: &str, body: String) -> Result<(), String> {
+    let https = HttpsConnector::new(1).map_err(|e| e.to_string())?;
+    let client = Client::builder().keep_alive(false).build::<_, Body>(https);
+    let request = Request::post(url.clone())
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+
+    let response = crate::async_blocking_run(client.request(request).timeout(PUSH_TIMEOUT))
+        .map_err(|e| e.to_string())?;
 
-pub trait Metrics {
-    fn new(result: &CanaryTargetTypes) -> Self;
-    fn update(&self, target_name: &str, result: &CanaryCheck) -> Result<(), String>;
-    fn print(&self) -> Result<String, String>;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("responded with {}", response.status()))
+    }
 }
@@ -0,0 +1,421 @@
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+use hyper::Uri;
+use log::info;
+use serde_json::{json, Value};
+use time::Timespec;
+
+use librcanary::{CanaryCheck, CanaryOtlpConfig, CanaryTarget, Status};
+
+use super::{post, Metrics};
+
+// https://opentelemetry.io/docs/specs/otel/trace/api/#spankind
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_CLIENT: u8 = 3;
+// https://opentelemetry.io/docs/specs/otel/trace/api/#set-status
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+/// Checks waiting to be exported, beyond which checks are dropped while the collector is slow
+const QUEUE_SIZE: usize = 100;
+
+/// Exports every check to an OpenTelemetry collector over OTLP/HTTP (JSON).
+///
+/// Each check becomes a `rcanary.check` span with a child span per probed address, which in
+/// turn has a child span per phase of the request (DNS, connect, TLS, HTTP). The target's
+/// status, latency and status code are exported as gauges.
+pub struct OtlpMetrics {
+    checks: SyncSender<(CanaryCheck, Timespec)>,
+}
+
+impl OtlpMetrics {
+    pub fn new(config: &CanaryOtlpConfig) -> Result<OtlpMetrics, String> {
+        OtlpMetrics::with_queue_size(config, QUEUE_SIZE)
+    }
+
+    fn with_queue_size(
+        config: &CanaryOtlpConfig,
+        queue_size: usize,
+    ) -> Result<OtlpMetrics, String> {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let url = |path: &str| {
+            format!("{}{}", endpoint, path)
+                .parse::<Uri>()
+                .map_err(|e| format!("invalid OTLP endpoint {}: {}", config.endpoint, e))
+        };
+        let traces_url = url("/v1/traces")?;
+        let metrics_url = url("/v1/metrics")?;
+
+        let (checks, received) = mpsc::sync_channel::<(CanaryCheck, Timespec)>(queue_size);
+        let config = config.clone();
+
+        // Exports happen off the polling threads so a slow collector cannot delay checks
+        thread::spawn(move || {
+            for (result, ended_at) in received {
+                if config.traces {
+                    let body = traces(&config.service_name, &result, ended_at).to_string();
+                    if let Err(err) = post(&traces_url, "application/json", body) {
+                        info!("[metrics.otlp] failed to export spans: {}", err);
+                    }
+                }
+
+                if config.metrics {
+                    let body = metrics(&config.service_name, &result, ended_at).to_string();
+                    if let Err(err) = post(&metrics_url, "application/json", body) {
+                        info!("[metrics.otlp] failed to export metrics: {}", err);
+                    }
+                }
+            }
+        });
+
+        Ok(OtlpMetrics { checks })
+    }
+}
+
+impl Metrics for OtlpMetrics {
+    fn update(&self, result: &CanaryCheck) -> Result<(), String> {
+        match self.checks.try_send((result.clone(), time::get_time())) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full((result, _))) => {
+                let message = format!(
+                    "export queue is full, dropped check of {}",
+                    result.target.name
+                );
+                info!("[metrics.otlp] {}", message);
+                Err(message)
+            }
+            Err(TrySendError::Disconnected(_)) => Err("OTLP exporter stopped".to_string()),
+        }
+    }
+}
+
+/// Makes a W3C trace context header for a probe request, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+pub fn new_traceparent() -> String {
+    format!("00-{}-{}-01", random_id(16), random_id(8))
+}
+
+// The trace ID and parent span ID of a `traceparent` header
+fn parse_traceparent(traceparent: &str) -> Option<(String, String)> {
+    let parts = traceparent.split('-').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [_, trace_id, span_id, _] if trace_id.len() == 32 && span_id.len() == 16 => {
+            Some((trace_id.to_string(), span_id.to_string()))
+        }
+        _ => None,
+    }
+}
+
+fn random_id(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+fn unix_nanos(t: Timespec) -> u64 {
+    t.sec as u64 * 1_000_000_000 + t.nsec as u64
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        other => json!({ "stringValue": other.as_str().unwrap_or_default() }),
+    };
+
+    json!({ "key": key, "value": value })
+}
+
+fn target_attributes(target: &CanaryTarget) -> Vec<Value> {
+    let mut attributes = vec![
+        attribute("rcanary.target.name", json!(target.name)),
+        attribute("rcanary.target.host", json!(target.host)),
+    ];
+    if let Some(ref tag) = target.tag {
+        attributes.push(attribute("rcanary.target.tag", json!(tag)));
+    }
+    attributes
+}
+
+fn resource(service_name: &str) -> Value {
+    json!({ "attributes": [attribute("service.name", json!(service_name))] })
+}
+
+fn scope() -> Value {
+    json!({ "name": "rcanary", "version": crate::CARGO_PKG_VERSION })
+}
+
+// Builds an ExportTraceServiceRequest for a check which ended at `ended_at`
+fn traces(service_name: &str, result: &CanaryCheck, ended_at: Timespec) -> Value {
+    // Reuse the IDs sent to the target so its own spans nest under the check
+    let (trace_id, root_id) = result
+        .traceparent
+        .as_ref()
+        .and_then(|t| parse_traceparent(t))
+        .unwrap_or_else(|| (random_id(16), random_id(8)));
+
+    let end = unix_nanos(ended_at);
+    let start = end.saturating_sub(result.latency_ms * 1_000_000);
+    let span = |id: &str, parent: Option<&str>, name: &str, start: u64, end: u64| {
+        json!({
+            "traceId": trace_id,
+            "spanId": id,
+            "parentSpanId": parent.unwrap_or(""),
+            "name": name,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+        })
+    };
+
+    let mut root = span(&root_id, None, "rcanary.check", start, end);
+    let mut attributes = target_attributes(&result.target);
+    attributes.push(attribute("rcanary.status", json!(result.status)));
+    root["kind"] = json!(SPAN_KIND_CLIENT);
+    root["attributes"] = json!(attributes);
+    root["status"] = if result.status == Status::Okay {
+        json!({ "code": STATUS_CODE_OK })
+    } else {
+        json!({ "code": STATUS_CODE_ERROR, "message": result.status_reason })
+    };
+
+    let mut spans = vec![root];
+    for element in &result.elements {
+        let element_id = random_id(8);
+        let element_start = start + element.phases.first().map_or(0, |p| p.offset_ms) * 1_000_000;
+        let element_end = element_start + element.latency_ms * 1_000_000;
+
+        let mut probe = span(
+            &element_id,
+            Some(&root_id),
+            "rcanary.probe",
+            element_start,
+            element_end,
+        );
+        let mut attributes = vec![attribute("net.peer.ip", json!(element.address))];
+        if element.status_code != 0 {
+            attributes.push(attribute("http.status_code", json!(element.status_code)));
+        }
+        probe["kind"] = json!(SPAN_KIND_CLIENT);
+        probe["attributes"] = json!(attributes);
+        probe["status"] = match element.error {
+            Some(ref error) => json!({ "code": STATUS_CODE_ERROR, "message": error }),
+            None => json!({ "code": STATUS_CODE_OK }),
+        };
+        spans.push(probe);
+
+        for phase in &element.phases {
+            let phase_start = start + phase.offset_ms * 1_000_000;
+            let mut phase_span = span(
+                &random_id(8),
+                Some(&element_id),
+                &phase.name,
+                phase_start,
+                phase_start + phase.latency_ms * 1_000_000,
+            );
+            phase_span["kind"] = json!(SPAN_KIND_INTERNAL);
+            spans.push(phase_span);
+        }
+    }
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(service_name),
+            "scopeSpans": [{ "scope": scope(), "spans": spans }],
+        }]
+    })
+}
+
+// Builds an ExportMetricsServiceRequest with the check's gauges
+fn metrics(service_name: &str, result: &CanaryCheck, ended_at: Timespec) -> Value {
+    let attributes = target_attributes(&result.target);
+    let gauge = |name: &str, unit: &str, value: u64| {
+        json!({
+            "name": name,
+            "unit": unit,
+            "gauge": {
+                "dataPoints": [{
+                    "attributes": attributes,
+                    "timeUnixNano": unix_nanos(ended_at).to_string(),
+                    "asInt": value.to_string(),
+                }]
+            }
+        })
+    };
+
+    let up = if result.status == Status::Okay { 1 } else { 0 };
+    let mut metrics = vec![
+        gauge("rcanary.target.up", "1", up),
+        gauge("rcanary.check.duration", "ms", result.latency_ms),
+    ];
+    if let Some(status_code) = result.elements.iter().map(|e| e.status_code).max() {
+        metrics.push(gauge(
+            "rcanary.http.status_code",
+            "1",
+            u64::from(status_code),
+        ));
+    }
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(service_name),
+            "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::check;
+    use librcanary::{CanaryCheckElement, CanaryCheckPhase};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn result() -> CanaryCheck {
+        CanaryCheck {
+            elements: vec![CanaryCheckElement {
+                address: "127.0.0.1".to_string(),
+                error: None,
+                latency_ms: 100,
+                phases: vec![
+                    CanaryCheckPhase {
+                        latency_ms: 5,
+                        name: "dns".to_string(),
+                        offset_ms: 0,
+                    },
+                    CanaryCheckPhase {
+                        latency_ms: 20,
+                        name: "connect".to_string(),
+                        offset_ms: 5,
+                    },
+                ],
+                status_code: 200,
+            }],
+            latency_ms: 125,
+            status_code: "200 OK".to_string(),
+            traceparent: Some(TRACEPARENT.to_string()),
+            ..check("foo", Status::Okay)
+        }
+    }
+
+    #[test]
+    fn it_makes_traceparent_headers() {
+        let traceparent = new_traceparent();
+
+        assert_eq!(55, traceparent.len());
+        assert!(parse_traceparent(&traceparent).is_some());
+        assert_eq!(
+            Some((
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                "00f067aa0ba902b7".to_string()
+            )),
+            parse_traceparent(TRACEPARENT)
+        );
+        assert_eq!(None, parse_traceparent("00-nope-01"));
+    }
+
+    #[test]
+    fn it_builds_spans_from_the_timeline() {
+        let actual = traces("rcanary", &result(), Timespec::new(10, 0));
+        let spans = actual["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+
+        assert_eq!(4, spans.len());
+        assert_eq!("rcanary.check", spans[0]["name"]);
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", spans[0]["traceId"]);
+        assert_eq!("00f067aa0ba902b7", spans[0]["spanId"]);
+        assert_eq!("9875000000", spans[0]["startTimeUnixNano"]);
+        assert_eq!("10000000000", spans[0]["endTimeUnixNano"]);
+
+        assert_eq!("rcanary.probe", spans[1]["name"]);
+        assert_eq!("00f067aa0ba902b7", spans[1]["parentSpanId"]);
+        assert_eq!("connect", spans[3]["name"]);
+        assert_eq!(spans[1]["spanId"], spans[3]["parentSpanId"]);
+        assert_eq!("9880000000", spans[3]["startTimeUnixNano"]);
+        assert_eq!("9900000000", spans[3]["endTimeUnixNano"]);
+    }
+
+    #[test]
+    fn it_exports_gauges() {
+        let mut failed = result();
+        failed.status = Status::Fire;
+
+        let actual = metrics("rcanary", &failed, Timespec::new(10, 0));
+        let metrics = actual["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+
+        assert_eq!(3, metrics.len());
+        assert_eq!("rcanary.target.up", metrics[0]["name"]);
+        assert_eq!("0", metrics[0]["gauge"]["dataPoints"][0]["asInt"]);
+        assert_eq!("125", metrics[1]["gauge"]["dataPoints"][0]["asInt"]);
+        assert_eq!(
+            json!({ "key": "rcanary.target.name", "value": { "stringValue": "foo" } }),
+            metrics[2]["gauge"]["dataPoints"][0]["attributes"][0]
+        );
+    }
+
+    #[test]
+    fn it_posts_to_the_collector() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            rouille::start_server("127.0.0.1:56478", move |request| {
+                let mut body = String::new();
+                request.data().unwrap().read_to_string(&mut body).unwrap();
+                server_received.lock().unwrap().push((request.url(), body));
+                rouille::Response::text("")
+            });
+        });
+        thread::sleep(Duration::from_millis(250));
+
+        let metrics = OtlpMetrics::new(&CanaryOtlpConfig {
+            enabled: true,
+            endpoint: "http://127.0.0.1:56478/".to_string(),
+            ..CanaryOtlpConfig::default()
+        })
+        .unwrap();
+        metrics.update(&result()).unwrap();
+
+        let started = Instant::now();
+        while received.lock().unwrap().len() < 2 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let received = received.lock().unwrap();
+        assert_eq!("/v1/traces", received[0].0);
+        assert!(received[0].1.contains("rcanary.check"));
+        assert_eq!("/v1/metrics", received[1].0);
+        assert!(received[1].1.contains("rcanary.target.up"));
+    }
+
+    #[test]
+    fn it_drops_checks_while_the_collector_is_down() {
+        // Accepts connections but never answers, so exports hang until they time out
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let metrics = OtlpMetrics::with_queue_size(
+            &CanaryOtlpConfig {
+                enabled: true,
+                endpoint,
+                ..CanaryOtlpConfig::default()
+            },
+            3,
+        )
+        .unwrap();
+
+        let queued = (0..10)
+            .filter(|_| metrics.update(&result()).is_ok())
+            .count();
+
+        // One check is being exported and the rest wait in the queue
+        assert!(queued <= 4, "{} checks were queued", queued);
+        assert!(metrics.update(&result()).is_err());
+        drop(listener);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use prometheus::{opts, Counter, Gauge, GaugeVec, IntGauge, Registry};
+
+use super::prometheus::register;
+
+// USER_HZ, in case the kernel's clock ticks per second cannot be read
+const DEFAULT_CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Metrics about the rcanary process itself, refreshed on every scrape.
+/// Resource usage is read from /proc and is only available on Linux.
+#[derive(Clone)]
+pub struct ProcessMetrics {
+    cpu_seconds: Counter,
+    last_cpu_seconds: Arc<Mutex<f64>>,
+    resident_memory: IntGauge,
+    open_fds: IntGauge,
+    threads: IntGauge,
+}
+
+impl ProcessMetrics {
+    pub fn new(registry: &Registry) -> ProcessMetrics {
+        let build_info = register(
+            registry,
+            GaugeVec::new(
+                opts!("rcanary_build_info", "version of rcanary which is running"),
+                &["version"],
+            ),
+        );
+        build_info
+            .with_label_values(&[crate::CARGO_PKG_VERSION])
+            .set(1.0);
+
+        let start_time = register(
+            registry,
+            Gauge::with_opts(opts!(
+                "process_start_time_seconds",
+                "start time of the process since the unix epoch in seconds"
+            )),
+        );
+        start_time.set(time::get_time().sec as f64);
+
+        ProcessMetrics {
+            cpu_seconds: register(
+                registry,
+                Counter::with_opts(opts!(
+                    "process_cpu_seconds_total",
+                    "total user and system CPU time spent in seconds"
+                )),
+            ),
+            last_cpu_seconds: Arc::new(Mutex::new(0.0)),
+            resident_memory: register(
+                registry,
+                IntGauge::with_opts(opts!(
+                    "process_resident_memory_bytes",
+                    "resident memory size in bytes"
+                )),
+            ),
+            open_fds: register(
+                registry,
+                IntGauge::with_opts(opts!("process_open_fds", "number of open file descriptors")),
+            ),
+            threads: register(
+                registry,
+                IntGauge::with_opts(opts!("process_threads", "number of OS threads")),
+            ),
+        }
+    }
+
+    pub fn refresh(&self) {
+        if let Some(cpu_seconds) = read_cpu_seconds() {
+            if let Ok(mut last) = self.last_cpu_seconds.lock() {
+                if cpu_seconds > *last {
+                    self.cpu_seconds.inc_by(cpu_seconds - *last);
+                    *last = cpu_seconds;
+                }
+            }
+        }
+
+        if let Ok(status) = fs::read_to_string("/proc/self/status") {
+            if let Some(kb) = status_field(&status, "VmRSS:") {
+                self.resident_memory.set(kb * 1024);
+            }
+            if let Some(threads) = status_field(&status, "Threads:") {
+                self.threads.set(threads);
+            }
+        }
+
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            self.open_fds.set(fds.count() as i64);
+        }
+    }
+}
+
+fn read_cpu_seconds() -> Option<f64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so count fields from after it
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    // utime and stime are the 14th and 15th fields, counting from the pid
+    let utime = fields.get(11)?.parse::<f64>().ok()?;
+    let stime = fields.get(12)?.parse::<f64>().ok()?;
+
+    Some((utime + stime) / clock_ticks_per_second())
+}
+
+// Unit of the times in /proc/<pid>/stat
+fn clock_ticks_per_second() -> f64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as f64,
+        _ => DEFAULT_CLOCK_TICKS_PER_SECOND,
+    }
+}
+
+// Parses lines such as `VmRSS:     1234 kB`
+fn status_field(status: &str, name: &str) -> Option<i64> {
+    status
+        .lines()
+        .find(|line| line.starts_with(name))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_proc_status_fields() {
+        let status = "Name:\trcanary\nVmRSS:\t    2048 kB\nThreads:\t7\n";
+
+        assert_eq!(Some(2048), status_field(status, "VmRSS:"));
+        assert_eq!(Some(7), status_field(status, "Threads:"));
+        assert_eq!(None, status_field(status, "VmSwap:"));
+    }
+}
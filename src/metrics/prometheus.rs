@@ -1,84 +1,265 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
-use lazy_static::lazy_static;
-use prometheus::{opts, Encoder, Gauge, Registry, TextEncoder};
+use prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+use prometheus::{
+    histogram_opts, opts, Encoder, GaugeVec, HistogramVec, IntCounterVec, Registry, TextEncoder,
+};
 
-use librcanary::CanaryCheck;
-use librcanary::CanaryTargetTypes;
+use librcanary::{CanaryCheck, CanaryMetricsConfig, CanaryTarget, CanaryTargetTypes, Status};
 
+use super::process::ProcessMetrics;
 use super::Metrics;
 
-lazy_static! {
-    static ref GAUGES: Mutex<HashMap<String, Gauge>> = {
-        let m = HashMap::new();
-        Mutex::new(m)
-    };
-}
+const LABELS: &[&str] = &["name", "host", "tag"];
 
 #[derive(Clone)]
 pub struct PrometheusMetrics {
     pub registry: Registry,
+    up: GaugeVec,
+    status_code: GaugeVec,
+    duration: GaugeVec,
+    latency: HistogramVec,
+    phase_latency: HistogramVec,
+    checks: IntCounterVec,
+    failures: IntCounterVec,
+    transitions: IntCounterVec,
+    alerts_sent: IntCounterVec,
+    alerts_failed: IntCounterVec,
+    process: ProcessMetrics,
+    // Last status of each target, keyed by name, to count transitions
+    last_statuses: Arc<Mutex<HashMap<String, Status>>>,
 }
 
-impl Metrics for PrometheusMetrics {
-    fn new(targets: &CanaryTargetTypes) -> PrometheusMetrics {
+impl PrometheusMetrics {
+    pub fn new(config: &CanaryMetricsConfig, targets: &CanaryTargetTypes) -> PrometheusMetrics {
         let registry = Registry::new();
+        let buckets = config
+            .latency_buckets_ms
+            .iter()
+            .map(|ms| *ms as f64 / 1000.0)
+            .collect::<Vec<_>>();
 
-        for target in targets.clone().http {
-            // We want metrics setup failures to surface ASAP (on startup)
-            #[allow(clippy::expect_fun_call)] // borrow-ck issue, might go away with NLL?
-            let tag = target
-                .tag_metric
-                .expect(&format!("Missing tag_metric for {:?}", target.host));
-
-            let status_tag = format!("{}_status", tag);
-            let status_opts = opts!(status_tag.clone(), format!("status for {}", tag));
-            let status_gauge = Gauge::with_opts(status_opts)
-                .unwrap_or_else(|_| panic!("failed to create status gauge for {}", tag));
-            registry
-                .register(Box::new(status_gauge.clone()))
-                .unwrap_or_else(|_| panic!("failed to register gauge: {}", tag));
-            GAUGES
-                .lock()
-                .expect("GAUGES mutex is poisoned")
-                .insert(status_tag, status_gauge);
-
-            let latency_tag = format!("{}_latency_ms", &tag);
-            let latency_opts = opts!(latency_tag.clone(), format!("latency for {}", &tag));
-            let latency_gauge = register_gauge!(latency_opts)
-                .unwrap_or_else(|_| panic!("failed to create latency gauge for {}", &tag));
-            registry
-                .register(Box::new(latency_gauge.clone()))
-                .unwrap_or_else(|_| panic!("failed to register gauge: {}", &tag));
-            GAUGES
-                .lock()
-                .expect("GAUGES mutex is poisoned")
-                .insert(latency_tag, latency_gauge);
-        }
+        // We want metrics setup failures to surface ASAP (on startup)
+        let up = register(
+            &registry,
+            GaugeVec::new(
+                opts!(
+                    "rcanary_target_up",
+                    "1 if the last check of the target was okay, 0 otherwise"
+                ),
+                LABELS,
+            ),
+        );
+        let status_code = register(
+            &registry,
+            GaugeVec::new(
+                opts!(
+                    "rcanary_http_status_code",
+                    "highest HTTP status code returned by the target, -1 if none"
+                ),
+                LABELS,
+            ),
+        );
+        let duration = register(
+            &registry,
+            GaugeVec::new(
+                opts!(
+                    "rcanary_check_duration_seconds",
+                    "duration of the last check of the target"
+                ),
+                LABELS,
+            ),
+        );
+        let latency = register(
+            &registry,
+            HistogramVec::new(
+                histogram_opts!(
+                    "rcanary_check_latency_seconds",
+                    "latency of checks of the target",
+                    buckets.clone()
+                ),
+                LABELS,
+            ),
+        );
+        let phase_latency = register(
+            &registry,
+            HistogramVec::new(
+                histogram_opts!(
+                    "rcanary_check_phase_latency_seconds",
+                    "latency of each phase of checks of the target, per resolved address",
+                    buckets
+                ),
+                &["name", "host", "tag", "phase"],
+            ),
+        );
+        let checks = register(
+            &registry,
+            IntCounterVec::new(
+                opts!("rcanary_checks_total", "checks of the target"),
+                LABELS,
+            ),
+        );
+        let failures = register(
+            &registry,
+            IntCounterVec::new(
+                opts!(
+                    "rcanary_check_failures_total",
+                    "checks of the target which were not okay, by reason"
+                ),
+                &["name", "host", "tag", "reason"],
+            ),
+        );
+        let transitions = register(
+            &registry,
+            IntCounterVec::new(
+                opts!(
+                    "rcanary_status_transitions_total",
+                    "changes in the status of the target"
+                ),
+                &["name", "host", "tag", "from", "to"],
+            ),
+        );
+        let alerts_sent = register(
+            &registry,
+            IntCounterVec::new(
+                opts!(
+                    "rcanary_alerts_sent_total",
+                    "alerts delivered to the receiver"
+                ),
+                &["receiver"],
+            ),
+        );
+        let alerts_failed = register(
+            &registry,
+            IntCounterVec::new(
+                opts!(
+                    "rcanary_alerts_failed_total",
+                    "alerts which could not be delivered to the receiver"
+                ),
+                &["receiver"],
+            ),
+        );
+        let process = ProcessMetrics::new(&registry);
+
+        let metrics = PrometheusMetrics {
+            registry,
+            up,
+            status_code,
+            duration,
+            latency,
+            phase_latency,
+            checks,
+            failures,
+            transitions,
+            alerts_sent,
+            alerts_failed,
+            process,
+            last_statuses: Arc::new(Mutex::new(HashMap::new())),
+        };
+        metrics.targets_changed(targets);
 
-        PrometheusMetrics { registry }
+        metrics
     }
+}
 
-    fn update(&self, tag: &str, result: &CanaryCheck) -> Result<(), String> {
-        if let Ok(gauges) = GAUGES.lock() {
-            let status_gauge = gauges.get(&format!("{}_status", tag));
-            if let Some(gauge) = status_gauge {
-                gauge.set(result.status_code.parse::<f64>().unwrap_or(-1.0f64));
-            }
+impl Metrics for PrometheusMetrics {
+    fn update(&self, result: &CanaryCheck) -> Result<(), String> {
+        let labels = label_values(&result.target);
 
-            let latency_gauge = gauges.get(&format!("{}_latency_ms", tag));
-            if let Some(gauge) = latency_gauge {
-                gauge.set(result.latency_ms as f64);
-            }
+        let up = if result.status == Status::Okay {
+            1.0
         } else {
-            return Err("Failed to update gauges: GAUGES mutex is poisoned".to_string());
+            0.0
+        };
+        let status_code = result
+            .elements
+            .iter()
+            .map(|e| f64::from(e.status_code))
+            .fold(-1.0f64, f64::max);
+        let latency_s = result.latency_ms as f64 / 1000.0;
+
+        self.up.with_label_values(&labels).set(up);
+        self.status_code.with_label_values(&labels).set(status_code);
+        self.duration.with_label_values(&labels).set(latency_s);
+        self.latency.with_label_values(&labels).observe(latency_s);
+        self.checks.with_label_values(&labels).inc();
+
+        for phase in result.elements.iter().flat_map(|e| &e.phases) {
+            self.phase_latency
+                .with_label_values(&[labels[0], labels[1], labels[2], &phase.name])
+                .observe(phase.latency_ms as f64 / 1000.0);
+        }
+
+        if let Some(reason) = failure_reason(result) {
+            self.failures
+                .with_label_values(&[labels[0], labels[1], labels[2], reason])
+                .inc();
+        }
+
+        let previous = self
+            .last_statuses
+            .lock()
+            .map_err(|_| "last statuses mutex is poisoned".to_string())?
+            .insert(result.target.name.clone(), result.status.clone());
+        if let Some(previous) = previous {
+            if previous != result.status {
+                let from = format!("{:?}", previous);
+                let to = format!("{:?}", result.status);
+                self.transitions
+                    .with_label_values(&[labels[0], labels[1], labels[2], &from, &to])
+                    .inc();
+            }
         }
 
         Ok(())
     }
 
+    // Drops the series of targets which are no longer checked, or whose labels changed, and
+    // exports new targets before their first check completes
+    fn targets_changed(&self, targets: &CanaryTargetTypes) {
+        let checked = targets
+            .http
+            .iter()
+            .filter(|t| !t.paused)
+            .map(|t| label_values(t).map(String::from))
+            .collect::<HashSet<_>>();
+        let exported = series(&self.checks)
+            .iter()
+            .map(target_labels)
+            .collect::<HashSet<_>>();
+
+        forget_targets(&self.up, &checked);
+        forget_targets(&self.status_code, &checked);
+        forget_targets(&self.duration, &checked);
+        forget_targets(&self.latency, &checked);
+        forget_targets(&self.phase_latency, &checked);
+        forget_targets(&self.checks, &checked);
+        forget_targets(&self.failures, &checked);
+        forget_targets(&self.transitions, &checked);
+        if let Ok(mut last_statuses) = self.last_statuses.lock() {
+            last_statuses.retain(|name, _| checked.iter().any(|labels| &labels[0] == name));
+        }
+
+        for labels in checked.difference(&exported) {
+            let labels = [&*labels[0], &*labels[1], &*labels[2]];
+            self.up.with_label_values(&labels).set(0.0);
+            self.checks.with_label_values(&labels);
+        }
+    }
+
+    fn alert_delivery(&self, receiver: &str, alerts: usize, delivered: bool) {
+        let counter = if delivered {
+            &self.alerts_sent
+        } else {
+            &self.alerts_failed
+        };
+        counter.with_label_values(&[receiver]).inc_by(alerts as i64);
+    }
+
     fn print(&self) -> Result<String, String> {
+        self.process.refresh();
+
         let mut buffer = vec![];
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -94,21 +275,101 @@ impl Metrics for PrometheusMetrics {
     }
 }
 
+pub(super) fn register<C>(registry: &Registry, collector: prometheus::Result<C>) -> C
+where
+    C: Collector + Clone + 'static,
+{
+    let collector = collector.unwrap_or_else(|err| panic!("failed to create metric: {}", err));
+    registry
+        .register(Box::new(collector.clone()))
+        .unwrap_or_else(|err| panic!("failed to register metric: {}", err));
+
+    collector
+}
+
+// Label names and values of every series of a metric
+fn series<T: MetricVecBuilder>(vec: &MetricVec<T>) -> Vec<HashMap<String, String>> {
+    vec.collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| {
+            metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+fn target_labels(labels: &HashMap<String, String>) -> [String; 3] {
+    let value = |name| labels.get(name).cloned().unwrap_or_default();
+    [value("name"), value("host"), value("tag")]
+}
+
+// Removes the series of targets which are not in `checked`, whatever their other labels
+fn forget_targets<T: MetricVecBuilder>(vec: &MetricVec<T>, checked: &HashSet<[String; 3]>) {
+    for labels in series(vec) {
+        if !checked.contains(&target_labels(&labels)) {
+            let labels = labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            let _ = vec.remove(&labels);
+        }
+    }
+}
+
+fn label_values(target: &CanaryTarget) -> [&str; 3] {
+    [
+        &target.name,
+        &target.host,
+        target.tag.as_ref().map_or("", String::as_str),
+    ]
+}
+
+// Why a check was not okay, for the `reason` label
+fn failure_reason(result: &CanaryCheck) -> Option<&'static str> {
+    if result.status == Status::Okay {
+        return None;
+    }
+
+    let reason = if result.elements.is_empty() {
+        if result.status_reason == crate::STATUS_REASON_BAD_URL {
+            "bad_url"
+        } else {
+            "poll_error"
+        }
+    } else if result.elements.iter().any(|e| e.status_code == 0) {
+        "connection"
+    } else if result.elements.iter().any(|e| e.status_code >= 400) {
+        "http_status"
+    } else {
+        "latency"
+    };
+
+    Some(reason)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use librcanary::*;
+    use std::collections::BTreeMap;
 
     fn test_targets() -> CanaryTargetTypes {
         CanaryTargetTypes {
             http: vec![CanaryTarget {
                 alert: false,
                 basic_auth: None,
+                depends_on: vec![],
                 host: "127.0.0.1".to_string(),
                 interval_s: 10,
+                labels: BTreeMap::new(),
                 name: "foo".to_string(),
-                tag_metric: Some("footag".to_string()),
-                tag: None,
+                paused: false,
+                tag_metric: None,
+                tag: Some("db".to_string()),
             }],
         }
     }
@@ -117,6 +378,23 @@ mod tests {
         let target = test_targets().http.get(0).unwrap().clone();
         CanaryCheck {
             alert: false,
+            blocked_by: vec![],
+            elements: vec![
+                CanaryCheckElement {
+                    address: "127.0.0.1".to_string(),
+                    error: None,
+                    latency_ms: 1234,
+                    phases: vec![],
+                    status_code: 200,
+                },
+                CanaryCheckElement {
+                    address: "127.0.0.2".to_string(),
+                    error: None,
+                    latency_ms: 1000,
+                    phases: vec![],
+                    status_code: 204,
+                },
+            ],
             latency_ms: 1234,
             need_to_alert: false,
             status_code: "200".to_string(),
@@ -124,27 +402,176 @@ mod tests {
             status: Status::Okay,
             target,
             time: "1234".to_string(),
+            traceparent: None,
+        }
+    }
+
+    fn metrics() -> PrometheusMetrics {
+        let config = CanaryMetricsConfig {
+            latency_buckets_ms: vec![100, 1000, 5000],
+            ..CanaryMetricsConfig::default()
+        };
+
+        PrometheusMetrics::new(&config, &test_targets())
+    }
+
+    fn assert_contains(printed: &str, lines: &[&str]) {
+        for line in lines {
+            assert!(
+                printed.contains(&format!("{}\n", line)),
+                "missing `{}` in:\n{}",
+                line,
+                printed
+            );
         }
     }
 
     #[test]
     fn it_creates_updates_and_prints_the_metrics_registry() {
-        let targets = test_targets();
-        let metrics: PrometheusMetrics = Metrics::new(&targets);
+        let metrics = metrics();
 
         let ok = ok_result();
-        metrics
-            .update("footag", &ok)
-            .expect("failed to update metrics");
-
-        let expected = "# HELP footag_latency_ms latency for footag\n\
-                        # TYPE footag_latency_ms gauge\n\
-                        footag_latency_ms 1234\n\
-                        # HELP footag_status status for footag\n\
-                        # TYPE footag_status gauge\n\
-                        footag_status 200\n\
-                        ";
-
-        assert_eq!(metrics.print().unwrap(), expected);
+        metrics.update(&ok).expect("failed to update metrics");
+
+        assert_contains(
+            &metrics.print().unwrap(),
+            &[
+                "# HELP rcanary_check_duration_seconds duration of the last check of the target",
+                "# TYPE rcanary_check_duration_seconds gauge",
+                "rcanary_check_duration_seconds{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 1.234",
+                "# TYPE rcanary_http_status_code gauge",
+                "rcanary_http_status_code{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 204",
+                "# TYPE rcanary_target_up gauge",
+                "rcanary_target_up{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 1",
+                "rcanary_checks_total{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 1",
+            ],
+        );
+    }
+
+    #[test]
+    fn it_exports_targets_before_their_first_check() {
+        assert_contains(
+            &metrics().print().unwrap(),
+            &[
+                "rcanary_target_up{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 0",
+                "rcanary_checks_total{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 0",
+            ],
+        );
+    }
+
+    #[test]
+    fn it_forgets_targets_which_are_no_longer_checked() {
+        let metrics = metrics();
+        let mut fire = ok_result();
+        fire.status = Status::Fire;
+        metrics.update(&fire).unwrap();
+
+        let mut targets = test_targets();
+        targets.http[0].paused = true;
+        metrics.targets_changed(&targets);
+        assert!(!metrics.print().unwrap().contains("name=\"foo\""));
+
+        targets.http[0].paused = false;
+        targets.http[0].host = "127.0.0.2".to_string();
+        metrics.targets_changed(&targets);
+        let printed = metrics.print().unwrap();
+        assert_contains(
+            &printed,
+            &["rcanary_target_up{host=\"127.0.0.2\",name=\"foo\",tag=\"db\"} 0"],
+        );
+        assert!(!printed.contains("host=\"127.0.0.1\""));
+
+        // Series of targets still being checked are kept as they are
+        let mut changed = ok_result();
+        changed.target = targets.http[0].clone();
+        metrics.update(&changed).unwrap();
+        metrics.targets_changed(&targets);
+        assert_contains(
+            &metrics.print().unwrap(),
+            &["rcanary_target_up{host=\"127.0.0.2\",name=\"foo\",tag=\"db\"} 1"],
+        );
+    }
+
+    #[test]
+    fn it_records_latency_histograms_per_target_and_phase() {
+        let metrics = metrics();
+        let mut ok = ok_result();
+        ok.elements[0].phases = vec![
+            CanaryCheckPhase {
+                latency_ms: 50,
+                name: "tls-handshake".to_string(),
+                offset_ms: 0,
+            },
+            CanaryCheckPhase {
+                latency_ms: 1184,
+                name: "http".to_string(),
+                offset_ms: 0,
+            },
+        ];
+
+        metrics.update(&ok).unwrap();
+
+        assert_contains(
+            &metrics.print().unwrap(),
+            &[
+                "# TYPE rcanary_check_latency_seconds histogram",
+                "rcanary_check_latency_seconds_bucket{host=\"127.0.0.1\",name=\"foo\",tag=\"db\",le=\"1\"} 0",
+                "rcanary_check_latency_seconds_bucket{host=\"127.0.0.1\",name=\"foo\",tag=\"db\",le=\"5\"} 1",
+                "rcanary_check_latency_seconds_count{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 1",
+                "rcanary_check_phase_latency_seconds_bucket{host=\"127.0.0.1\",name=\"foo\",phase=\"tls-handshake\",tag=\"db\",le=\"0.1\"} 1",
+                "rcanary_check_phase_latency_seconds_bucket{host=\"127.0.0.1\",name=\"foo\",phase=\"http\",tag=\"db\",le=\"0.1\"} 0",
+            ],
+        );
+    }
+
+    #[test]
+    fn it_counts_failures_transitions_and_alerts() {
+        let metrics = metrics();
+        let mut fire = ok_result();
+        fire.status = Status::Fire;
+        fire.elements[1].status_code = 503;
+
+        metrics.update(&ok_result()).unwrap();
+        metrics.update(&fire).unwrap();
+        metrics.update(&fire).unwrap();
+        metrics.update(&ok_result()).unwrap();
+        metrics.alert_delivery("email", 2, true);
+        metrics.alert_delivery("email", 1, false);
+
+        assert_contains(
+            &metrics.print().unwrap(),
+            &[
+                "rcanary_checks_total{host=\"127.0.0.1\",name=\"foo\",tag=\"db\"} 4",
+                "rcanary_check_failures_total{host=\"127.0.0.1\",name=\"foo\",reason=\"http_status\",tag=\"db\"} 2",
+                "rcanary_status_transitions_total{from=\"Okay\",host=\"127.0.0.1\",name=\"foo\",tag=\"db\",to=\"Fire\"} 1",
+                "rcanary_status_transitions_total{from=\"Fire\",host=\"127.0.0.1\",name=\"foo\",tag=\"db\",to=\"Okay\"} 1",
+                "rcanary_alerts_sent_total{receiver=\"email\"} 2",
+                "rcanary_alerts_failed_total{receiver=\"email\"} 1",
+            ],
+        );
+    }
+
+    #[test]
+    fn it_gives_reasons_for_failures() {
+        let mut bad_url = crate::tests::target();
+        bad_url.host = "http://bad url/".to_string();
+        assert_eq!(
+            Some("bad_url"),
+            failure_reason(&crate::check_host(&bad_url, false))
+        );
+
+        let mut fire = ok_result();
+        fire.status = Status::Fire;
+        fire.elements[0].status_code = 0;
+        assert_eq!(Some("connection"), failure_reason(&fire));
+        assert_eq!(None, failure_reason(&ok_result()));
+    }
+
+    #[test]
+    fn it_exports_process_metrics() {
+        let printed = metrics().print().unwrap();
+
+        assert!(printed.contains("rcanary_build_info{version="));
+        assert!(printed.contains("process_start_time_seconds "));
     }
 }
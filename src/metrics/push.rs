@@ -0,0 +1,468 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use hyper::Uri;
+use log::info;
+
+use librcanary::{CanaryCheck, CanaryPushMetricsConfig, Status};
+
+use super::{post, sanitize_name, Metrics, PUSH_TIMEOUT};
+
+/// Somewhere to write batches of lines to
+trait Sink: Send {
+    fn write(&mut self, lines: &[String]) -> Result<(), String>;
+}
+
+/// Posts lines to the InfluxDB HTTP write API, e.g. `http://localhost:8086/write?db=rcanary`
+struct InfluxDbSink {
+    url: Uri,
+}
+
+impl Sink for InfluxDbSink {
+    fn write(&mut self, lines: &[String]) -> Result<(), String> {
+        post(&self.url, "text/plain; charset=utf-8", lines.join("\n"))
+            .map_err(|e| format!("failed to write to InfluxDB: {}", e))
+    }
+}
+
+/// Writes lines to a Graphite plaintext listener, reconnecting after errors
+struct GraphiteSink {
+    address: String,
+    stream: Option<TcpStream>,
+}
+
+impl Sink for GraphiteSink {
+    fn write(&mut self, lines: &[String]) -> Result<(), String> {
+        if self.stream.is_none() {
+            let stream = connect(&self.address)
+                .map_err(|e| format!("failed to connect to Graphite: {}", e))?;
+            self.stream = Some(stream);
+        }
+
+        let mut payload = lines.join("\n");
+        payload.push('\n');
+
+        let written = self
+            .stream
+            .as_mut()
+            .map_or(Ok(()), |s| s.write_all(payload.as_bytes()));
+        written.map_err(|e| {
+            self.stream = None;
+            format!("failed to write to Graphite: {}", e)
+        })
+    }
+}
+
+// Connects without waiting forever for a collector which does not answer
+fn connect(address: &str) -> std::io::Result<TcpStream> {
+    let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve")
+    })?;
+    let stream = TcpStream::connect_timeout(&address, PUSH_TIMEOUT)?;
+    stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+
+    Ok(stream)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Format {
+    InfluxDb,
+    Graphite { prefix: String },
+}
+
+// Lines waiting to be written, bounded so an unreachable sink cannot use up all memory
+struct Buffer {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Buffer {
+    // Drops the oldest lines over capacity
+    fn trim(&mut self) {
+        let excess = self.lines.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            self.lines.drain(..excess);
+            info!("[metrics.push] buffer is full, dropped {} points", excess);
+        }
+    }
+}
+
+/// Batches check results and pushes them to InfluxDB or Graphite.
+///
+/// Lines are written every `flush_interval_ms`, or as soon as `batch_size` lines are waiting.
+/// While the sink is down, lines are kept (up to `buffer_size`) and retried on the next flush.
+/// Only the flush thread writes to the sink, so a slow sink never holds up checks.
+pub struct PushMetrics {
+    format: Format,
+    batch_size: usize,
+    buffer: Arc<Mutex<Buffer>>,
+    wake: Mutex<Sender<()>>,
+}
+
+impl PushMetrics {
+    pub fn new(config: &CanaryPushMetricsConfig) -> Result<PushMetrics, String> {
+        let (format, sink): (Format, Box<dyn Sink>) = match config.format.as_str() {
+            "influxdb" => {
+                let url = config
+                    .address
+                    .parse::<Uri>()
+                    .map_err(|e| format!("invalid InfluxDB URL {}: {}", config.address, e))?;
+                (Format::InfluxDb, Box::new(InfluxDbSink { url }))
+            }
+            "graphite" => (
+                Format::Graphite {
+                    prefix: config.prefix.clone(),
+                },
+                Box::new(GraphiteSink {
+                    address: config.address.clone(),
+                    stream: None,
+                }),
+            ),
+            other => return Err(format!("unknown push metrics format `{}`", other)),
+        };
+
+        Ok(PushMetrics::start(config, format, sink))
+    }
+
+    fn start(config: &CanaryPushMetricsConfig, format: Format, sink: Box<dyn Sink>) -> PushMetrics {
+        let (wake, woken) = mpsc::channel();
+        let metrics = PushMetrics {
+            format,
+            batch_size: config.batch_size.max(1),
+            buffer: Arc::new(Mutex::new(Buffer {
+                lines: VecDeque::new(),
+                capacity: config.buffer_size,
+            })),
+            wake: Mutex::new(wake),
+        };
+
+        let buffer = metrics.buffer.clone();
+        let mut sink = sink;
+        let batch_size = metrics.batch_size;
+        let interval = Duration::from_millis(config.flush_interval_ms);
+
+        thread::spawn(move || loop {
+            let stopped = woken.recv_timeout(interval) == Err(RecvTimeoutError::Disconnected);
+            flush(&buffer, &mut *sink, batch_size);
+
+            if stopped {
+                return;
+            }
+        });
+
+        metrics
+    }
+
+    fn push(&self, lines: Vec<String>) {
+        let mut buffer = self.buffer.lock().expect("push buffer mutex is poisoned");
+        buffer.lines.extend(lines);
+        buffer.trim();
+
+        if buffer.lines.len() >= self.batch_size {
+            drop(buffer);
+            let _ = self.wake.lock().map(|wake| wake.send(()));
+        }
+    }
+
+    fn check_lines(&self, result: &CanaryCheck, now: time::Timespec) -> Vec<String> {
+        let up = if result.status == Status::Okay { 1 } else { 0 };
+        let status_code = result.elements.iter().map(|e| e.status_code).max();
+        let phases = result.elements.iter().flat_map(|e| &e.phases);
+        let target = &result.target;
+
+        match self.format {
+            Format::InfluxDb => {
+                let mut tags = vec![("name", target.name.as_str()), ("host", &target.host)];
+                if let Some(ref tag) = target.tag {
+                    tags.push(("tag", tag));
+                }
+                let tags = influx_tags(&tags);
+                let timestamp = now.sec * 1_000_000_000 + i64::from(now.nsec);
+
+                let mut fields = format!("up={}i,latency_ms={}i", up, result.latency_ms);
+                if let Some(status_code) = status_code {
+                    fields.push_str(&format!(",status_code={}i", status_code));
+                }
+
+                let mut lines = vec![format!("rcanary_check,{} {} {}", tags, fields, timestamp)];
+                for phase in phases {
+                    lines.push(format!(
+                        "rcanary_check_phase,{},phase={} latency_ms={}i {}",
+                        tags,
+                        influx_escape(&phase.name),
+                        phase.latency_ms,
+                        timestamp
+                    ));
+                }
+                lines
+            }
+            Format::Graphite { ref prefix } => {
+                let path = format!("{}.target.{}", prefix, sanitize_name(&target.name));
+
+                let mut lines = vec![
+                    format!("{}.up {} {}", path, up, now.sec),
+                    format!("{}.latency_ms {} {}", path, result.latency_ms, now.sec),
+                ];
+                if let Some(status_code) = status_code {
+                    lines.push(format!("{}.status_code {} {}", path, status_code, now.sec));
+                }
+                for phase in phases {
+                    lines.push(format!(
+                        "{}.phase.{}.latency_ms {} {}",
+                        path,
+                        sanitize_name(&phase.name),
+                        phase.latency_ms,
+                        now.sec
+                    ));
+                }
+                lines
+            }
+        }
+    }
+}
+
+impl Metrics for PushMetrics {
+    fn update(&self, result: &CanaryCheck) -> Result<(), String> {
+        self.push(self.check_lines(result, time::get_time()));
+        Ok(())
+    }
+
+    fn alert_delivery(&self, receiver: &str, alerts: usize, delivered: bool) {
+        let now = time::get_time();
+        let name = if delivered { "sent" } else { "failed" };
+
+        let line = match self.format {
+            Format::InfluxDb => format!(
+                "rcanary_alerts,receiver={} {}={}i {}",
+                influx_escape(receiver),
+                name,
+                alerts,
+                now.sec * 1_000_000_000 + i64::from(now.nsec)
+            ),
+            Format::Graphite { ref prefix } => format!(
+                "{}.alerts.{}.{} {} {}",
+                prefix,
+                sanitize_name(receiver),
+                name,
+                alerts,
+                now.sec
+            ),
+        };
+
+        self.push(vec![line]);
+    }
+}
+
+// Writes everything buffered, stopping at the first failure
+fn flush(buffer: &Mutex<Buffer>, sink: &mut dyn Sink, batch_size: usize) {
+    loop {
+        let batch = {
+            let mut buffer = buffer.lock().expect("push buffer mutex is poisoned");
+            let size = batch_size.min(buffer.lines.len());
+            buffer.lines.drain(..size).collect::<Vec<_>>()
+        };
+
+        if batch.is_empty() {
+            return;
+        }
+
+        if let Err(err) = sink.write(&batch) {
+            info!(
+                "[metrics.push] failed to push {} points, will retry: {}",
+                batch.len(),
+                err
+            );
+
+            // Put the batch back in front of anything buffered since, keeping the newest lines
+            let mut buffer = buffer.lock().expect("push buffer mutex is poisoned");
+            for line in batch.into_iter().rev() {
+                buffer.lines.push_front(line);
+            }
+            buffer.trim();
+            return;
+        }
+    }
+}
+
+fn influx_tags(tags: &[(&str, &str)]) -> String {
+    tags.iter()
+        .map(|(k, v)| format!("{}={}", k, influx_escape(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Tag values cannot contain unescaped commas, equals signs or spaces
+fn influx_escape(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::check;
+    use librcanary::{CanaryCheckElement, CanaryCheckPhase};
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    // Records what it is sent, or fails while `down` is set
+    struct FakeSink {
+        down: Arc<Mutex<bool>>,
+        written: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Sink for FakeSink {
+        fn write(&mut self, lines: &[String]) -> Result<(), String> {
+            if *self.down.lock().unwrap() {
+                return Err("connection refused".to_string());
+            }
+
+            self.written.lock().unwrap().extend(lines.iter().cloned());
+            Ok(())
+        }
+    }
+
+    fn config(format: &str, address: &str) -> CanaryPushMetricsConfig {
+        CanaryPushMetricsConfig {
+            address: address.to_string(),
+            batch_size: 100,
+            buffer_size: 3,
+            enabled: true,
+            // Only flush when told to, unless a test fills a batch
+            flush_interval_ms: 60_000,
+            format: format.to_string(),
+            prefix: "rcanary".to_string(),
+        }
+    }
+
+    fn result(latency_ms: u64) -> CanaryCheck {
+        CanaryCheck {
+            elements: vec![CanaryCheckElement {
+                address: "127.0.0.1".to_string(),
+                error: None,
+                latency_ms,
+                phases: vec![CanaryCheckPhase {
+                    latency_ms: 20,
+                    name: "http".to_string(),
+                    offset_ms: 0,
+                }],
+                status_code: 200,
+            }],
+            latency_ms,
+            status_code: "200 OK".to_string(),
+            ..check("foo bar", Status::Okay)
+        }
+    }
+
+    fn wait_for<F: Fn() -> bool>(done: F) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn it_formats_influxdb_lines() {
+        let metrics = PushMetrics::new(&config("influxdb", "http://localhost:8086/write")).unwrap();
+
+        let actual = metrics.check_lines(&result(125), time::Timespec::new(1, 5));
+
+        assert_eq!(
+            vec![
+                "rcanary_check,name=foo\\ bar,host=invalid,tag=tag up=1i,latency_ms=125i,status_code=200i 1000000005",
+                "rcanary_check_phase,name=foo\\ bar,host=invalid,tag=tag,phase=http latency_ms=20i 1000000005",
+            ],
+            actual
+        );
+    }
+
+    #[test]
+    fn it_buffers_while_the_sink_is_down() {
+        let down = Arc::new(Mutex::new(true));
+        let written = Arc::new(Mutex::new(vec![]));
+        let sink = || FakeSink {
+            down: down.clone(),
+            written: written.clone(),
+        };
+        let mut config = config("graphite", "");
+        config.buffer_size = 5;
+        let metrics = PushMetrics::start(
+            &config,
+            Format::Graphite {
+                prefix: "rcanary".to_string(),
+            },
+            Box::new(sink()),
+        );
+
+        metrics.alert_delivery("email", 1, true);
+        metrics.alert_delivery("email", 2, true);
+        flush(&metrics.buffer, &mut sink(), metrics.batch_size);
+        assert!(written.lock().unwrap().is_empty());
+
+        // The oldest points are dropped to make room
+        metrics.update(&result(125)).unwrap();
+        *down.lock().unwrap() = false;
+        flush(&metrics.buffer, &mut sink(), metrics.batch_size);
+
+        let written = written.lock().unwrap();
+        assert_eq!(5, written.len());
+        assert!(written[0].starts_with("rcanary.alerts.email.sent 2 "));
+        assert!(written[1].starts_with("rcanary.target.foo_bar.up 1 "));
+        assert!(written[4].starts_with("rcanary.target.foo_bar.phase.http.latency_ms 20 "));
+    }
+
+    #[test]
+    fn it_pushes_to_graphite() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = config("graphite", &listener.local_addr().unwrap().to_string());
+        config.batch_size = 1;
+        config.buffer_size = 100;
+        let metrics = PushMetrics::new(&config).unwrap();
+
+        metrics.update(&result(125)).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("rcanary.target.foo_bar.up 1 "));
+    }
+
+    #[test]
+    fn it_pushes_to_influxdb() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            rouille::start_server("127.0.0.1:56477", move |request| {
+                let mut body = String::new();
+                request.data().unwrap().read_to_string(&mut body).unwrap();
+                server_received
+                    .lock()
+                    .unwrap()
+                    .push((request.raw_url().to_string(), body));
+                rouille::Response::text("").with_status_code(204)
+            });
+        });
+        thread::sleep(Duration::from_millis(250));
+
+        let mut config = config("influxdb", "http://127.0.0.1:56477/write?db=rcanary");
+        config.batch_size = 2;
+        let metrics = PushMetrics::new(&config).unwrap();
+
+        metrics.update(&result(125)).unwrap();
+
+        wait_for(|| !received.lock().unwrap().is_empty());
+        let received = received.lock().unwrap();
+        assert_eq!("/write?db=rcanary", received[0].0);
+        assert!(received[0]
+            .1
+            .starts_with("rcanary_check,name=foo\\ bar,host=invalid,tag=tag up=1i"));
+    }
+}
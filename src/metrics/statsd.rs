@@ -0,0 +1,263 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use librcanary::{CanaryCheck, CanaryStatsdConfig, CanaryTarget, Status};
+
+use super::{sanitize_name, Metrics};
+
+/// Pushes metrics to a StatsD server over UDP after every check.
+///
+/// Plain StatsD has no tags, so target names are part of the metric name,
+/// e.g. `rcanary.target.some_target.up`. With `dogstatsd` set the target's
+/// name, host and tag are sent as tags instead, e.g. `rcanary.target.up|#name:some_target`.
+pub struct StatsdMetrics {
+    config: CanaryStatsdConfig,
+    socket: UdpSocket,
+}
+
+impl StatsdMetrics {
+    pub fn new(config: &CanaryStatsdConfig) -> Result<StatsdMetrics, String> {
+        let address = config
+            .address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| format!("failed to resolve {}", config.address))?;
+        // The local socket has to be of the same family as the server
+        let local = if address.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+        socket
+            .connect(address)
+            .map_err(|e| format!("failed to connect to {}: {}", config.address, e))?;
+
+        Ok(StatsdMetrics {
+            config: config.clone(),
+            socket,
+        })
+    }
+
+    fn send(&self, lines: &[String]) -> Result<(), String> {
+        // Several metrics fit in a single datagram, one per line
+        self.socket
+            .send(lines.join("\n").as_bytes())
+            .map(|_| ())
+            .map_err(|e| format!("failed to send statsd metrics: {}", e))
+    }
+
+    // Formats a metric about a target, with extra (key, value) tags for DogStatsD
+    fn line(
+        &self,
+        target: &CanaryTarget,
+        name: &str,
+        value: &str,
+        kind: &str,
+        extra: &[(&str, &str)],
+    ) -> String {
+        if self.config.dogstatsd {
+            let mut tags = vec![
+                ("name", target.name.as_str()),
+                ("host", target.host.as_str()),
+            ];
+            if let Some(ref tag) = target.tag {
+                tags.push(("tag", tag));
+            }
+            tags.extend_from_slice(extra);
+
+            let tags = tags
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, sanitize_tag(v)))
+                .collect::<Vec<_>>();
+
+            format!(
+                "{}.target.{}:{}|{}|#{}",
+                self.config.prefix,
+                name,
+                value,
+                kind,
+                tags.join(",")
+            )
+        } else {
+            let mut path = vec![sanitize_name(&target.name)];
+            path.extend(extra.iter().map(|(_, v)| sanitize_name(v)));
+
+            format!(
+                "{}.target.{}.{}:{}|{}",
+                self.config.prefix,
+                path.join("."),
+                name,
+                value,
+                kind
+            )
+        }
+    }
+}
+
+impl Metrics for StatsdMetrics {
+    fn update(&self, result: &CanaryCheck) -> Result<(), String> {
+        let target = &result.target;
+        let up = if result.status == Status::Okay {
+            "1"
+        } else {
+            "0"
+        };
+
+        let mut lines = vec![
+            self.line(target, "checks", "1", "c", &[]),
+            self.line(target, "up", up, "g", &[]),
+            self.line(target, "latency", &result.latency_ms.to_string(), "ms", &[]),
+        ];
+
+        if let Some(status_code) = result.elements.iter().map(|e| e.status_code).max() {
+            lines.push(self.line(target, "status_code", &status_code.to_string(), "g", &[]));
+        }
+
+        for phase in result.elements.iter().flat_map(|e| &e.phases) {
+            lines.push(self.line(
+                target,
+                "phase_latency",
+                &phase.latency_ms.to_string(),
+                "ms",
+                &[("phase", &phase.name)],
+            ));
+        }
+
+        self.send(&lines)
+    }
+
+    fn alert_delivery(&self, receiver: &str, alerts: usize, delivered: bool) {
+        let name = if delivered { "sent" } else { "failed" };
+        let line = if self.config.dogstatsd {
+            format!(
+                "{}.alerts.{}:{}|c|#receiver:{}",
+                self.config.prefix,
+                name,
+                alerts,
+                sanitize_tag(receiver)
+            )
+        } else {
+            format!(
+                "{}.alerts.{}.{}:{}|c",
+                self.config.prefix,
+                sanitize_name(receiver),
+                name,
+                alerts
+            )
+        };
+
+        let _ = self.send(&[line]);
+    }
+}
+
+// Tag values cannot contain tag or field separators
+fn sanitize_tag(s: &str) -> String {
+    s.replace([',', '|', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check, target};
+    use librcanary::{CanaryCheckElement, CanaryCheckPhase};
+    use std::time::Duration;
+
+    fn listener() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn receive(socket: &UdpSocket) -> Vec<String> {
+        let mut buffer = [0; 4096];
+        let length = socket.recv(&mut buffer).unwrap();
+
+        String::from_utf8_lossy(&buffer[..length])
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    fn metrics(socket: &UdpSocket, dogstatsd: bool) -> StatsdMetrics {
+        StatsdMetrics::new(&CanaryStatsdConfig {
+            address: socket.local_addr().unwrap().to_string(),
+            enabled: true,
+            prefix: "rcanary".to_string(),
+            dogstatsd,
+        })
+        .unwrap()
+    }
+
+    fn result() -> CanaryCheck {
+        let mut target = target();
+        target.name = "foo bar".to_string();
+        target.host = "http://example.com".to_string();
+
+        CanaryCheck {
+            elements: vec![CanaryCheckElement {
+                address: "127.0.0.1".to_string(),
+                error: None,
+                latency_ms: 120,
+                phases: vec![CanaryCheckPhase {
+                    latency_ms: 20,
+                    name: "tls-handshake".to_string(),
+                    offset_ms: 0,
+                }],
+                status_code: 200,
+            }],
+            latency_ms: 125,
+            status_code: "200 OK".to_string(),
+            target,
+            ..check("foo bar", Status::Okay)
+        }
+    }
+
+    #[test]
+    fn it_pushes_statsd_metrics_after_a_check() {
+        let socket = listener();
+        let metrics = metrics(&socket, false);
+
+        metrics.update(&result()).unwrap();
+
+        assert_eq!(
+            vec![
+                "rcanary.target.foo_bar.checks:1|c",
+                "rcanary.target.foo_bar.up:1|g",
+                "rcanary.target.foo_bar.latency:125|ms",
+                "rcanary.target.foo_bar.status_code:200|g",
+                "rcanary.target.foo_bar.tls-handshake.phase_latency:20|ms",
+            ],
+            receive(&socket)
+        );
+
+        metrics.alert_delivery("email", 2, false);
+        assert_eq!(vec!["rcanary.alerts.email.failed:2|c"], receive(&socket));
+    }
+
+    #[test]
+    fn it_pushes_dogstatsd_tags() {
+        let socket = listener();
+        let metrics = metrics(&socket, true);
+
+        metrics.update(&result()).unwrap();
+
+        let actual = receive(&socket);
+        assert_eq!(
+            "rcanary.target.up:1|g|#name:foo bar,host:http://example.com,tag:tag",
+            actual[1]
+        );
+        assert_eq!(
+            "rcanary.target.phase_latency:20|ms|#name:foo bar,host:http://example.com,tag:tag,phase:tls-handshake",
+            actual[4]
+        );
+
+        metrics.alert_delivery("email", 1, true);
+        assert_eq!(
+            vec!["rcanary.alerts.sent:1|c|#receiver:email"],
+            receive(&socket)
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::Serialize;
+
+use librcanary::CanaryCheck;
+
+/// Checks kept for each target
+const RECENT_CHECKS: usize = 20;
+
+/// The last few checks of every target, shared by websocket clients and the API
+#[derive(Default)]
+pub struct RecentChecks {
+    checks: BTreeMap<String, VecDeque<CanaryCheck>>,
+}
+
+/// Everything a new client needs to show the current state without waiting for checks
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Latest check of every target which has been checked
+    pub latest: Vec<CanaryCheck>,
+    /// Recent checks of every target, oldest first
+    pub history: BTreeMap<String, Vec<CanaryCheck>>,
+}
+
+impl RecentChecks {
+    pub fn new() -> RecentChecks {
+        RecentChecks::default()
+    }
+
+    pub fn push(&mut self, check: CanaryCheck) {
+        let checks = self.checks.entry(check.target.name.clone()).or_default();
+
+        checks.push_back(check);
+        if checks.len() > RECENT_CHECKS {
+            checks.pop_front();
+        }
+    }
+
+    /// Forgets the checks of targets for which `keep` is false
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.checks.retain(|name, _| keep(name));
+    }
+
+    /// Latest check of every target, by name
+    pub fn latest(&self) -> impl Iterator<Item = &CanaryCheck> {
+        self.checks.values().filter_map(|checks| checks.back())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            latest: self.latest().cloned().collect(),
+            history: self
+                .checks
+                .iter()
+                .map(|(name, checks)| (name.clone(), checks.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librcanary::Status;
+
+    fn check(name: &str, latency_ms: u64) -> CanaryCheck {
+        CanaryCheck {
+            latency_ms,
+            status_code: "200 OK".to_string(),
+            ..crate::tests::check(name, Status::Okay)
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_last_few_checks_of_each_target() {
+        let mut recent = RecentChecks::new();
+        for latency_ms in 0..25 {
+            recent.push(check("foo", latency_ms));
+        }
+        recent.push(check("bar", 100));
+
+        let actual = recent.snapshot();
+
+        assert_eq!(
+            vec![100, 24],
+            actual
+                .latest
+                .iter()
+                .map(|c| c.latency_ms)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(RECENT_CHECKS, actual.history["foo"].len());
+        assert_eq!(5, actual.history["foo"][0].latency_ms);
+        assert_eq!(1, actual.history["bar"].len());
+    }
+}
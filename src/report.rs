@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use time::Timespec;
+
+use crate::storage::Storage;
+use librcanary::{CanaryTarget, Status};
+
+const DEFAULT_RANGE_DAYS: i64 = 30;
+
+/// Availability of every target, and of every tag, over a time range
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub from: String,
+    pub to: String,
+    pub targets: Vec<ReportRow>,
+    pub tags: Vec<ReportRow>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReportRow {
+    pub name: String,
+    pub checks: u64,
+    /// Share of checks that were okay, `None` if there were no checks
+    pub uptime_percent: Option<f64>,
+    /// Outages which started during the range
+    pub incidents: u64,
+    /// Mean time to recovery, of outages which ended during the range
+    pub mttr_s: Option<u64>,
+    /// Mean time between failures, time spent okay divided by incidents
+    pub mtbf_s: Option<u64>,
+    /// Latency percentiles of raw checks, checks which have been downsampled are left out
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p95_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Markdown,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "markdown" | "md" => Ok(Format::Markdown),
+            other => Err(format!("unknown report format `{}`", other)),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+}
+
+// What is needed from one or more targets to fill in a row
+#[derive(Default)]
+struct Stats {
+    checks: u64,
+    okay: u64,
+    incidents: u64,
+    repairs_s: Vec<i64>,
+    up_s: i64,
+    latencies: Vec<u64>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.checks += other.checks;
+        self.okay += other.okay;
+        self.incidents += other.incidents;
+        self.repairs_s.extend_from_slice(&other.repairs_s);
+        self.up_s += other.up_s;
+        self.latencies.extend_from_slice(&other.latencies);
+    }
+
+    fn row(mut self, name: &str) -> ReportRow {
+        self.latencies.sort();
+
+        ReportRow {
+            name: name.to_string(),
+            checks: self.checks,
+            uptime_percent: if self.checks > 0 {
+                Some(self.okay as f64 * 100.0 / self.checks as f64)
+            } else {
+                None
+            },
+            incidents: self.incidents,
+            mttr_s: self
+                .repairs_s
+                .iter()
+                .sum::<i64>()
+                .checked_div(self.repairs_s.len() as i64)
+                .map(|s| s as u64),
+            mtbf_s: (self.up_s as u64).checked_div(self.incidents),
+            latency_p50_ms: percentile(&self.latencies, 50),
+            latency_p95_ms: percentile(&self.latencies, 95),
+            latency_p99_ms: percentile(&self.latencies, 99),
+        }
+    }
+}
+
+/// Parses RFC 3339 times, eg. `2019-01-01T00:00:00Z`. `to` defaults to now, and `from` to 30 days
+/// before `to`.
+pub fn parse_range(
+    from: Option<&str>,
+    to: Option<&str>,
+    now: Timespec,
+) -> Result<(Timespec, Timespec), String> {
+    let parse = |t: &str| {
+        time::strptime(t, "%Y-%m-%dT%H:%M:%SZ")
+            .map(|tm| tm.to_timespec())
+            .map_err(|e| format!("invalid time `{}`: {}", t, e))
+    };
+
+    let to = to.map_or(Ok(now), parse)?;
+    let from = from.map_or(Ok(to - time::Duration::days(DEFAULT_RANGE_DAYS)), parse)?;
+
+    if from >= to {
+        return Err("`from` must be before `to`".to_string());
+    }
+
+    Ok((from, to))
+}
+
+pub fn build(
+    storage: &Storage,
+    targets: &[CanaryTarget],
+    from: Timespec,
+    to: Timespec,
+) -> Result<Report, String> {
+    let mut rows = Vec::new();
+    let mut tags: BTreeMap<&str, Stats> = BTreeMap::new();
+
+    for target in targets {
+        let stats = target_stats(storage, &target.name, from.sec, to.sec)?;
+
+        if let Some(ref tag) = target.tag {
+            tags.entry(tag).or_default().merge(&stats);
+        }
+        rows.push(stats.row(&target.name));
+    }
+
+    Ok(Report {
+        from: format_time(from),
+        to: format_time(to),
+        targets: rows,
+        tags: tags
+            .into_iter()
+            .map(|(tag, stats)| stats.row(tag))
+            .collect(),
+    })
+}
+
+fn target_stats(storage: &Storage, target: &str, from: i64, to: i64) -> Result<Stats, String> {
+    let (checks, okay) = storage.availability(target, from, to)?;
+    let mut stats = Stats {
+        checks,
+        okay,
+        latencies: storage.latencies(target, from, to)?,
+        ..Stats::default()
+    };
+
+    // Walk the transitions, starting from whatever state the target was in at `from`
+    let mut down_since = match storage.status_at(target, from)? {
+        Some(Status::Okay) | None => None,
+        Some(_) => Some(from),
+    };
+    let mut up_since = if down_since.is_none() {
+        Some(from)
+    } else {
+        None
+    };
+
+    for transition in storage.transitions(target, from, to)? {
+        match (&transition.to, down_since) {
+            (Status::Okay, Some(since)) => {
+                // Outages carried over from before the range do not count towards MTTR
+                if since > from {
+                    stats.repairs_s.push(transition.time - since);
+                }
+                down_since = None;
+                up_since = Some(transition.time);
+            }
+            (Status::Fire, None) | (Status::Unknown, None) => {
+                stats.incidents += 1;
+                stats.up_s += up_since.map_or(0, |since| transition.time - since);
+                down_since = Some(transition.time);
+                up_since = None;
+            }
+            _ => (),
+        }
+    }
+    stats.up_s += up_since.map_or(0, |since| to - since);
+
+    Ok(stats)
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (p * sorted.len()).div_ceil(100);
+    Some(sorted[rank.max(1) - 1])
+}
+
+fn format_time(t: Timespec) -> String {
+    format!("{}", time::at_utc(t).rfc3339())
+}
+
+pub fn render(report: &Report, format: Format) -> Result<String, String> {
+    match format {
+        Format::Json => serde_json::to_string_pretty(report).map_err(|e| e.to_string()),
+        Format::Csv => {
+            let mut out = String::from(
+                "kind,name,checks,uptime_percent,incidents,mttr_s,mtbf_s,latency_p50_ms,latency_p95_ms,latency_p99_ms\n",
+            );
+            let kinds = report
+                .targets
+                .iter()
+                .map(|r| ("target", r))
+                .chain(report.tags.iter().map(|r| ("tag", r)));
+
+            for (kind, row) in kinds {
+                let mut cells = vec![kind.to_string(), csv_escape(&row.name)];
+                cells.extend(cells_of(row, ""));
+                out.push_str(&cells.join(","));
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        Format::Markdown => {
+            let mut out = format!("# Uptime from {} to {}\n", report.from, report.to);
+            for (title, rows) in &[("Targets", &report.targets), ("Tags", &report.tags)] {
+                if rows.is_empty() {
+                    continue;
+                }
+
+                out.push_str(&format!("\n## {}\n\n", title));
+                out.push_str("| Name | Checks | Uptime % | Incidents | MTTR (s) | MTBF (s) | p50 (ms) | p95 (ms) | p99 (ms) |\n");
+                out.push_str("|---|--:|--:|--:|--:|--:|--:|--:|--:|\n");
+                for row in rows.iter() {
+                    let mut cells = vec![row.name.replace('|', "\\|")];
+                    cells.extend(cells_of(row, "-"));
+                    out.push_str(&format!("| {} |\n", cells.join(" | ")));
+                }
+            }
+            Ok(out)
+        }
+    }
+}
+
+// Everything but the name, with `missing` for values that could not be worked out
+fn cells_of(row: &ReportRow, missing: &str) -> Vec<String> {
+    let optional = |v: Option<u64>| v.map_or(missing.to_string(), |v| v.to_string());
+
+    vec![
+        row.checks.to_string(),
+        row.uptime_percent
+            .map_or(missing.to_string(), |u| format!("{:.3}", u)),
+        row.incidents.to_string(),
+        optional(row.mttr_s),
+        optional(row.mtbf_s),
+        optional(row.latency_p50_ms),
+        optional(row.latency_p95_ms),
+        optional(row.latency_p99_ms),
+    ]
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check, target};
+    use librcanary::{CanaryCheck, CanaryStorageConfig};
+
+    fn storage() -> Storage {
+        Storage::open(&CanaryStorageConfig {
+            enabled: true,
+            path: ":memory:".to_string(),
+            ..CanaryStorageConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn record(storage: &Storage, status: Status, previous: Option<Status>, seconds: i64) {
+        let check = CanaryCheck {
+            latency_ms: seconds as u64,
+            time: format_time(Timespec::new(seconds, 0)),
+            ..check("foo", status)
+        };
+        storage.record(&check, previous.as_ref()).unwrap();
+    }
+
+    #[test]
+    fn it_parses_ranges() {
+        let now = Timespec::new(40 * 24 * 60 * 60, 0);
+
+        assert_eq!(
+            Ok((Timespec::new(10 * 24 * 60 * 60, 0), now)),
+            parse_range(None, None, now)
+        );
+        assert_eq!(
+            Ok((Timespec::new(0, 0), Timespec::new(60, 0))),
+            parse_range(
+                Some("1970-01-01T00:00:00Z"),
+                Some("1970-01-01T00:01:00Z"),
+                now
+            )
+        );
+        assert!(parse_range(Some("yesterday"), None, now).is_err());
+        assert!(parse_range(
+            Some("1970-01-02T00:00:00Z"),
+            Some("1970-01-01T00:00:00Z"),
+            now
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_reports_uptime_incidents_and_latency() {
+        let storage = storage();
+        record(&storage, Status::Okay, None, 0);
+        record(&storage, Status::Fire, Some(Status::Okay), 100);
+        record(&storage, Status::Fire, Some(Status::Fire), 110);
+        record(&storage, Status::Okay, Some(Status::Fire), 160);
+        record(&storage, Status::Unknown, Some(Status::Okay), 300);
+
+        let mut untagged = target();
+        untagged.name = "bar".to_string();
+        untagged.tag = None;
+
+        let report = build(
+            &storage,
+            &[target(), untagged],
+            Timespec::new(0, 0),
+            Timespec::new(400, 0),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ReportRow {
+                name: "foo".to_string(),
+                checks: 5,
+                uptime_percent: Some(40.0),
+                incidents: 2,
+                mttr_s: Some(60),
+                mtbf_s: Some(120),
+                latency_p50_ms: Some(110),
+                latency_p95_ms: Some(300),
+                latency_p99_ms: Some(300),
+            },
+            report.targets[0]
+        );
+        assert_eq!(None, report.targets[1].uptime_percent);
+        assert_eq!(1, report.tags.len());
+        assert_eq!("tag", report.tags[0].name);
+        assert_eq!(report.targets[0].incidents, report.tags[0].incidents);
+    }
+
+    #[test]
+    fn it_carries_outages_over_from_before_the_range() {
+        let storage = storage();
+        record(&storage, Status::Fire, None, 0);
+        record(&storage, Status::Okay, Some(Status::Fire), 100);
+
+        let report = build(
+            &storage,
+            &[target()],
+            Timespec::new(50, 0),
+            Timespec::new(200, 0),
+        )
+        .unwrap();
+
+        assert_eq!(0, report.targets[0].incidents);
+        assert_eq!(None, report.targets[0].mttr_s);
+        assert_eq!(Some(100.0), report.targets[0].uptime_percent);
+    }
+
+    #[test]
+    fn it_renders_csv_and_markdown() {
+        let report = Report {
+            from: "1970-01-01T00:00:00Z".to_string(),
+            to: "1970-01-01T00:01:00Z".to_string(),
+            targets: vec![ReportRow {
+                name: "foo, bar".to_string(),
+                checks: 3,
+                uptime_percent: Some(200.0 / 3.0),
+                incidents: 1,
+                mttr_s: None,
+                mtbf_s: Some(30),
+                latency_p50_ms: Some(10),
+                latency_p95_ms: Some(20),
+                latency_p99_ms: Some(20),
+            }],
+            tags: vec![],
+        };
+
+        let csv = render(&report, Format::Csv).unwrap();
+        assert_eq!(
+            "target,\"foo, bar\",3,66.667,1,,30,10,20,20",
+            csv.lines().nth(1).unwrap()
+        );
+
+        let markdown = render(&report, Format::Markdown).unwrap();
+        assert!(markdown.contains("| foo, bar | 3 | 66.667 | 1 | - | 30 | 10 | 20 | 20 |"));
+        assert!(!markdown.contains("## Tags"));
+
+        assert!(Format::parse("xml").is_err());
+    }
+}
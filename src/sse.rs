@@ -0,0 +1,545 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use rouille::{Request, Response};
+
+use crate::auth::{self, Authenticator};
+use crate::storage;
+use crate::tls::{self, Tls};
+use crate::ws_handler::{Message, Subscription};
+use librcanary::{CanaryAuthScope, CanaryCheck, Status};
+
+/// Comments sent while there are no results, so proxies keep the stream open and dead
+/// clients are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Streams open at once, further clients get a 503. Each stream holds a thread of its own.
+const MAX_CLIENTS: usize = 64;
+const MAX_HEADERS: usize = 64;
+
+/// A check result, as the websocket `result` message
+pub struct Event {
+    id: u64,
+    result: CanaryCheck,
+    previous_status: Option<Status>,
+    data: String,
+}
+
+impl Event {
+    fn to_sse(&self) -> String {
+        format!("id: {}\ndata: {}\n\n", self.id, self.data)
+    }
+}
+
+struct Inner {
+    buffer: VecDeque<Arc<Event>>,
+    next_id: u64,
+    listeners: Vec<Sender<Arc<Event>>>,
+}
+
+/// Hands results to connected SSE clients, keeping the last `capacity` for clients resuming
+/// with `Last-Event-ID`
+pub struct Events {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    clients: AtomicUsize,
+}
+
+impl Events {
+    pub fn new(capacity: usize) -> Events {
+        Events {
+            capacity,
+            inner: Mutex::new(Inner {
+                buffer: VecDeque::new(),
+                next_id: 1,
+                listeners: vec![],
+            }),
+            clients: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn publish(&self, check: &CanaryCheck, previous_status: Option<&Status>) {
+        let mut inner = self.inner.lock().unwrap();
+        let event = Arc::new(Event {
+            id: inner.next_id,
+            result: check.clone(),
+            previous_status: previous_status.cloned(),
+            data: Message::Result(check).to_json(),
+        });
+        inner.next_id += 1;
+
+        inner.buffer.push_back(event.clone());
+        if inner.buffer.len() > self.capacity {
+            inner.buffer.pop_front();
+        }
+
+        // Disconnected clients have dropped their receivers
+        inner
+            .listeners
+            .retain(|listener| listener.send(event.clone()).is_ok());
+    }
+
+    /// Buffered events after `last_event_id`, and a receiver for newer ones. Events which
+    /// have dropped out of the buffer are skipped.
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> (Vec<Arc<Event>>, Receiver<Arc<Event>>) {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::channel();
+        inner.listeners.push(tx);
+
+        let missed = match last_event_id {
+            Some(last) => inner
+                .buffer
+                .iter()
+                .filter(|e| e.id > last)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+
+        (missed, rx)
+    }
+}
+
+/// An open stream, writing results to the client as they are published
+struct Subscriber {
+    events: Arc<Events>,
+    receiver: Receiver<Arc<Event>>,
+    subscription: Subscription,
+    missed: Vec<Arc<Event>>,
+}
+
+impl Subscriber {
+    fn wants(&self, event: &Event) -> bool {
+        self.subscription
+            .wants_result(&event.result, event.previous_status.as_ref())
+    }
+
+    /// Runs until the client disconnects or stops reading
+    fn stream(&self, stream: &mut tls::Stream, head_only: bool) -> io::Result<()> {
+        stream
+            .socket()
+            .set_write_timeout(Some(KEEPALIVE_INTERVAL))?;
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+              X-Accel-Buffering: no\r\nConnection: close\r\n\r\n",
+        )?;
+        if head_only {
+            return stream.flush();
+        }
+
+        // Sent straight away, even without missed results, so clients see the stream open
+        for event in self.missed.iter().filter(|e| self.wants(e)) {
+            stream.write_all(event.to_sse().as_bytes())?;
+        }
+        stream.flush()?;
+
+        loop {
+            let text = match self.receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(event) if self.wants(&event) => event.to_sse(),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            stream.write_all(text.as_bytes())?;
+            stream.flush()?;
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.events.clients.fetch_sub(1, Ordering::SeqCst);
+        info!("[sse.disconnect] {:?}", self.subscription);
+    }
+}
+
+/// Every value of a query parameter, splitting comma separated lists
+fn params(request: &Request, name: &str) -> Vec<String> {
+    url::form_urlencoded::parse(request.raw_query_string().as_bytes())
+        .filter(|(key, _)| key == name)
+        .flat_map(|(_, value)| {
+            value
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn bad_request(err: String) -> Response {
+    Response::text(err).with_status_code(400)
+}
+
+/// The filters and resume point a client asked for
+fn subscription(request: &Request) -> Result<(Subscription, Option<u64>), Response> {
+    let statuses = params(request, "status")
+        .iter()
+        .map(|s| storage::parse_status(s))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(bad_request)?;
+    let subscription = Subscription::new(
+        params(request, "name"),
+        params(request, "tag").pop().as_deref(),
+        statuses,
+    )
+    .map_err(bad_request)?;
+
+    // Browsers send the header when reconnecting, the parameter lets clients resume otherwise
+    let last_event_id = request
+        .header("Last-Event-ID")
+        .map(|id| id.to_string())
+        .or_else(|| params(request, "last_event_id").pop())
+        .map(|id| id.trim().parse::<u64>())
+        .transpose()
+        .map_err(|_| bad_request("invalid last event id".to_string()))?;
+
+    Ok((subscription, last_event_id))
+}
+
+fn open(
+    request: &Request,
+    events: &Arc<Events>,
+    auth: Option<&Authenticator>,
+) -> Result<Subscriber, Response> {
+    // Like the websocket server, clients may pass a token in the URL as `EventSource` cannot
+    // set headers
+    let token = params(request, "token").pop();
+    auth::check_with_token(auth, request, token.as_deref(), CanaryAuthScope::Read)?;
+
+    let (subscription, last_event_id) = subscription(request)?;
+
+    if events.clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+        events.clients.fetch_sub(1, Ordering::SeqCst);
+        info!(
+            "[sse.error] refused {}, too many clients",
+            request.remote_addr()
+        );
+        return Err(Response::text("Too many clients").with_status_code(503));
+    }
+
+    info!("[sse.connect] {} {:?}", request.remote_addr(), subscription);
+    let (missed, receiver) = events.subscribe(last_event_id);
+
+    Ok(Subscriber {
+        events: events.clone(),
+        receiver,
+        subscription,
+        missed,
+    })
+}
+
+/// A subscriber for requests opening a stream, the response to send otherwise
+fn handle(
+    request: &Request,
+    events: &Arc<Events>,
+    auth: Option<&Authenticator>,
+) -> Result<Subscriber, Response> {
+    match (request.method(), request.url().as_str()) {
+        ("GET", "/events") | ("HEAD", "/events") => open(request, events, auth),
+        ("OPTIONS", "/events") => {
+            Err(Response::empty_204().with_additional_header("Allow", "GET, HEAD, OPTIONS"))
+        }
+        _ => Err(Response::empty_404()),
+    }
+}
+
+fn read_request(stream: &mut tls::Stream, peer: SocketAddr) -> Result<Request, String> {
+    let head = tls::read_head(stream).map_err(|err| err.to_string())?;
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Err("incomplete request".to_string()),
+        Err(err) => return Err(err.to_string()),
+    }
+
+    let headers = request
+        .headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_string(),
+                String::from_utf8_lossy(header.value).into_owned(),
+            )
+        })
+        .collect();
+    Ok(Request::fake_http_from(
+        peer,
+        request.method.unwrap_or_default(),
+        request.path.unwrap_or_default(),
+        headers,
+        vec![],
+    ))
+}
+
+fn reason(status_code: u16) -> &'static str {
+    match status_code {
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn respond(stream: &mut tls::Stream, response: Response, head_only: bool) -> io::Result<()> {
+    let (mut reader, _) = response.data.into_reader_and_size();
+    let mut body = vec![];
+    reader.read_to_end(&mut body)?;
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n",
+        response.status_code,
+        reason(response.status_code)
+    )?;
+    for (name, value) in &response.headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    if response.status_code != 204 {
+        write!(stream, "Content-Length: {}\r\n", body.len())?;
+    }
+    stream.write_all(b"Connection: close\r\n\r\n")?;
+    if !head_only {
+        stream.write_all(&body)?;
+    }
+
+    stream.flush()
+}
+
+fn serve(
+    mut stream: tls::Stream,
+    peer: SocketAddr,
+    events: &Arc<Events>,
+    auth: Option<&Authenticator>,
+) {
+    let request = match read_request(&mut stream, peer) {
+        Ok(request) => request,
+        Err(err) => {
+            info!("[sse.error] invalid request from {}: {}", peer, err);
+            let _ = respond(&mut stream, bad_request(err), false);
+            return;
+        }
+    };
+
+    let head_only = request.method() == "HEAD";
+    let _ = match handle(&request, events, auth) {
+        Ok(subscriber) => subscriber.stream(&mut stream, head_only),
+        Err(response) => respond(&mut stream, response, head_only),
+    };
+}
+
+/// Serves SSE clients from a listener of its own, with a thread for each client, so open
+/// streams never hold up other servers
+pub fn start_sse_server(
+    bind_to: &str,
+    events: Arc<Events>,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<Arc<Tls>>,
+) {
+    info!("[status.startup] starting sse server at {}...", bind_to);
+    let listener = TcpListener::bind(bind_to).unwrap_or_else(|err| {
+        panic!("[status.startup] failed to start sse server: {}", err);
+    });
+
+    thread::spawn(move || {
+        tls::accept(listener, tls, move |stream, peer| {
+            serve(stream, peer, &events, auth.as_deref())
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::check;
+    use librcanary::{CanaryAuthConfig, CanaryAuthUser};
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn request(url: &str, headers: &[(&str, &str)]) -> Request {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Request::fake_http_from(
+            "127.0.0.1:4000".parse().unwrap(),
+            "GET",
+            url,
+            headers,
+            vec![],
+        )
+    }
+
+    fn status(result: Result<Subscriber, Response>) -> u16 {
+        match result {
+            Ok(_) => 200,
+            Err(response) => response.status_code,
+        }
+    }
+
+    fn read_until(stream: &mut TcpStream, received: &mut String, text: &str) {
+        let mut buffer = [0; 1024];
+        while !received.contains(text) {
+            let n = stream.read(&mut buffer).unwrap();
+            assert!(n > 0, "stream closed before {:?}", text);
+            received.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+    }
+
+    #[test]
+    fn it_resumes_from_the_buffer() {
+        let events = Events::new(2);
+        for name in &["a", "b", "c"] {
+            events.publish(&check(name, Status::Okay), None);
+        }
+
+        let ids = |last| {
+            let (missed, _) = events.subscribe(last);
+            missed.iter().map(|e| e.id).collect::<Vec<_>>()
+        };
+        assert_eq!(Vec::<u64>::new(), ids(None));
+        assert_eq!(vec![2, 3], ids(Some(0)));
+        assert_eq!(vec![3], ids(Some(2)));
+
+        let (_, receiver) = events.subscribe(None);
+        events.publish(&check("d", Status::Fire), None);
+        let event = receiver.recv().unwrap();
+        assert_eq!(4, event.id);
+        assert!(event
+            .to_sse()
+            .starts_with("id: 4\ndata: {\"version\":1,\"type\":\"result\""));
+        assert!(event.to_sse().ends_with("}\n\n"));
+    }
+
+    #[test]
+    fn it_filters_by_query_parameters() {
+        let parse = |url, headers| subscription(&request(url, headers)).ok().unwrap();
+
+        let (wanted, last_event_id) = parse("/events?name=a,b&status=Fire&last_event_id=7", &[]);
+        assert_eq!(Some(7), last_event_id);
+        let tag = Some("tag".to_string());
+        assert!(wanted.wants("a", tag.as_ref(), Some(&Status::Fire)));
+        assert!(!wanted.wants("a", tag.as_ref(), Some(&Status::Okay)));
+        assert!(!wanted.wants("c", tag.as_ref(), Some(&Status::Fire)));
+
+        let (wanted, last_event_id) = parse("/events?tag=%5Eweb", &[("Last-Event-ID", "3")]);
+        assert_eq!(Some(3), last_event_id);
+        assert!(wanted.wants("a", Some(&"web-1".to_string()), None));
+        assert!(!wanted.wants("a", Some(&"db".to_string()), None));
+
+        let events = Arc::new(Events::new(10));
+        let status = |url| status(handle(&request(url, &[]), &events, None));
+        assert_eq!(400, status("/events?tag=("));
+        assert_eq!(400, status("/events?status=Sad"));
+        assert_eq!(400, status("/events?last_event_id=x"));
+        assert_eq!(404, status("/stream"));
+    }
+
+    #[test]
+    fn it_authenticates_clients() {
+        let auth = Authenticator::new(&CanaryAuthConfig {
+            allowed_ips: vec!["127.0.0.1".to_string()],
+            enabled: true,
+            users: vec![CanaryAuthUser {
+                name: "wall".to_string(),
+                password: None,
+                scope: CanaryAuthScope::Read,
+                token: Some("wall-token".to_string()),
+            }],
+        })
+        .unwrap();
+        let events = Arc::new(Events::new(10));
+        let status = |request| status(handle(&request, &events, Some(&auth)));
+
+        let denied = handle(&request("/events", &[]), &events, Some(&auth))
+            .err()
+            .unwrap();
+        assert_eq!(401, denied.status_code);
+        assert!(denied
+            .headers
+            .iter()
+            .any(|(name, value)| name == "WWW-Authenticate" && value.starts_with("Basic")));
+
+        assert_eq!(200, status(request("/events?token=wall-token", &[])));
+        assert_eq!(
+            200,
+            status(request(
+                "/events",
+                &[("Authorization", "Bearer wall-token")]
+            ))
+        );
+
+        let elsewhere = Request::fake_http_from(
+            "192.0.2.1:4000".parse().unwrap(),
+            "GET",
+            "/events?token=wall-token",
+            vec![],
+            vec![],
+        );
+        assert_eq!(403, status(elsewhere));
+    }
+
+    #[test]
+    fn it_limits_clients() {
+        let events = Arc::new(Events::new(10));
+        let open = || handle(&request("/events", &[]), &events, None);
+
+        let streams = (0..MAX_CLIENTS).map(|_| open()).collect::<Vec<_>>();
+        assert!(streams.iter().all(|result| result.is_ok()));
+        assert_eq!(503, status(open()));
+
+        drop(streams);
+        assert_eq!(200, status(open()));
+    }
+
+    #[test]
+    fn it_streams_results() {
+        let events = Arc::new(Events::new(10));
+        events.publish(&check("foo", Status::Fire), None);
+        events.publish(&check("bar", Status::Fire), None);
+        start_sse_server("127.0.0.1:56481", events.clone(), None, None);
+
+        let mut stream = TcpStream::connect("127.0.0.1:56481").unwrap();
+        stream
+            .write_all(b"GET /events?name=foo HTTP/1.1\r\nLast-Event-ID: 0\r\n\r\n")
+            .unwrap();
+        let mut received = String::new();
+
+        read_until(&mut stream, &mut received, "id: 1\n");
+        assert!(received.starts_with("HTTP/1.1 200"));
+        assert!(received.contains("text/event-stream"));
+        assert!(received.contains(r#""name":"foo""#));
+        assert!(!received.contains("id: 2\n"));
+
+        // Results published while connected follow, skipping filtered out ones
+        events.publish(&check("bar", Status::Okay), Some(&Status::Fire));
+        events.publish(&check("foo", Status::Okay), Some(&Status::Fire));
+        read_until(&mut stream, &mut received, "id: 4\n");
+        assert!(!received.contains("id: 3\n"));
+        assert!(received.ends_with("}\n\n"));
+    }
+
+    #[test]
+    fn it_answers_other_requests_and_closes() {
+        start_sse_server("127.0.0.1:56484", Arc::new(Events::new(10)), None, None);
+
+        let mut stream = TcpStream::connect("127.0.0.1:56484").unwrap();
+        stream.write_all(b"GET /stream HTTP/1.1\r\n\r\n").unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+
+        assert!(received.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(received.contains("Content-Length: 0\r\n"));
+    }
+}
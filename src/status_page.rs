@@ -0,0 +1,781 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use rouille::Response;
+use serde::Serialize;
+use time::Timespec;
+
+use crate::alerter::alert::parse_check_time;
+use crate::alerter::template::format_duration;
+use crate::incidents::IncidentTracker;
+use crate::recent::RecentChecks;
+use crate::storage::Storage;
+use crate::tls::{self, Tls};
+use librcanary::{CanaryStatusPageConfig, CanaryTarget, Status};
+
+const DAY_S: i64 = 24 * 60 * 60;
+
+/// Incidents looked through for the page, ongoing and most recent first
+const INCIDENT_LIMIT: usize = 100;
+
+/// Worst first when sorted in reverse. Days are `degraded` below 100% uptime, `partial_outage`
+/// below 95% and `major_outage` below 50%.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    NoData,
+    Operational,
+    Degraded,
+    PartialOutage,
+    MajorOutage,
+}
+
+impl ComponentStatus {
+    fn label(self) -> &'static str {
+        match self {
+            ComponentStatus::NoData => "No data",
+            ComponentStatus::Operational => "Operational",
+            ComponentStatus::Degraded => "Degraded performance",
+            ComponentStatus::PartialOutage => "Partial outage",
+            ComponentStatus::MajorOutage => "Major outage",
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            ComponentStatus::NoData => "no-data",
+            ComponentStatus::Operational => "operational",
+            ComponentStatus::Degraded => "degraded",
+            ComponentStatus::PartialOutage => "partial-outage",
+            ComponentStatus::MajorOutage => "major-outage",
+        }
+    }
+
+    fn from_uptime(uptime_percent: Option<f64>) -> ComponentStatus {
+        match uptime_percent {
+            None => ComponentStatus::NoData,
+            Some(u) if u >= 100.0 => ComponentStatus::Operational,
+            Some(u) if u >= 95.0 => ComponentStatus::Degraded,
+            Some(u) if u >= 50.0 => ComponentStatus::PartialOutage,
+            Some(_) => ComponentStatus::MajorOutage,
+        }
+    }
+}
+
+/// Everything shown on the status page. Only public names are included, never targets'
+/// names, hosts or status reasons.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Page {
+    pub title: String,
+    pub updated_at: String,
+    /// Worst status of all components
+    pub status: ComponentStatus,
+    pub components: Vec<Component>,
+    /// Incidents which started in the last `days` days, newest first
+    pub incidents: Vec<PublicIncident>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Component {
+    pub name: String,
+    pub description: Option<String>,
+    pub status: ComponentStatus,
+    pub uptime_percent: Option<f64>,
+    /// Oldest first, today last
+    pub days: Vec<Day>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Day {
+    /// `YYYY-MM-DD`, in UTC
+    pub date: String,
+    pub status: ComponentStatus,
+    pub uptime_percent: Option<f64>,
+    #[serde(skip)]
+    checks: u64,
+    #[serde(skip)]
+    okay: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PublicIncident {
+    pub components: Vec<String>,
+    /// `major_outage` if any target was on fire, `degraded` otherwise
+    pub impact: ComponentStatus,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub duration_s: u64,
+    pub note: Option<String>,
+}
+
+// Where the page is built from, owned by the build thread
+struct Sources {
+    config: CanaryStatusPageConfig,
+    /// Names of the targets which exist, components leave out the others
+    targets: Arc<RwLock<HashSet<String>>>,
+    incidents: Arc<Mutex<IncidentTracker>>,
+    recent: Arc<Mutex<RecentChecks>>,
+    storage: Option<Arc<Storage>>,
+}
+
+// The page as served, so visitors never cause storage queries
+struct Rendered {
+    html: String,
+    json: String,
+}
+
+/// Builds the status page whenever something changes, and at midnight UTC for the new day.
+/// The page is kept for the server, and written to `output_dir` if set.
+pub struct StatusPage {
+    config: CanaryStatusPageConfig,
+    targets: Arc<RwLock<HashSet<String>>>,
+    rendered: Arc<RwLock<Option<Rendered>>>,
+    rebuilds: Mutex<mpsc::Sender<()>>,
+}
+
+impl StatusPage {
+    pub fn new(
+        config: &CanaryStatusPageConfig,
+        targets: &[CanaryTarget],
+        incidents: Arc<Mutex<IncidentTracker>>,
+        recent: Arc<Mutex<RecentChecks>>,
+        storage: Option<Arc<Storage>>,
+    ) -> Result<StatusPage, String> {
+        for component in &config.components {
+            for name in &component.targets {
+                if !targets.iter().any(|t| &t.name == name) {
+                    return Err(format!(
+                        "component `{}` has unknown target `{}`",
+                        component.name, name
+                    ));
+                }
+            }
+        }
+
+        let names = Arc::new(RwLock::new(target_names(targets)));
+        let sources = Sources {
+            config: config.clone(),
+            targets: names.clone(),
+            incidents,
+            recent,
+            storage,
+        };
+
+        let rendered = Arc::new(RwLock::new(None));
+        let (tx, rx) = mpsc::channel();
+        let built = rendered.clone();
+
+        thread::spawn(move || loop {
+            let now = time::get_time();
+            match sources.page(now) {
+                Ok(page) => {
+                    if let Some(ref dir) = sources.config.output_dir {
+                        match export(&page, dir) {
+                            Ok(()) => info!("[status_page.export] wrote status page to {}", dir),
+                            Err(err) => info!("[status_page.error] failed to export: {}", err),
+                        }
+                    }
+                    *built.write().unwrap() = Some(Rendered {
+                        html: render_html(&page),
+                        json: serde_json::to_string(&page).unwrap_or_default(),
+                    });
+                }
+                Err(err) => info!("[status_page.error] failed to build page: {}", err),
+            }
+
+            let midnight = DAY_S - now.sec.rem_euclid(DAY_S);
+            match rx.recv_timeout(Duration::from_secs(midnight as u64)) {
+                // One build covers every change made while the last one was built
+                Ok(()) => while rx.try_recv().is_ok() {},
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+
+        Ok(StatusPage {
+            config: config.clone(),
+            targets: names,
+            rendered,
+            rebuilds: Mutex::new(tx),
+        })
+    }
+
+    /// Builds the page again
+    pub fn changed(&self) {
+        let _ = self.rebuilds.lock().unwrap().send(());
+    }
+
+    /// Builds the page again for the new target list. Components leave out targets which
+    /// have been deleted, and pick them up again if they are added back.
+    pub fn targets_changed(&self, targets: &[CanaryTarget]) {
+        let names = target_names(targets);
+        let mut known = self.targets.write().unwrap();
+        for component in &self.config.components {
+            for name in &component.targets {
+                if known.contains(name) && !names.contains(name) {
+                    info!(
+                        "[status_page.error] component `{}` has unknown target `{}`, leaving it out",
+                        component.name, name
+                    );
+                }
+            }
+        }
+        *known = names;
+        drop(known);
+
+        self.changed();
+    }
+
+    // The latest built page, `None` until the first build finished
+    fn response(&self, json: bool) -> Option<Response> {
+        let rendered = self.rendered.read().unwrap();
+        let rendered = rendered.as_ref()?;
+
+        Some(if json {
+            Response::from_data("application/json", rendered.json.clone())
+        } else {
+            Response::html(rendered.html.clone())
+        })
+    }
+}
+
+impl Sources {
+    fn page(&self, now: Timespec) -> Result<Page, String> {
+        let latest = self
+            .recent
+            .lock()
+            .unwrap()
+            .latest()
+            .map(|c| (c.target.name.clone(), c.status.clone()))
+            .collect::<Vec<_>>();
+
+        let known = self.targets.read().unwrap().clone();
+        let mut components = Vec::new();
+        for component in &self.config.components {
+            let targets = component
+                .targets
+                .iter()
+                .filter(|name| known.contains(*name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let statuses = latest
+                .iter()
+                .filter(|(name, _)| targets.contains(name))
+                .map(|(_, status)| status)
+                .collect::<Vec<_>>();
+            let days = self.days(&targets, now)?;
+
+            components.push(Component {
+                name: component.name.clone(),
+                description: component.description.clone(),
+                status: current_status(&statuses),
+                uptime_percent: combined_uptime(&days),
+                days,
+            });
+        }
+
+        let status = components
+            .iter()
+            .map(|c| c.status)
+            .max()
+            .unwrap_or(ComponentStatus::NoData);
+
+        Ok(Page {
+            title: self.config.title.clone(),
+            updated_at: format_time(now),
+            status,
+            components,
+            incidents: self.incidents(now),
+        })
+    }
+
+    fn days(&self, targets: &[String], now: Timespec) -> Result<Vec<Day>, String> {
+        let today = now.sec - now.sec.rem_euclid(DAY_S);
+        let mut days = Vec::new();
+
+        for day in (0..self.config.days as i64).rev() {
+            let from = today - day * DAY_S;
+            let (mut checks, mut okay) = (0, 0);
+            if let Some(ref storage) = self.storage {
+                for target in targets {
+                    let (c, o) = storage.availability(target, from, from + DAY_S)?;
+                    checks += c;
+                    okay += o;
+                }
+            }
+
+            let uptime_percent = uptime(checks, okay);
+            days.push(Day {
+                checks,
+                okay,
+                date: time::at_utc(Timespec::new(from, 0))
+                    .strftime("%Y-%m-%d")
+                    .map(|d| d.to_string())
+                    .map_err(|e| e.to_string())?,
+                status: ComponentStatus::from_uptime(uptime_percent),
+                uptime_percent,
+            });
+        }
+
+        Ok(days)
+    }
+
+    fn incidents(&self, now: Timespec) -> Vec<PublicIncident> {
+        let since = now - time::Duration::days(self.config.days as i64);
+        let mut incidents: Vec<PublicIncident> = Vec::new();
+
+        for incident in self.incidents.lock().unwrap().list(None, INCIDENT_LIMIT) {
+            let components = self
+                .config
+                .components
+                .iter()
+                .filter(|c| c.targets.contains(&incident.target))
+                .map(|c| c.name.clone())
+                .collect::<Vec<_>>();
+            if components.is_empty() || parse_check_time(&incident.started_at) < since {
+                continue;
+            }
+
+            incidents.push(PublicIncident {
+                components,
+                impact: match incident.peak_status {
+                    Status::Fire => ComponentStatus::MajorOutage,
+                    _ => ComponentStatus::Degraded,
+                },
+                started_at: incident.started_at,
+                ended_at: incident.ended_at,
+                duration_s: incident.duration_s,
+                note: incident.public_note,
+            });
+        }
+
+        incidents.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+        incidents
+    }
+}
+
+fn target_names(targets: &[CanaryTarget]) -> HashSet<String> {
+    targets.iter().map(|t| t.name.clone()).collect()
+}
+
+fn current_status(statuses: &[&Status]) -> ComponentStatus {
+    let fire = statuses.iter().filter(|s| ***s == Status::Fire).count();
+
+    if statuses.is_empty() {
+        ComponentStatus::NoData
+    } else if fire == statuses.len() {
+        ComponentStatus::MajorOutage
+    } else if fire > 0 {
+        ComponentStatus::PartialOutage
+    } else if statuses.iter().any(|s| **s == Status::Unknown) {
+        ComponentStatus::Degraded
+    } else {
+        ComponentStatus::Operational
+    }
+}
+
+fn uptime(checks: u64, okay: u64) -> Option<f64> {
+    if checks > 0 {
+        Some(okay as f64 * 100.0 / checks as f64)
+    } else {
+        None
+    }
+}
+
+fn combined_uptime(days: &[Day]) -> Option<f64> {
+    uptime(
+        days.iter().map(|d| d.checks).sum(),
+        days.iter().map(|d| d.okay).sum(),
+    )
+}
+
+fn format_time(t: Timespec) -> String {
+    time::at_utc(t).rfc3339().to_string()
+}
+
+/// Escapes text for HTML and SVG documents, including attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn format_uptime(uptime_percent: Option<f64>) -> String {
+    match uptime_percent {
+        Some(uptime) => format!("{:.2}%", uptime),
+        None => "no data".to_string(),
+    }
+}
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 860px; margin: 2em auto; color: #222; }
+.banner { padding: 1em; border-radius: 4px; color: #fff; font-size: 1.2em; }
+.component { margin: 1.5em 0; }
+.component-header { display: flex; justify-content: space-between; }
+.description { color: #666; font-size: 0.9em; }
+.bars { display: flex; gap: 1px; height: 30px; margin: 0.5em 0; }
+.bar { flex: 1; border-radius: 1px; }
+.uptime { color: #666; font-size: 0.8em; text-align: right; }
+.incident { border-left: 4px solid; padding: 0.2em 1em; margin: 1em 0; }
+.incident time { color: #666; font-size: 0.9em; }
+.operational { background: #2fcc66; border-color: #2fcc66; }
+.degraded { background: #f1c40f; border-color: #f1c40f; }
+.partial-outage { background: #e67e22; border-color: #e67e22; }
+.major-outage { background: #e74c3c; border-color: #e74c3c; }
+.no-data { background: #ddd; border-color: #ddd; color: #222; }
+.incident.operational, .incident.degraded, .incident.partial-outage, .incident.major-outage { background: none; }
+";
+
+pub fn render_html(page: &Page) -> String {
+    let mut html = format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />\n\
+         <title>{title}</title>\n<style>\n{style}</style>\n</head>\n<body>\n\
+         <h1>{title}</h1>\n<div class=\"banner {class}\">{status}</div>\n",
+        title = escape(&page.title),
+        style = STYLE,
+        class = page.status.class(),
+        status = match page.status {
+            ComponentStatus::Operational => "All systems operational",
+            status => status.label(),
+        },
+    );
+
+    for component in &page.components {
+        html.push_str(&format!(
+            "<div class=\"component\">\n<div class=\"component-header\"><strong>{}</strong>\
+             <span>{}</span></div>\n",
+            escape(&component.name),
+            component.status.label()
+        ));
+        if let Some(ref description) = component.description {
+            html.push_str(&format!(
+                "<div class=\"description\">{}</div>\n",
+                escape(description)
+            ));
+        }
+
+        html.push_str("<div class=\"bars\">");
+        for day in &component.days {
+            html.push_str(&format!(
+                "<div class=\"bar {}\" title=\"{}: {}\"></div>",
+                day.status.class(),
+                day.date,
+                format_uptime(day.uptime_percent)
+            ));
+        }
+        html.push_str(&format!(
+            "</div>\n<div class=\"uptime\">{} days, {} uptime</div>\n</div>\n",
+            component.days.len(),
+            format_uptime(component.uptime_percent)
+        ));
+    }
+
+    html.push_str("<h2>Incidents</h2>\n");
+    if page.incidents.is_empty() {
+        html.push_str("<p>No incidents reported.</p>\n");
+    }
+    for incident in &page.incidents {
+        let ended = match incident.ended_at {
+            Some(ref ended_at) => format!(
+                "resolved {}, after {}",
+                escape(ended_at),
+                format_duration(incident.duration_s)
+            ),
+            None => "ongoing".to_string(),
+        };
+
+        html.push_str(&format!(
+            "<div class=\"incident {}\">\n<strong>{}: {}</strong><br />\n\
+             <time>{} &middot; {}</time>\n",
+            incident.impact.class(),
+            escape(&incident.components.join(", ")),
+            incident.impact.label(),
+            escape(&incident.started_at),
+            ended
+        ));
+        if let Some(ref note) = incident.note {
+            html.push_str(&format!("<p>{}</p>\n", escape(note)));
+        }
+        html.push_str("</div>\n");
+    }
+
+    html.push_str(&format!(
+        "<footer><small>Updated {}</small></footer>\n</body>\n</html>\n",
+        escape(&page.updated_at)
+    ));
+    html
+}
+
+/// Writes `index.html` and `status.json`, replacing the old files only once the new ones are
+/// complete so web servers never serve half-written pages
+pub fn export(page: &Page, dir: &str) -> Result<(), String> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+
+    let json = serde_json::to_string_pretty(page).map_err(|e| e.to_string())?;
+    for (name, contents) in &[("index.html", render_html(page)), ("status.json", json)] {
+        let path = dir.join(name);
+        let partial = dir.join(format!(".{}.tmp", name));
+
+        fs::write(&partial, contents)
+            .map_err(|e| format!("failed to write {:?}: {}", partial, e))?;
+        fs::rename(&partial, &path).map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Serves the page to anyone, it only has public names in it
+pub fn start_status_page_server(
+    bind_to: &str,
+    status_page: Arc<StatusPage>,
+    tls: Option<Arc<Tls>>,
+) {
+    let addr: SocketAddr = bind_to.parse().unwrap_or_else(|err| {
+        panic!(
+            "[status.startup] failed to start status page server: {}",
+            err
+        );
+    });
+
+    info!(
+        "[status.startup] starting status page server at {}...",
+        &addr
+    );
+
+    thread::spawn(move || {
+        tls::serve(addr, tls, move |request| {
+            let response = match (request.method(), request.url().as_str()) {
+                ("GET", "/") | ("GET", "/index.html") => status_page.response(false),
+                ("GET", "/status.json") => status_page.response(true),
+                _ => return Response::empty_404(),
+            };
+
+            response.unwrap_or_else(|| Response::text("Status unavailable").with_status_code(503))
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::target;
+    use librcanary::{CanaryCheck, CanaryStatusPageComponent, CanaryStorageConfig};
+    use std::env;
+
+    fn check(name: &str, status: Status, time: &str) -> CanaryCheck {
+        CanaryCheck {
+            status_reason: "internal detail".to_string(),
+            time: time.to_string(),
+            ..crate::tests::check(name, status)
+        }
+    }
+
+    fn targets() -> Vec<CanaryTarget> {
+        ["web-1", "web-2", "db"]
+            .iter()
+            .map(|name| {
+                let mut target = target();
+                target.name = name.to_string();
+                target
+            })
+            .collect()
+    }
+
+    fn config() -> CanaryStatusPageConfig {
+        CanaryStatusPageConfig {
+            components: vec![
+                CanaryStatusPageComponent {
+                    description: Some("Our <b>website</b>".to_string()),
+                    name: "Website".to_string(),
+                    targets: vec!["web-1".to_string(), "web-2".to_string()],
+                },
+                CanaryStatusPageComponent {
+                    description: None,
+                    name: "Database".to_string(),
+                    targets: vec!["db".to_string()],
+                },
+            ],
+            days: 3,
+            enabled: true,
+            title: "Example status".to_string(),
+            ..CanaryStatusPageConfig::default()
+        }
+    }
+
+    fn sources(config: &CanaryStatusPageConfig) -> Sources {
+        let storage = Arc::new(
+            Storage::open(&CanaryStorageConfig {
+                enabled: true,
+                path: ":memory:".to_string(),
+                ..CanaryStorageConfig::default()
+            })
+            .unwrap(),
+        );
+        let incidents = Arc::new(Mutex::new(IncidentTracker::new(None).unwrap()));
+        let recent = Arc::new(Mutex::new(RecentChecks::new()));
+
+        let checks = vec![
+            check("web-1", Status::Okay, "2019-01-02T10:00:00Z"),
+            check("web-1", Status::Fire, "2019-01-03T10:00:00Z"),
+            check("web-2", Status::Okay, "2019-01-03T10:00:00Z"),
+            check("db", Status::Okay, "2019-01-03T10:00:00Z"),
+        ];
+        for check in checks {
+            storage.record(&check, None).unwrap();
+            recent.lock().unwrap().push(check.clone());
+            incidents.lock().unwrap().update(&check);
+        }
+        incidents
+            .lock()
+            .unwrap()
+            .set_public_note(0, Some("We are looking into it".to_string()))
+            .unwrap();
+
+        Sources {
+            config: config.clone(),
+            targets: Arc::new(RwLock::new(target_names(&targets()))),
+            incidents,
+            recent,
+            storage: Some(storage),
+        }
+    }
+
+    fn now() -> Timespec {
+        parse_check_time("2019-01-03T12:00:00Z")
+    }
+
+    #[test]
+    fn it_builds_the_page_from_components() {
+        let page = sources(&config()).page(now()).unwrap();
+
+        assert_eq!(ComponentStatus::PartialOutage, page.status);
+        let website = &page.components[0];
+        assert_eq!(ComponentStatus::PartialOutage, website.status);
+        assert_eq!(
+            vec!["2019-01-01", "2019-01-02", "2019-01-03"],
+            website
+                .days
+                .iter()
+                .map(|d| d.date.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![None, Some(100.0), Some(50.0)],
+            website
+                .days
+                .iter()
+                .map(|d| d.uptime_percent)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(ComponentStatus::PartialOutage, website.days[2].status);
+        // Weighted by checks, 2 of the 3 were okay
+        assert_eq!(Some(200.0 / 3.0), website.uptime_percent);
+        assert_eq!(ComponentStatus::Operational, page.components[1].status);
+
+        assert_eq!(1, page.incidents.len());
+        assert_eq!(vec!["Website"], page.incidents[0].components);
+        assert_eq!(ComponentStatus::MajorOutage, page.incidents[0].impact);
+        assert_eq!(
+            Some("We are looking into it".to_string()),
+            page.incidents[0].note
+        );
+    }
+
+    #[test]
+    fn it_keeps_internal_details_off_the_page() {
+        let page = sources(&config()).page(now()).unwrap();
+
+        let html = render_html(&page);
+        let json = serde_json::to_string(&page).unwrap();
+
+        for rendered in &[&html, &json] {
+            assert!(!rendered.contains("web-1"));
+            assert!(!rendered.contains("invalid"));
+            assert!(!rendered.contains("internal detail"));
+        }
+        assert!(html.contains("Our &lt;b&gt;website&lt;/b&gt;"));
+        assert!(html.contains("We are looking into it"));
+    }
+
+    #[test]
+    fn it_exports_static_files() {
+        let dir = env::temp_dir().join("rcanary-status-page");
+        let _ = fs::remove_dir_all(&dir);
+        export(
+            &sources(&config()).page(now()).unwrap(),
+            dir.to_str().unwrap(),
+        )
+        .unwrap();
+
+        assert!(fs::read_to_string(dir.join("index.html"))
+            .unwrap()
+            .contains("Example status"));
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("status.json")).unwrap()).unwrap();
+        assert_eq!("partial_outage", json["status"]);
+        assert!(!dir.join(".index.html.tmp").exists());
+    }
+
+    #[test]
+    fn it_serves_the_page_built_in_the_background() {
+        let sources = sources(&config());
+        let status_page = StatusPage::new(
+            &sources.config,
+            &targets(),
+            sources.incidents,
+            sources.recent,
+            sources.storage,
+        )
+        .unwrap();
+
+        let mut waited = 0;
+        while status_page.response(false).is_none() {
+            assert!(waited < 100, "page was never built");
+            thread::sleep(Duration::from_millis(10));
+            waited += 1;
+        }
+
+        let mut html = String::new();
+        let (mut reader, _) = status_page
+            .response(false)
+            .unwrap()
+            .data
+            .into_reader_and_size();
+        std::io::Read::read_to_string(&mut reader, &mut html).unwrap();
+        assert!(html.contains("Example status"));
+        assert!(status_page.response(true).is_some());
+    }
+
+    #[test]
+    fn it_rejects_unknown_targets() {
+        let mut config = config();
+        config.components[1].targets.push("cache".to_string());
+        let incidents = Arc::new(Mutex::new(IncidentTracker::new(None).unwrap()));
+        let recent = Arc::new(Mutex::new(RecentChecks::new()));
+
+        assert!(StatusPage::new(&config, &targets(), incidents, recent, None).is_err());
+    }
+
+    #[test]
+    fn it_leaves_out_deleted_targets() {
+        let sources = sources(&config());
+        let page = sources.page(now()).unwrap();
+        assert_eq!(ComponentStatus::Operational, page.components[1].status);
+
+        sources.targets.write().unwrap().remove("db");
+        let page = sources.page(now()).unwrap();
+        assert_eq!(ComponentStatus::NoData, page.components[1].status);
+        assert!(page.components[1].days.iter().all(|day| day.checks == 0));
+        assert_eq!(ComponentStatus::PartialOutage, page.components[0].status);
+    }
+}
@@ -0,0 +1,668 @@
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, ToSql, NO_PARAMS};
+use time::Timespec;
+
+use crate::alerter::alert::parse_check_time;
+use librcanary::{
+    CanaryAcknowledgement, CanaryCheck, CanaryIncident, CanarySilence, CanaryStorageConfig, Status,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS checks (
+    id INTEGER PRIMARY KEY,
+    target TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    result TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS checks_target_time ON checks (target, time);
+
+CREATE TABLE IF NOT EXISTS check_rollups (
+    target TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    checks INTEGER NOT NULL,
+    okay INTEGER NOT NULL,
+    latency_ms_sum INTEGER NOT NULL,
+    latency_ms_max INTEGER NOT NULL,
+    PRIMARY KEY (target, time)
+);
+
+CREATE TABLE IF NOT EXISTS transitions (
+    id INTEGER PRIMARY KEY,
+    target TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    from_status TEXT,
+    to_status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transitions_target_time ON transitions (target, time);
+
+CREATE TABLE IF NOT EXISTS incidents (
+    id INTEGER PRIMARY KEY,
+    target TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    ended_at INTEGER,
+    incident TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS acknowledgements (
+    target TEXT PRIMARY KEY,
+    acknowledgement TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS silences (
+    id INTEGER PRIMARY KEY,
+    ends_at INTEGER NOT NULL,
+    silence TEXT NOT NULL
+);
+";
+
+/// Persists checks, status transitions, incidents, acknowledgements and silences to SQLite
+/// so they survive restarts.
+///
+/// Raw checks are kept for `downsample_after_days`, then rolled up into buckets of
+/// `downsample_interval_s`. Rollups, transitions and closed incidents are kept for
+/// `retention_days`, silences until they end.
+pub struct Storage {
+    config: CanaryStorageConfig,
+    connection: Mutex<Connection>,
+}
+
+/// A change in a target's status
+#[derive(Debug, PartialEq)]
+pub struct Transition {
+    pub time: i64,
+    pub from: Option<Status>,
+    pub to: Status,
+}
+
+/// What a compaction did, for logging
+#[derive(Debug, PartialEq)]
+pub struct Compaction {
+    pub downsampled: usize,
+    pub expired: usize,
+}
+
+impl Storage {
+    pub fn open(config: &CanaryStorageConfig) -> Result<Storage, String> {
+        if config.downsample_interval_s == 0 {
+            return Err("`downsample_interval_s` must be greater than 0".to_string());
+        }
+
+        let connection = Connection::open(&config.path)
+            .map_err(|e| format!("failed to open {}: {}", config.path, e))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| format!("failed to create tables: {}", e))?;
+
+        Ok(Storage {
+            config: config.clone(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Saves a check, and a transition if the target's status changed
+    pub fn record(&self, result: &CanaryCheck, previous: Option<&Status>) -> Result<(), String> {
+        let time = parse_check_time(&result.time).sec;
+        let target = &result.target.name;
+        let status = status_name(&result.status);
+        // Credentials are redacted on serialisation, which could not be read back
+        let mut result = result.clone();
+        result.target.basic_auth = None;
+        let json = serde_json::to_string(&result).map_err(|e| e.to_string())?;
+
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+
+        transaction
+            .execute(
+                "INSERT INTO checks (target, time, status, latency_ms, result) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![target, time, status, result.latency_ms as i64, json],
+            )
+            .map_err(|e| e.to_string())?;
+
+        if previous != Some(&result.status) {
+            transaction
+                .execute(
+                    "INSERT INTO transitions (target, time, from_status, to_status) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![target, time, previous.map(status_name), status],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// When a failing target last stopped being okay, used to restore outages on start-up.
+    /// None if that transition is past retention.
+    pub fn failing_since(&self, target: &str) -> Result<Option<Timespec>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .query_row(
+                "SELECT MAX(time) FROM transitions \
+                 WHERE target = ?1 AND to_status != 'Okay' \
+                 AND (from_status IS NULL OR from_status = 'Okay')",
+                params![target],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .map(|time| time.map(|sec| Timespec::new(sec, 0)))
+            .map_err(|e| e.to_string())
+    }
+
+    /// The latest check of every target, used to restore state on start-up
+    pub fn last_checks(&self) -> Result<Vec<CanaryCheck>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare(
+                "SELECT result FROM checks \
+                 WHERE id IN (SELECT MAX(id) FROM checks GROUP BY target) \
+                 ORDER BY target",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+
+        let mut checks = Vec::new();
+        for row in rows {
+            let json = row.map_err(|e| e.to_string())?;
+            checks.push(serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        }
+
+        Ok(checks)
+    }
+
+    /// A target's raw checks between `from` and `to`, newest first
+    pub fn checks(
+        &self,
+        target: &str,
+        from: i64,
+        to: i64,
+        status: Option<&Status>,
+        limit: usize,
+    ) -> Result<Vec<CanaryCheck>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare(
+                "SELECT result FROM checks \
+                 WHERE target = ?1 AND time >= ?2 AND time < ?3 AND (?4 IS NULL OR status = ?4) \
+                 ORDER BY time DESC, id DESC LIMIT ?5",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map(
+                params![target, from, to, status.map(status_name), limit as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(|e| e.to_string())?;
+
+        let mut checks = Vec::new();
+        for row in rows {
+            let json = row.map_err(|e| e.to_string())?;
+            checks.push(serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        }
+
+        Ok(checks)
+    }
+
+    /// Number of checks, and of okay checks, of a target between `from` and `to`
+    pub fn availability(&self, target: &str, from: i64, to: i64) -> Result<(u64, u64), String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .query_row(
+                "SELECT COALESCE(SUM(checks), 0), COALESCE(SUM(okay), 0) FROM ( \
+                 SELECT COUNT(*) AS checks, SUM(status = 'Okay') AS okay FROM checks \
+                 WHERE target = ?1 AND time >= ?2 AND time < ?3 \
+                 UNION ALL \
+                 SELECT SUM(checks), SUM(okay) FROM check_rollups \
+                 WHERE target = ?1 AND time >= ?2 AND time < ?3)",
+                params![target, from, to],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    /// Latencies of a target's raw checks between `from` and `to`, downsampled checks are not included
+    pub fn latencies(&self, target: &str, from: i64, to: i64) -> Result<Vec<u64>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare(
+                "SELECT latency_ms FROM checks \
+                 WHERE target = ?1 AND time >= ?2 AND time < ?3 ORDER BY latency_ms",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map(params![target, from, to], |row| row.get::<_, i64>(0))
+            .map_err(|e| e.to_string())?;
+
+        rows.map(|row| row.map(|l| l as u64).map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    /// The status a target had at `time`, if it had been checked by then
+    pub fn status_at(&self, target: &str, time: i64) -> Result<Option<Status>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare(
+                "SELECT to_status FROM transitions \
+                 WHERE target = ?1 AND time < ?2 ORDER BY time DESC, id DESC LIMIT 1",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let mut rows = statement
+            .query_map(params![target, time], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+
+        match rows.next() {
+            Some(status) => Ok(Some(parse_status(&status.map_err(|e| e.to_string())?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A target's transitions between `from` and `to`, oldest first
+    pub fn transitions(&self, target: &str, from: i64, to: i64) -> Result<Vec<Transition>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection
+            .prepare(
+                "SELECT time, from_status, to_status FROM transitions \
+                 WHERE target = ?1 AND time >= ?2 AND time < ?3 ORDER BY time, id",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map(params![target, from, to], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(|e| e.to_string())?;
+
+        let mut transitions = Vec::new();
+        for row in rows {
+            let (time, from, to) = row.map_err(|e| e.to_string())?;
+            transitions.push(Transition {
+                time,
+                from: from.as_ref().map(|s| parse_status(s)).transpose()?,
+                to: parse_status(&to)?,
+            });
+        }
+
+        Ok(transitions)
+    }
+
+    /// Saves a new incident, or replaces an updated one
+    pub fn save_incident(&self, incident: &CanaryIncident) -> Result<(), String> {
+        let time = parse_check_time(&incident.started_at).sec;
+        let ended_at = incident.ended_at.as_ref().map(|t| parse_check_time(t).sec);
+        let json = serde_json::to_string(incident).map_err(|e| e.to_string())?;
+
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO incidents (id, target, time, ended_at, incident) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![incident.id as i64, incident.target, time, ended_at, json],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn incident(&self, id: u64) -> Result<Option<CanaryIncident>, String> {
+        self.query_json(
+            "SELECT incident FROM incidents WHERE id = ?1",
+            params![id as i64],
+        )
+        .map(|mut incidents| incidents.pop())
+    }
+
+    /// Incidents which have not ended, however long ago they started
+    pub fn open_incidents(&self) -> Result<Vec<CanaryIncident>, String> {
+        self.query_json(
+            "SELECT incident FROM incidents WHERE ended_at IS NULL ORDER BY id",
+            NO_PARAMS,
+        )
+    }
+
+    /// The latest closed incidents, newest first
+    pub fn recent_incidents(&self, limit: usize) -> Result<Vec<CanaryIncident>, String> {
+        self.query_json(
+            "SELECT incident FROM incidents WHERE ended_at IS NOT NULL ORDER BY id DESC LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    // Runs a query whose first column is a JSON document
+    fn query_json<T>(&self, sql: &str, params: &[&dyn ToSql]) -> Result<Vec<T>, String>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let mut statement = connection.prepare(sql).map_err(|e| e.to_string())?;
+        let rows = statement
+            .query_map(params, |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+
+        let mut values = Vec::new();
+        for row in rows {
+            let json = row.map_err(|e| e.to_string())?;
+            values.push(serde_json::from_str(&json).map_err(|e| e.to_string())?);
+        }
+
+        Ok(values)
+    }
+
+    /// Saves an acknowledgement, replacing any earlier one for the target
+    pub fn save_acknowledgement(
+        &self,
+        acknowledgement: &CanaryAcknowledgement,
+    ) -> Result<(), String> {
+        let json = serde_json::to_string(acknowledgement).map_err(|e| e.to_string())?;
+
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO acknowledgements (target, acknowledgement) VALUES (?1, ?2)",
+                params![acknowledgement.target, json],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn remove_acknowledgement(&self, target: &str) -> Result<(), String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute(
+                "DELETE FROM acknowledgements WHERE target = ?1",
+                params![target],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn acknowledgements(&self) -> Result<Vec<CanaryAcknowledgement>, String> {
+        self.query_json("SELECT acknowledgement FROM acknowledgements", NO_PARAMS)
+    }
+
+    pub fn save_silence(&self, silence: &CanarySilence) -> Result<(), String> {
+        let ends_at = parse_check_time(&silence.ends_at).sec;
+        let json = serde_json::to_string(silence).map_err(|e| e.to_string())?;
+
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO silences (id, ends_at, silence) VALUES (?1, ?2, ?3)",
+                params![silence.id as i64, ends_at, json],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn remove_silence(&self, id: u64) -> Result<(), String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .execute("DELETE FROM silences WHERE id = ?1", params![id as i64])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Highest silence id saved, including silences which have ended but not been compacted
+    pub fn last_silence_id(&self) -> Result<Option<u64>, String> {
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+        connection
+            .query_row("SELECT MAX(id) FROM silences", NO_PARAMS, |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+            .map(|id| id.map(|id| id as u64))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Silences which have not ended by `now`
+    pub fn silences(&self, now: Timespec) -> Result<Vec<CanarySilence>, String> {
+        self.query_json(
+            "SELECT silence FROM silences WHERE ends_at > ?1 ORDER BY id",
+            params![now.sec],
+        )
+    }
+
+    /// Rolls up old raw checks and deletes anything past retention
+    pub fn compact(&self, now: Timespec) -> Result<Compaction, String> {
+        let day = 24 * 60 * 60;
+        let interval = self.config.downsample_interval_s as i64;
+        let retain_after = now.sec - self.config.retention_days as i64 * day;
+        // Only whole buckets are rolled up, so a bucket is never split across runs
+        let downsample_before = now.sec - self.config.downsample_after_days as i64 * day;
+        let downsample_before = downsample_before - downsample_before.rem_euclid(interval);
+
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+
+        transaction
+            .execute(
+                "INSERT INTO check_rollups (target, time, checks, okay, latency_ms_sum, latency_ms_max) \
+                 SELECT target, time - time % ?1, COUNT(*), SUM(status = 'Okay'), SUM(latency_ms), MAX(latency_ms) \
+                 FROM checks WHERE time < ?2 GROUP BY target, time - time % ?1 \
+                 ON CONFLICT (target, time) DO UPDATE SET \
+                 checks = checks + excluded.checks, \
+                 okay = okay + excluded.okay, \
+                 latency_ms_sum = latency_ms_sum + excluded.latency_ms_sum, \
+                 latency_ms_max = MAX(latency_ms_max, excluded.latency_ms_max)",
+                params![interval, downsample_before],
+            )
+            .map_err(|e| e.to_string())?;
+        let downsampled = transaction
+            .execute(
+                "DELETE FROM checks WHERE time < ?1",
+                params![downsample_before],
+            )
+            .map_err(|e| e.to_string())?;
+
+        let mut expired = 0;
+        for table in &["checks", "check_rollups", "transitions"] {
+            expired += transaction
+                .execute(
+                    &format!("DELETE FROM {} WHERE time < ?1", table),
+                    params![retain_after],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        // Ongoing incidents are kept however long they last
+        expired += transaction
+            .execute(
+                "DELETE FROM incidents WHERE time < ?1 AND ended_at IS NOT NULL",
+                params![retain_after],
+            )
+            .map_err(|e| e.to_string())?;
+
+        expired += transaction
+            .execute("DELETE FROM silences WHERE ends_at <= ?1", params![now.sec])
+            .map_err(|e| e.to_string())?;
+
+        transaction.commit().map_err(|e| e.to_string())?;
+
+        Ok(Compaction {
+            downsampled,
+            expired,
+        })
+    }
+}
+
+fn status_name(status: &Status) -> &'static str {
+    match status {
+        Status::Okay => "Okay",
+        Status::Fire => "Fire",
+        Status::Unknown => "Unknown",
+    }
+}
+
+pub fn parse_status(status: &str) -> Result<Status, String> {
+    match status {
+        "Okay" => Ok(Status::Okay),
+        "Fire" => Ok(Status::Fire),
+        "Unknown" => Ok(Status::Unknown),
+        other => Err(format!("unknown status `{}`", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn storage() -> Storage {
+        Storage::open(&CanaryStorageConfig {
+            enabled: true,
+            path: ":memory:".to_string(),
+            ..CanaryStorageConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn check(name: &str, status: Status, seconds: i64, latency_ms: u64) -> CanaryCheck {
+        CanaryCheck {
+            latency_ms,
+            time: format!("{}", time::at_utc(Timespec::new(seconds, 0)).rfc3339()),
+            ..crate::tests::check(name, status)
+        }
+    }
+
+    fn count(storage: &Storage, sql: &str) -> i64 {
+        let connection = storage.connection.lock().unwrap();
+        connection
+            .query_row(sql, NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn it_restores_the_last_check_of_each_target() {
+        let storage = storage();
+        storage
+            .record(&check("foo", Status::Okay, 0, 10), None)
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Fire, 60, 10), Some(&Status::Okay))
+            .unwrap();
+        storage
+            .record(&check("bar", Status::Okay, 30, 10), None)
+            .unwrap();
+
+        let actual = storage.last_checks().unwrap();
+
+        assert_eq!(2, actual.len());
+        assert_eq!("bar", actual[0].target.name);
+        assert_eq!("foo", actual[1].target.name);
+        assert_eq!(Status::Fire, actual[1].status);
+    }
+
+    #[test]
+    fn it_restores_checks_of_targets_with_credentials() {
+        let storage = storage();
+        let mut secret = check("foo", Status::Fire, 60, 10);
+        secret.target.basic_auth = Some(librcanary::Auth {
+            username: "user".to_string(),
+            password: Some("hunter2".to_string()),
+        });
+        storage.record(&secret, None).unwrap();
+
+        let actual = storage.last_checks().unwrap();
+        assert_eq!(1, actual.len());
+        assert_eq!(None, actual[0].target.basic_auth);
+        assert_eq!(Status::Fire, actual[0].status);
+    }
+
+    #[test]
+    fn it_records_transitions() {
+        let storage = storage();
+        storage
+            .record(&check("foo", Status::Okay, 0, 10), None)
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Okay, 60, 10), Some(&Status::Okay))
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Fire, 120, 10), Some(&Status::Okay))
+            .unwrap();
+
+        assert_eq!(3, count(&storage, "SELECT COUNT(*) FROM checks"));
+        assert_eq!(2, count(&storage, "SELECT COUNT(*) FROM transitions"));
+        assert_eq!(
+            1,
+            count(
+                &storage,
+                "SELECT COUNT(*) FROM transitions WHERE from_status = 'Okay' AND to_status = 'Fire'"
+            )
+        );
+    }
+
+    #[test]
+    fn it_finds_when_a_target_started_failing() {
+        let storage = storage();
+        storage
+            .record(&check("foo", Status::Okay, 0, 10), None)
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Fire, 60, 10), Some(&Status::Okay))
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Unknown, 120, 10), Some(&Status::Fire))
+            .unwrap();
+
+        assert_eq!(
+            Some(Timespec::new(60, 0)),
+            storage.failing_since("foo").unwrap()
+        );
+        assert_eq!(None, storage.failing_since("bar").unwrap());
+    }
+
+    #[test]
+    fn it_downsamples_and_expires_old_checks() {
+        let storage = storage();
+        storage
+            .record(&check("foo", Status::Okay, 0, 10), None)
+            .unwrap();
+        storage
+            .record(&check("foo", Status::Fire, 60, 30), Some(&Status::Okay))
+            .unwrap();
+        storage
+            .record(
+                &check("foo", Status::Okay, 8 * DAY, 10),
+                Some(&Status::Fire),
+            )
+            .unwrap();
+
+        let actual = storage.compact(Timespec::new(8 * DAY, 0)).unwrap();
+
+        assert_eq!(
+            Compaction {
+                downsampled: 2,
+                expired: 0
+            },
+            actual
+        );
+        assert_eq!(1, count(&storage, "SELECT COUNT(*) FROM checks"));
+        assert_eq!(
+            1,
+            count(
+                &storage,
+                "SELECT checks - okay FROM check_rollups WHERE time = 0"
+            )
+        );
+        assert_eq!(
+            30,
+            count(&storage, "SELECT latency_ms_max FROM check_rollups")
+        );
+
+        let actual = storage.compact(Timespec::new(91 * DAY, 0)).unwrap();
+        assert_eq!(1, actual.downsampled);
+        assert_eq!(3, actual.expired);
+        assert_eq!(8 * DAY, count(&storage, "SELECT time FROM check_rollups"));
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::dependencies::DependencyGraph;
+use librcanary::{CanaryCheck, CanaryTarget, CanaryTargetTypes};
+
+/// How long to wait for checks triggered through `check_now`
+const CHECK_NOW_TIMEOUT: Duration = Duration::from_secs(15);
+/// Triggered checks waited for at once, each holds a thread of the API or websocket server
+const MAX_CHECKS_WAITING: usize = 8;
+
+/// Asks a poller to check its target now, the check is sent back once it has been handled
+pub type Trigger = Sender<CanaryCheck>;
+
+/// Starts checking a target in the background, and right away whenever it receives a
+/// trigger, until the sender of `triggers` is dropped
+pub type Poller = Box<dyn Fn(CanaryTarget, Receiver<Trigger>) + Send + Sync>;
+
+/// Told about the new target list after every change
+pub type Listener = Box<dyn Fn(&CanaryTargetTypes) + Send + Sync>;
+
+/// Why a change to the targets was refused
+#[derive(Debug, PartialEq)]
+pub enum TargetError {
+    NotFound(String),
+    /// The name is taken, or other targets depend on the target
+    Conflict(String),
+    Invalid(String),
+    /// The change could not be saved to the targets file
+    Persist(String),
+    /// A triggered check did not finish in time
+    Timeout(String),
+    /// Too many triggered checks are being waited for already
+    Busy(String),
+}
+
+impl TargetError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            TargetError::NotFound(_) => 404,
+            TargetError::Conflict(_) => 409,
+            TargetError::Invalid(_) => 400,
+            TargetError::Persist(_) => 500,
+            TargetError::Timeout(_) => 504,
+            TargetError::Busy(_) => 429,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            TargetError::NotFound(message)
+            | TargetError::Conflict(message)
+            | TargetError::Invalid(message)
+            | TargetError::Persist(message)
+            | TargetError::Timeout(message)
+            | TargetError::Busy(message) => message,
+        }
+    }
+}
+
+struct Entry {
+    target: CanaryTarget,
+    /// Dropped to stop checking the target, `None` while it is paused
+    triggers: Option<Sender<Trigger>>,
+}
+
+struct Inner {
+    entries: Vec<Entry>,
+    graph: DependencyGraph,
+}
+
+/// The targets being checked, which can be changed while rcanary runs
+pub struct Targets {
+    inner: Mutex<Inner>,
+    poller: Poller,
+    listener: Listener,
+    /// Where changes are saved, if anywhere
+    file: Option<String>,
+    /// Triggered checks being waited for
+    waiting: Arc<AtomicUsize>,
+}
+
+/// Checks triggered by `check_now`, counted against `MAX_CHECKS_WAITING` until dropped
+#[derive(Debug)]
+pub struct Triggered {
+    checks: Vec<Receiver<CanaryCheck>>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl Triggered {
+    /// Waits for the checks, giving up after `CHECK_NOW_TIMEOUT`
+    pub fn wait(self) -> Result<Vec<CanaryCheck>, TargetError> {
+        let deadline = Instant::now() + CHECK_NOW_TIMEOUT;
+
+        self.checks
+            .iter()
+            .map(|checked| {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                checked.recv_timeout(timeout).map_err(|err| match err {
+                    mpsc::RecvTimeoutError::Timeout => {
+                        TargetError::Timeout("the check did not finish in time".to_string())
+                    }
+                    // The target was paused, changed or deleted before its check was handled
+                    mpsc::RecvTimeoutError::Disconnected => {
+                        TargetError::Conflict("the target changed while being checked".to_string())
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for Triggered {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Checks targets the same way as the config loader, and that names are unique
+pub fn validate(targets: &[CanaryTarget]) -> Result<DependencyGraph, String> {
+    let mut names = HashSet::new();
+    for target in targets {
+        if target.name.is_empty() {
+            return Err("targets need a name".to_string());
+        }
+        // Names are part of API paths, such as `/api/targets/{name}`
+        if target.name.contains(|c: char| c == '/' || c.is_control()) {
+            return Err(format!(
+                "target name {:?} cannot contain `/` or control characters",
+                target.name
+            ));
+        }
+        if !names.insert(&target.name) {
+            return Err(format!("there is already a target named `{}`", target.name));
+        }
+        if target.interval_s == 0 {
+            return Err(format!(
+                "target `{}` needs an interval_s above 0",
+                target.name
+            ));
+        }
+    }
+
+    DependencyGraph::new(targets)
+}
+
+/// Reads targets saved by the API, `None` if nothing has been saved yet
+pub fn load(path: &str) -> Result<Option<Vec<CanaryTarget>>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("failed to read {}: {}", path, err)),
+    };
+
+    let targets: CanaryTargetTypes =
+        toml::from_str(&contents).map_err(|err| format!("invalid {}: {}", path, err))?;
+    Ok(Some(targets.http))
+}
+
+// Targets serialize with their credentials redacted, so put them back before saving
+fn save(path: &str, targets: &[CanaryTarget]) -> Result<(), String> {
+    let mut http = Vec::new();
+    for target in targets {
+        let mut value = toml::Value::try_from(target).map_err(|e| e.to_string())?;
+        if let (Some(auth), Some(table)) = (&target.basic_auth, value.as_table_mut()) {
+            let mut credentials = toml::value::Table::new();
+            credentials.insert("username".to_string(), auth.username.clone().into());
+            if let Some(ref password) = auth.password {
+                credentials.insert("password".to_string(), password.clone().into());
+            }
+            table.insert("basic_auth".to_string(), credentials.into());
+        }
+        http.push(value);
+    }
+
+    let mut file = toml::value::Table::new();
+    file.insert("http".to_string(), http.into());
+    let contents = toml::to_string(&file).map_err(|e| e.to_string())?;
+
+    // Only readable by rcanary, as the file holds credentials
+    let partial = format!("{}.tmp", path);
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)
+        .and_then(|mut file| {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(contents.as_bytes())
+        })
+        .map_err(|e| format!("failed to write {}: {}", partial, e))?;
+    fs::rename(&partial, path).map_err(|e| format!("failed to write {}: {}", path, e))
+}
+
+impl Targets {
+    pub fn new(
+        targets: Vec<CanaryTarget>,
+        file: Option<String>,
+        poller: Poller,
+        listener: Listener,
+    ) -> Result<Targets, String> {
+        let graph = validate(&targets)?;
+        let entries = targets
+            .into_iter()
+            .map(|target| Entry {
+                target,
+                triggers: None,
+            })
+            .collect();
+
+        let targets = Targets {
+            inner: Mutex::new(Inner { entries, graph }),
+            poller,
+            listener,
+            file,
+            waiting: Arc::new(AtomicUsize::new(0)),
+        };
+        for entry in &mut targets.inner.lock().unwrap().entries {
+            targets.start(entry);
+        }
+
+        Ok(targets)
+    }
+
+    pub fn list(&self) -> Vec<CanaryTarget> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.iter().map(|e| e.target.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<CanaryTarget> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .find(|e| e.target.name == name)
+            .map(|e| e.target.clone())
+    }
+
+    /// Whether results of `target` should still be handled. Results of deleted, paused or
+    /// since changed targets may arrive from checks which were running at the time.
+    pub fn is_checked(&self, target: &CanaryTarget) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .any(|e| &e.target == target && e.triggers.is_some())
+    }
+
+    pub fn blocked_by<F>(&self, name: &str, is_failing: F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.inner
+            .lock()
+            .unwrap()
+            .graph
+            .blocked_by(name, is_failing)
+    }
+
+    pub fn create(&self, target: CanaryTarget) -> Result<CanaryTarget, TargetError> {
+        self.change(|entries| {
+            if entries.iter().any(|e| e.target.name == target.name) {
+                return Err(TargetError::Conflict(format!(
+                    "there is already a target named `{}`",
+                    target.name
+                )));
+            }
+
+            entries.push(Entry {
+                target: target.clone(),
+                triggers: None,
+            });
+            Ok(target.clone())
+        })
+    }
+
+    pub fn update(&self, name: &str, target: CanaryTarget) -> Result<CanaryTarget, TargetError> {
+        if target.name != name {
+            return Err(TargetError::Invalid(
+                "targets cannot be renamed, delete and create it instead".to_string(),
+            ));
+        }
+
+        self.change(|entries| {
+            let entry = find(entries, name)?;
+            entry.target = target.clone();
+            Ok(target.clone())
+        })
+    }
+
+    pub fn delete(&self, name: &str) -> Result<CanaryTarget, TargetError> {
+        self.change(|entries| {
+            let index = entries
+                .iter()
+                .position(|e| e.target.name == name)
+                .ok_or_else(|| not_found(name))?;
+            Ok(entries.remove(index).target)
+        })
+    }
+
+    pub fn set_paused(&self, name: &str, paused: bool) -> Result<CanaryTarget, TargetError> {
+        self.change(|entries| {
+            let entry = find(entries, name)?;
+            entry.target.paused = paused;
+            Ok(entry.target.clone())
+        })
+    }
+
+    // Applies a change to a copy of the targets, then validates and saves it before
+    // restarting the checks of targets which changed
+    fn change<F>(&self, f: F) -> Result<CanaryTarget, TargetError>
+    where
+        F: FnOnce(&mut Vec<Entry>) -> Result<CanaryTarget, TargetError>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let mut entries = inner
+            .entries
+            .iter()
+            .map(|e| Entry {
+                target: e.target.clone(),
+                triggers: None,
+            })
+            .collect::<Vec<_>>();
+        let changed = f(&mut entries)?;
+
+        let targets = entries.iter().map(|e| e.target.clone()).collect::<Vec<_>>();
+        let graph = validate(&targets).map_err(|err| {
+            // Removing a target others depend on is the only way to orphan a dependency
+            if entries.len() < inner.entries.len() {
+                TargetError::Conflict(err)
+            } else {
+                TargetError::Invalid(err)
+            }
+        })?;
+        if let Some(ref file) = self.file {
+            save(file, &targets).map_err(TargetError::Persist)?;
+        }
+
+        // Unchanged targets keep being checked on their schedule
+        for entry in &mut entries {
+            let running = inner
+                .entries
+                .iter_mut()
+                .find(|e| e.target == entry.target)
+                .and_then(|e| e.triggers.take());
+            match running {
+                Some(triggers) => entry.triggers = Some(triggers),
+                None => self.start(entry),
+            }
+        }
+        // Dropping the rest stops their checks
+        inner.entries = entries;
+        inner.graph = graph;
+        drop(inner);
+
+        info!("[targets.change] {:?}", &changed);
+        (self.listener)(&CanaryTargetTypes { http: targets });
+        Ok(changed)
+    }
+
+    fn start(&self, entry: &mut Entry) {
+        if entry.target.paused {
+            return;
+        }
+
+        let (triggers, triggered) = mpsc::channel();
+        (self.poller)(entry.target.clone(), triggered);
+        entry.triggers = Some(triggers);
+    }
+
+    /// Checks a target now instead of at its next interval, see `wait`
+    pub fn check_now(&self, name: &str) -> Result<Triggered, TargetError> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner
+            .entries
+            .iter()
+            .find(|e| e.target.name == name)
+            .ok_or_else(|| not_found(name))?;
+        if entry.triggers.is_none() {
+            return Err(TargetError::Conflict(format!(
+                "target `{}` is paused",
+                name
+            )));
+        }
+
+        self.trigger(&[entry])
+    }
+
+    /// Checks every target with a tag now, skipping paused ones
+    pub fn check_tag_now(&self, tag: &str) -> Result<Triggered, TargetError> {
+        let inner = self.inner.lock().unwrap();
+        let tagged = inner
+            .entries
+            .iter()
+            .filter(|e| e.target.tag.as_deref() == Some(tag))
+            .collect::<Vec<_>>();
+        if tagged.is_empty() {
+            return Err(TargetError::NotFound(format!(
+                "no targets are tagged `{}`",
+                tag
+            )));
+        }
+
+        if tagged.iter().all(|e| e.triggers.is_none()) {
+            return Err(TargetError::Conflict(format!(
+                "every target tagged `{}` is paused",
+                tag
+            )));
+        }
+
+        self.trigger(&tagged)
+    }
+
+    // Triggers checks of the entries which are not paused
+    fn trigger(&self, entries: &[&Entry]) -> Result<Triggered, TargetError> {
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= MAX_CHECKS_WAITING {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(TargetError::Busy(
+                "too many checks are running, try again later".to_string(),
+            ));
+        }
+
+        let checks = entries
+            .iter()
+            .filter_map(|entry| {
+                let (trigger, checked) = mpsc::channel();
+                entry.triggers.as_ref()?.send(trigger).ok()?;
+                Some(checked)
+            })
+            .collect();
+        let triggered = Triggered {
+            checks,
+            waiting: self.waiting.clone(),
+        };
+
+        // Checks of targets being stopped are not delivered, so there would be nothing to wait for
+        if triggered.checks.is_empty() {
+            return Err(TargetError::Conflict(
+                "the target stopped being checked".to_string(),
+            ));
+        }
+        Ok(triggered)
+    }
+}
+
+fn find<'a>(entries: &'a mut [Entry], name: &str) -> Result<&'a mut Entry, TargetError> {
+    entries
+        .iter_mut()
+        .find(|e| e.target.name == name)
+        .ok_or_else(|| not_found(name))
+}
+
+fn not_found(name: &str) -> TargetError {
+    TargetError::NotFound(format!("unknown target `{}`", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{check, target};
+    use librcanary::{Auth, Status};
+    use std::env;
+    use std::sync::mpsc::TryRecvError;
+    use std::sync::{Arc, Mutex};
+
+    fn named(name: &str) -> CanaryTarget {
+        let mut target = target();
+        target.name = name.to_string();
+        target
+    }
+
+    /// Names of the started targets with their trigger receivers
+    type Polled = Arc<Mutex<Vec<(String, Receiver<Trigger>)>>>;
+
+    /// Targets whose pollers hand back their trigger receivers, and the broadcast target lists
+    fn targets(
+        initial: Vec<CanaryTarget>,
+        file: Option<String>,
+    ) -> (Targets, Polled, Arc<Mutex<Vec<usize>>>) {
+        let polled = Arc::new(Mutex::new(vec![]));
+        let listened = Arc::new(Mutex::new(vec![]));
+        let (polled_clone, listened_clone) = (polled.clone(), listened.clone());
+
+        let targets = Targets::new(
+            initial,
+            file,
+            Box::new(move |target, triggered| {
+                polled_clone.lock().unwrap().push((target.name, triggered));
+            }),
+            Box::new(move |targets| listened_clone.lock().unwrap().push(targets.http.len())),
+        )
+        .unwrap();
+
+        (targets, polled, listened)
+    }
+
+    fn is_stopped(triggered: &Receiver<Trigger>) -> bool {
+        matches!(triggered.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    #[test]
+    fn it_starts_and_stops_checks() {
+        let mut paused = named("b");
+        paused.paused = true;
+        let (targets, polled, listened) = targets(vec![named("a"), paused], None);
+        assert_eq!(vec!["a"], names(&polled));
+
+        targets.create(named("c")).unwrap();
+        assert_eq!(vec!["a", "c"], names(&polled));
+        assert!(targets.is_checked(&named("c")));
+
+        targets.set_paused("c", true).unwrap();
+        assert!(is_stopped(&polled.lock().unwrap()[1].1));
+        assert!(!targets.is_checked(&named("c")));
+        targets.set_paused("b", false).unwrap();
+        assert_eq!(vec!["a", "c", "b"], names(&polled));
+
+        let mut slower = named("a");
+        slower.interval_s = 60;
+        targets.update("a", slower.clone()).unwrap();
+        assert!(is_stopped(&polled.lock().unwrap()[0].1));
+        assert!(!targets.is_checked(&named("a")));
+        assert!(targets.is_checked(&slower));
+
+        targets.delete("b").unwrap();
+        assert!(is_stopped(&polled.lock().unwrap()[2].1));
+        assert!(!is_stopped(&polled.lock().unwrap()[3].1));
+        assert_eq!(vec!["a", "c"], target_names(&targets));
+        assert_eq!(vec![3, 3, 3, 3, 2], *listened.lock().unwrap());
+    }
+
+    fn names(polled: &Polled) -> Vec<String> {
+        polled
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+
+    fn target_names(targets: &Targets) -> Vec<String> {
+        targets.list().into_iter().map(|t| t.name).collect()
+    }
+
+    #[test]
+    fn it_triggers_checks() {
+        let mut paused = named("b");
+        paused.paused = true;
+        let (targets, polled, _) = targets(vec![named("a"), paused], None);
+
+        let checked = targets.check_now("a").unwrap();
+        let check = check("a", Status::Okay);
+        let trigger = polled.lock().unwrap()[0].1.try_recv().unwrap();
+        trigger.send(check.clone()).unwrap();
+        assert_eq!(Ok(vec![check]), checked.wait());
+
+        assert_eq!(409, targets.check_now("b").unwrap_err().status_code());
+        assert_eq!(404, targets.check_now("c").unwrap_err().status_code());
+        assert_eq!(1, targets.check_tag_now("tag").unwrap().checks.len());
+        assert_eq!(
+            404,
+            targets.check_tag_now("other").unwrap_err().status_code()
+        );
+
+        // Results of checks dropped by a change never arrive
+        let checked = targets.check_now("a").unwrap();
+        targets.delete("a").unwrap();
+        polled.lock().unwrap().clear();
+        assert_eq!(409, checked.wait().unwrap_err().status_code());
+
+        // Nor are triggers of targets whose checks have stopped
+        targets.create(named("c")).unwrap();
+        polled.lock().unwrap().clear();
+        assert_eq!(409, targets.check_now("c").unwrap_err().status_code());
+        assert_eq!(409, targets.check_tag_now("tag").unwrap_err().status_code());
+        assert_eq!(0, targets.waiting.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_limits_checks_waited_for() {
+        let (targets, _, _) = targets(vec![named("a")], None);
+
+        let waiting = (0..MAX_CHECKS_WAITING)
+            .map(|_| targets.check_now("a").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(429, targets.check_now("a").unwrap_err().status_code());
+        assert_eq!(429, targets.check_tag_now("tag").unwrap_err().status_code());
+
+        drop(waiting);
+        assert!(targets.check_now("a").is_ok());
+    }
+
+    #[test]
+    fn it_validates_changes() {
+        let mut child = named("child");
+        child.depends_on = vec!["parent".to_string()];
+        let (targets, _, listened) = targets(vec![named("parent"), child], None);
+
+        assert_eq!(
+            409,
+            targets.create(named("parent")).unwrap_err().status_code()
+        );
+        assert_eq!(409, targets.delete("parent").unwrap_err().status_code());
+        assert_eq!(404, targets.delete("orphan").unwrap_err().status_code());
+        assert_eq!(
+            404,
+            targets
+                .set_paused("orphan", true)
+                .unwrap_err()
+                .status_code()
+        );
+        assert_eq!(
+            400,
+            targets
+                .update("parent", named("other"))
+                .unwrap_err()
+                .status_code()
+        );
+
+        let mut cycle = named("parent");
+        cycle.depends_on = vec!["child".to_string()];
+        assert_eq!(
+            400,
+            targets.update("parent", cycle).unwrap_err().status_code()
+        );
+        let mut never = named("never");
+        never.interval_s = 0;
+        assert_eq!(400, targets.create(never).unwrap_err().status_code());
+        for name in &["a/b", "tab\tbed", ""] {
+            assert_eq!(400, targets.create(named(name)).unwrap_err().status_code());
+        }
+
+        assert_eq!(vec!["parent", "child"], target_names(&targets));
+        assert!(listened.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_saves_changes_to_the_targets_file() {
+        let path = env::temp_dir().join("rcanary-targets.toml");
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        assert_eq!(Ok(None), load(&path));
+
+        let (targets, _, _) = targets(vec![named("a")], Some(path.clone()));
+        let mut secret = named("secret");
+        secret.basic_auth = Some(Auth {
+            username: "AzureDiamond".to_string(),
+            password: Some("hunter2".to_string()),
+        });
+        secret
+            .labels
+            .insert("team".to_string(), "payments".to_string());
+        targets.create(secret.clone()).unwrap();
+
+        assert_eq!(Some(vec![named("a"), secret]), load(&path).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+}
@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslAcceptor, SslMethod, SslStream};
+use openssl::x509::X509;
+use rouille::{Request, Response};
+
+use librcanary::CanaryTlsConfig;
+
+/// How often the reload thread looks for SIGHUPs
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long clients get to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a forwarded TLS connection waits for the rest of a record from the client while
+/// holding the connection. Kept short as responses to the client wait behind it, the read is
+/// retried once more of the record has arrived.
+const RECORD_TIMEOUT: Duration = Duration::from_millis(2);
+/// Wait after failing to accept a connection, eg. when out of file descriptors
+const ACCEPT_ERROR_INTERVAL: Duration = Duration::from_millis(100);
+/// How long clients get to send the head of a request read with `read_head`
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Certificate and key shared by every listener, reloaded from disk on SIGHUP. Listeners
+/// stay bound across reloads, new connections get the new certificate.
+pub struct Tls {
+    config: CanaryTlsConfig,
+    current: RwLock<SslAcceptor>,
+}
+
+impl Tls {
+    pub fn new(config: &CanaryTlsConfig) -> Result<Tls, String> {
+        Ok(Tls {
+            config: config.clone(),
+            current: RwLock::new(load(config)?),
+        })
+    }
+
+    /// Reads the certificate and key again, keeping the old ones if they are invalid
+    pub fn reload(&self) -> Result<(), String> {
+        let acceptor = load(&self.config)?;
+        *self.current.write().unwrap() = acceptor;
+
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> SslAcceptor {
+        self.current.read().unwrap().clone()
+    }
+}
+
+fn load(config: &CanaryTlsConfig) -> Result<SslAcceptor, String> {
+    let chain = fs::read(&config.certificate_path)
+        .map_err(|err| format!("failed to read {}: {}", config.certificate_path, err))?;
+    let key = fs::read(&config.key_path)
+        .map_err(|err| format!("failed to read {}: {}", config.key_path, err))?;
+
+    let certificates = X509::stack_from_pem(&chain)
+        .map_err(|err| format!("invalid certificate {}: {}", config.certificate_path, err))?;
+    let private_key = PKey::private_key_from_pem(&key)
+        .map_err(|err| format!("invalid key {}: {}", config.key_path, err))?;
+
+    let mut builder =
+        SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|err| err.to_string())?;
+    let mut certificates = certificates.into_iter();
+    let leaf = certificates
+        .next()
+        .ok_or_else(|| format!("no certificates in {}", config.certificate_path))?;
+    builder
+        .set_certificate(&leaf)
+        .map_err(|err| err.to_string())?;
+    for intermediate in certificates {
+        builder
+            .add_extra_chain_cert(intermediate)
+            .map_err(|err| err.to_string())?;
+    }
+    builder
+        .set_private_key(&private_key)
+        .map_err(|err| err.to_string())?;
+    builder
+        .check_private_key()
+        .map_err(|err| format!("key does not match certificate: {}", err))?;
+
+    Ok(builder.build())
+}
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reloads the certificate and key whenever rcanary gets a SIGHUP
+pub fn reload_on_sighup(tls: Arc<Tls>) {
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            request_reload as *const () as libc::sighandler_t,
+        );
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(RELOAD_CHECK_INTERVAL);
+
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            match tls.reload() {
+                Ok(()) => info!("[tls.reload] reloaded certificate"),
+                Err(err) => info!(
+                    "[tls.error] failed to reload certificate, keeping the old one: {}",
+                    err
+                ),
+            }
+        }
+    });
+}
+
+/// A client connection, encrypted if TLS is enabled
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Stream {
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Reads the request line and headers of an HTTP request, up to the blank line ending them
+pub fn read_head(stream: &mut Stream) -> io::Result<Vec<u8>> {
+    stream.socket().set_read_timeout(Some(HEAD_TIMEOUT))?;
+
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => head.extend_from_slice(&buffer[..n]),
+        }
+    }
+
+    stream.socket().set_read_timeout(None)?;
+    Ok(head)
+}
+
+/// Accepts connections and hands each to `handle` on its own thread, after the TLS handshake
+/// if TLS is enabled. Handshakes use the certificate current at the time, so the listener
+/// never has to be bound again.
+pub fn accept<F>(listener: TcpListener, tls: Option<Arc<Tls>>, handle: F) -> !
+where
+    F: Fn(Stream, SocketAddr) + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+
+    loop {
+        let (socket, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                info!("[tls.error] failed to accept a connection: {}", err);
+                thread::sleep(ACCEPT_ERROR_INTERVAL);
+                continue;
+            }
+        };
+        let tls = tls.clone();
+        let handle = handle.clone();
+
+        thread::spawn(move || {
+            let stream = match tls {
+                Some(tls) => match handshake(&tls, socket) {
+                    Ok(stream) => Stream::Tls(stream),
+                    Err(err) => {
+                        info!("[tls.error] handshake with {} failed: {}", peer, err);
+                        return;
+                    }
+                },
+                None => Stream::Plain(socket),
+            };
+
+            handle(stream, peer);
+        });
+    }
+}
+
+fn handshake(tls: &Tls, socket: TcpStream) -> Result<SslStream<TcpStream>, String> {
+    socket
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|_| socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+        .map_err(|err| err.to_string())?;
+    let stream = tls
+        .acceptor()
+        .accept(socket)
+        .map_err(|err| err.to_string())?;
+    stream
+        .get_ref()
+        .set_read_timeout(None)
+        .and_then(|_| stream.get_ref().set_write_timeout(None))
+        .map_err(|err| err.to_string())?;
+
+    Ok(stream)
+}
+
+/// Copies bytes both ways between a client and a server until either side is done
+pub fn forward(client: Stream, server: TcpStream) {
+    let mut from_server = match server.try_clone() {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+
+    match client {
+        Stream::Plain(client) => {
+            let mut to_server = server;
+            let mut from_client = match client.try_clone() {
+                Ok(client) => client,
+                Err(_) => return,
+            };
+            let upstream = thread::spawn(move || {
+                let _ = io::copy(&mut from_client, &mut to_server);
+                let _ = to_server.shutdown(Shutdown::Write);
+            });
+
+            let _ = io::copy(&mut from_server, &mut &client);
+            let _ = client.shutdown(Shutdown::Both);
+            let _ = upstream.join();
+        }
+        Stream::Tls(client) => {
+            // Reads and writes both need the TLS session, so reads only hold it once the
+            // socket has something to read, and then only for a bounded time
+            let fd = client.get_ref().as_raw_fd();
+            if client
+                .get_ref()
+                .set_read_timeout(Some(RECORD_TIMEOUT))
+                .is_err()
+            {
+                return;
+            }
+            let client = Arc::new(Mutex::new(client));
+
+            let mut to_server = server;
+            let from_client = client.clone();
+            let upstream = thread::spawn(move || {
+                let mut buffer = [0; 16 * 1024];
+                loop {
+                    if from_client.lock().unwrap().ssl().pending() == 0 && !wait_readable(fd) {
+                        break;
+                    }
+                    let read = from_client.lock().unwrap().read(&mut buffer);
+                    match read {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if to_server.write_all(&buffer[..n]).is_err() {
+                                break;
+                            }
+                        }
+                        Err(ref err)
+                            if err.kind() == io::ErrorKind::WouldBlock
+                                || err.kind() == io::ErrorKind::TimedOut => {}
+                        Err(_) => break,
+                    }
+                }
+                let _ = to_server.shutdown(Shutdown::Write);
+            });
+
+            let mut buffer = [0; 16 * 1024];
+            loop {
+                let n = match from_server.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                if client.lock().unwrap().write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+            }
+            {
+                let mut client = client.lock().unwrap();
+                let _ = client.shutdown();
+                let _ = client.get_ref().shutdown(Shutdown::Both);
+            }
+            let _ = upstream.join();
+        }
+    }
+}
+
+// Blocks until the socket has something to read, or has been closed
+fn wait_readable(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            return true;
+        }
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return false;
+        }
+    }
+}
+
+/// Serves HTTP requests, over TLS if it is enabled.
+///
+/// rouille cannot change certificates, so TLS connections are accepted here and forwarded to
+/// a plain server on a loopback address. Its requests carry the address of the client they
+/// were forwarded for.
+pub fn serve<F>(addr: SocketAddr, tls: Option<Arc<Tls>>, handler: F) -> !
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let tls = match tls {
+        Some(tls) => tls,
+        None => rouille::start_server(addr, handler),
+    };
+
+    let listener = TcpListener::bind(addr)
+        .unwrap_or_else(|err| panic!("[status.startup] failed to listen on {}: {}", addr, err));
+    // Clients, by the address their requests are forwarded from
+    let clients = Arc::new(Mutex::new(HashMap::<SocketAddr, SocketAddr>::new()));
+
+    let forwarded = clients.clone();
+    let server = rouille::Server::new("127.0.0.1:0", move |request| {
+        let client = forwarded
+            .lock()
+            .unwrap()
+            .get(request.remote_addr())
+            .cloned();
+        match client {
+            Some(client) => handler(&from_client(request, client)),
+            // Only connections forwarded from the TLS listener are served
+            None => Response::text("Forbidden").with_status_code(403),
+        }
+    })
+    .unwrap_or_else(|err| panic!("[status.startup] failed to listen on {}: {}", addr, err));
+    let server_addr = server.server_addr();
+    thread::spawn(move || server.run());
+
+    accept(listener, Some(tls), move |stream, peer| {
+        let server = match TcpStream::connect(server_addr) {
+            Ok(server) => server,
+            Err(err) => {
+                info!("[tls.error] failed to forward {}: {}", peer, err);
+                return;
+            }
+        };
+        let forwarded_from = match server.local_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+
+        clients.lock().unwrap().insert(forwarded_from, peer);
+        forward(stream, server);
+        clients.lock().unwrap().remove(&forwarded_from);
+    })
+}
+
+// The same request, as sent by `client`
+fn from_client(request: &Request, client: SocketAddr) -> Request {
+    let headers = request
+        .headers()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let mut body = vec![];
+    if let Some(mut data) = request.data() {
+        let _ = data.read_to_end(&mut body);
+    }
+
+    Request::fake_http_from(client, request.method(), request.raw_url(), headers, body)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use openssl::x509::X509NameBuilder;
+    use std::env;
+
+    /// Writes a self-signed certificate for `name` and its key, returning their paths
+    pub fn self_signed(name: &str) -> CanaryTlsConfig {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = env::temp_dir();
+        let config = CanaryTlsConfig {
+            certificate_path: dir
+                .join(format!("rcanary-{}.crt", name))
+                .to_string_lossy()
+                .into_owned(),
+            enabled: true,
+            key_path: dir
+                .join(format!("rcanary-{}.key", name))
+                .to_string_lossy()
+                .into_owned(),
+        };
+        fs::write(&config.certificate_path, builder.build().to_pem().unwrap()).unwrap();
+        fs::write(&config.key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn it_reloads_certificates() {
+        let config = self_signed("reload-old");
+        let tls = Tls::new(&config).unwrap();
+        let certificate = |tls: &Tls| {
+            tls.acceptor()
+                .context()
+                .certificate()
+                .unwrap()
+                .to_pem()
+                .unwrap()
+        };
+        assert_eq!(
+            fs::read(&config.certificate_path).unwrap(),
+            certificate(&tls)
+        );
+
+        let new = self_signed("reload-new");
+        fs::copy(&new.certificate_path, &config.certificate_path).unwrap();
+        fs::copy(&new.key_path, &config.key_path).unwrap();
+        tls.reload().unwrap();
+        assert_eq!(fs::read(&new.certificate_path).unwrap(), certificate(&tls));
+
+        // A certificate which does not match the key is refused, and the old one kept
+        fs::copy(
+            &self_signed("reload-other").certificate_path,
+            &config.certificate_path,
+        )
+        .unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(fs::read(&new.certificate_path).unwrap(), certificate(&tls));
+    }
+
+    #[test]
+    fn it_serves_https_and_picks_up_reloaded_certificates() {
+        let config = self_signed("serve-old");
+        let tls = Arc::new(Tls::new(&config).unwrap());
+        let serving = tls.clone();
+        thread::spawn(move || {
+            serve(
+                "127.0.0.1:56482".parse().unwrap(),
+                Some(serving),
+                |request| Response::text(request.remote_addr().ip().to_string()),
+            )
+        });
+
+        // Returns the response and the server's certificate
+        let get = || {
+            let started = std::time::Instant::now();
+            let socket = loop {
+                match TcpStream::connect("127.0.0.1:56482") {
+                    Ok(socket) => break socket,
+                    Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                        thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(err) => panic!("server did not start: {}", err),
+                }
+            };
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let mut stream = connector.build().connect("localhost", socket).unwrap();
+            let certificate = stream.ssl().peer_certificate().unwrap().to_pem().unwrap();
+
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = vec![];
+            let mut buffer = [0; 1024];
+            while let Ok(n) = stream.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                response.extend_from_slice(&buffer[..n]);
+            }
+
+            (String::from_utf8(response).unwrap(), certificate)
+        };
+
+        let (response, certificate) = get();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("127.0.0.1"));
+        assert_eq!(fs::read(&config.certificate_path).unwrap(), certificate);
+
+        let new = self_signed("serve-new");
+        fs::copy(&new.certificate_path, &config.certificate_path).unwrap();
+        fs::copy(&new.key_path, &config.key_path).unwrap();
+        tls.reload().unwrap();
+
+        let (response, certificate) = get();
+        assert!(response.ends_with("127.0.0.1"));
+        assert_eq!(fs::read(&new.certificate_path).unwrap(), certificate);
+    }
+
+    #[test]
+    fn it_refuses_missing_files() {
+        let config = CanaryTlsConfig {
+            certificate_path: "/nonexistent/rcanary.crt".to_string(),
+            enabled: true,
+            key_path: "/nonexistent/rcanary.key".to_string(),
+        };
+
+        assert!(Tls::new(&config).is_err());
+    }
+}
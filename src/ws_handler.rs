@@ -1,23 +1,820 @@
-use crate::CanaryConfig;
-use ws::{Factory, Handler, Sender};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
-pub struct ClientHandler;
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-impl Handler for ClientHandler {}
+use crate::alerter::silence::{AlertControls, AlertControlsState};
+use crate::auth::{Authenticator, Denied};
+use crate::incidents::IncidentTracker;
+use crate::recent::{RecentChecks, Snapshot};
+use crate::targets::{Targets, Triggered};
+use crate::tls::{self, Tls};
+use librcanary::{CanaryAuthScope, CanaryCheck, CanaryIncident, CanaryTargetTypes, Status};
+use url::Url;
+use ws::{CloseCode, Factory, Handler, Handshake, Sender};
 
+/// Bumped whenever messages change in a way old clients cannot handle
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Incidents sent to clients when they connect
+const RECENT_INCIDENTS: usize = 50;
+
+/// Everything sent to websocket clients, eg. `{"version": 1, "type": "result", "data": {...}}`
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Message<'a> {
+    /// The targets being checked, sent first and whenever they change
+    Config(&'a CanaryTargetTypes),
+    /// Latest and recent checks, sent when a client connects or asks for one
+    Snapshot(Snapshot),
+    Result(&'a CanaryCheck),
+    /// Acknowledgements and silences
+    Controls(AlertControlsState),
+    /// Recent incidents, sent when a client connects
+    Incidents(Vec<CanaryIncident>),
+    /// An incident which opened, changed or ended
+    Incident(&'a CanaryIncident),
+    /// The client's filters, after it subscribes or unsubscribes
+    Subscription(SubscriptionState),
+    /// Checks the client asked for, once they have been handled
+    Checked(Vec<CanaryCheck>),
+    Pong,
+    /// The client sent something that could not be handled
+    Error(String),
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, 'b> {
+    version: u32,
+    #[serde(flatten)]
+    message: &'b Message<'a>,
+}
+
+impl<'a> Message<'a> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Envelope {
+            version: PROTOCOL_VERSION,
+            message: self,
+        })
+        .expect("websocket messages are always serializable")
+    }
+}
+
+/// Everything clients can send, eg. `{"type": "subscribe", "tag": "^web-"}`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Adds `names`, and replaces the tag regex and statuses if given
+    Subscribe {
+        #[serde(default)]
+        names: Vec<String>,
+        tag: Option<String>,
+        statuses: Option<Vec<Status>>,
+    },
+    /// Removes `names`, or every filter if no names are given
+    Unsubscribe {
+        #[serde(default)]
+        names: Vec<String>,
+    },
+    Snapshot,
+    /// Checks a target, or every target with a tag, now
+    Check {
+        name: Option<String>,
+        tag: Option<String>,
+    },
+    Ping,
+}
+
+/// What a client wants to receive. Empty filters match everything, so new clients get every message.
+/// Results are filtered by status, except when the target's status changes, so clients only
+/// asking for failures still see targets recover.
+#[derive(Default, Debug)]
+pub struct Subscription {
+    names: BTreeSet<String>,
+    tag: Option<Regex>,
+    statuses: Vec<Status>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SubscriptionState {
+    pub names: Vec<String>,
+    pub tag: Option<String>,
+    pub statuses: Vec<Status>,
+}
+
+impl Subscription {
+    pub fn new(
+        names: Vec<String>,
+        tag: Option<&str>,
+        statuses: Vec<Status>,
+    ) -> Result<Subscription, String> {
+        Ok(Subscription {
+            names: names.into_iter().collect(),
+            tag: tag
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| format!("invalid tag regex: {}", e))?,
+            statuses,
+        })
+    }
+
+    // A `None` status matches any status filter
+    pub fn wants(&self, name: &str, tag: Option<&String>, status: Option<&Status>) -> bool {
+        (self.names.is_empty() || self.names.contains(name))
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|re| tag.is_some_and(|t| re.is_match(t)))
+            && status.is_none_or(|s| self.statuses.is_empty() || self.statuses.contains(s))
+    }
+
+    pub fn wants_result(&self, result: &CanaryCheck, previous_status: Option<&Status>) -> bool {
+        let changed = previous_status.is_some_and(|s| *s != result.status);
+        let status = if changed { None } else { Some(&result.status) };
+
+        self.wants(&result.target.name, result.target.tag.as_ref(), status)
+    }
+
+    fn state(&self) -> SubscriptionState {
+        SubscriptionState {
+            names: self.names.iter().cloned().collect(),
+            tag: self.tag.as_ref().map(|re| re.as_str().to_string()),
+            statuses: self.statuses.clone(),
+        }
+    }
+}
+
+struct Client {
+    send: Box<dyn Fn(String) + Send>,
+    subscription: Subscription,
+}
+
+/// Connected websocket clients and what they are subscribed to
+#[derive(Clone)]
+pub struct Clients {
+    clients: Arc<Mutex<BTreeMap<u32, Client>>>,
+    /// Tag of every target, by name, to filter incidents by tag
+    tags: Arc<RwLock<HashMap<String, Option<String>>>>,
+}
+
+impl Clients {
+    pub fn new(targets: &CanaryTargetTypes) -> Clients {
+        Clients {
+            clients: Arc::new(Mutex::new(BTreeMap::new())),
+            tags: Arc::new(RwLock::new(tags(targets))),
+        }
+    }
+
+    /// Sends every client the new targets
+    pub fn targets_changed(&self, targets: &CanaryTargetTypes) {
+        *self.tags.write().unwrap() = tags(targets);
+        self.broadcast(&Message::Config(targets));
+    }
+
+    fn add<F: Fn(String) + Send + 'static>(&self, id: u32, send: F) {
+        self.clients.lock().unwrap().insert(
+            id,
+            Client {
+                send: Box::new(send),
+                subscription: Subscription::default(),
+            },
+        );
+    }
+
+    fn remove(&self, id: u32) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Sends a message to every client subscribed to it
+    pub fn broadcast(&self, message: &Message) {
+        let json = message.to_json();
+
+        for client in self.clients.lock().unwrap().values() {
+            if self.wants(&client.subscription, message) {
+                (client.send)(json.clone());
+            }
+        }
+    }
+
+    /// Sends a check result to every client subscribed to its target and status
+    pub fn broadcast_result(&self, result: &CanaryCheck, previous_status: Option<&Status>) {
+        let json = Message::Result(result).to_json();
+
+        for client in self.clients.lock().unwrap().values() {
+            if client.subscription.wants_result(result, previous_status) {
+                (client.send)(json.clone());
+            }
+        }
+    }
+
+    /// Sends a message to one client, filtering lists down to what it is subscribed to
+    fn send(&self, id: u32, message: Message) {
+        let clients = self.clients.lock().unwrap();
+        let client = match clients.get(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let subscription = &client.subscription;
+
+        let message = match message {
+            Message::Snapshot(mut snapshot) => {
+                snapshot
+                    .latest
+                    .retain(|c| subscription.wants_result(c, None));
+                snapshot
+                    .history
+                    .retain(|name, _| subscription.wants(name, self.tag(name).as_ref(), None));
+                Message::Snapshot(snapshot)
+            }
+            Message::Incidents(mut incidents) => {
+                incidents
+                    .retain(|i| subscription.wants(&i.target, self.tag(&i.target).as_ref(), None));
+                Message::Incidents(incidents)
+            }
+            other => other,
+        };
+
+        (client.send)(message.to_json());
+    }
+
+    fn wants(&self, subscription: &Subscription, message: &Message) -> bool {
+        match message {
+            Message::Result(c) => subscription.wants_result(c, None),
+            Message::Incident(i) => {
+                subscription.wants(&i.target, self.tag(&i.target).as_ref(), None)
+            }
+            _ => true,
+        }
+    }
+
+    fn tag(&self, name: &str) -> Option<String> {
+        self.tags.read().unwrap().get(name).cloned().flatten()
+    }
+
+    // Applies a subscription change, returning the resulting filters
+    fn update(&self, id: u32, request: Request) -> Result<SubscriptionState, String> {
+        let mut clients = self.clients.lock().unwrap();
+        let subscription = match clients.get_mut(&id) {
+            Some(client) => &mut client.subscription,
+            None => return Err("client is not connected".to_string()),
+        };
+
+        match request {
+            Request::Subscribe {
+                names,
+                tag,
+                statuses,
+            } => {
+                if let Some(tag) = tag {
+                    let re = Regex::new(&tag).map_err(|e| format!("invalid tag regex: {}", e))?;
+                    subscription.tag = Some(re);
+                }
+                if let Some(statuses) = statuses {
+                    subscription.statuses = statuses;
+                }
+                subscription.names.extend(names);
+            }
+            Request::Unsubscribe { ref names } if names.is_empty() => {
+                *subscription = Subscription::default();
+            }
+            Request::Unsubscribe { names } => {
+                for name in names {
+                    subscription.names.remove(&name);
+                }
+            }
+            _ => (),
+        }
+
+        Ok(subscription.state())
+    }
+}
+
+fn tags(targets: &CanaryTargetTypes) -> HashMap<String, Option<String>> {
+    targets
+        .http
+        .iter()
+        .map(|t| (t.name.clone(), t.tag.clone()))
+        .collect()
+}
+
+/// Shared with every connection to answer requests
+#[derive(Clone)]
 pub struct ClientFactory {
-    pub config: CanaryConfig,
+    /// Who may connect, if auth is enabled
+    pub auth: Option<Arc<Authenticator>>,
+    pub targets: Arc<Targets>,
+    pub clients: Clients,
+    pub controls: Arc<Mutex<AlertControls>>,
+    pub incidents: Arc<Mutex<IncidentTracker>>,
+    pub recent: Arc<Mutex<RecentChecks>>,
+}
+
+impl ClientFactory {
+    // Everything a client needs to show the current state
+    fn connected(&self, id: u32) {
+        let targets = CanaryTargetTypes {
+            http: self.targets.list(),
+        };
+        self.clients.send(id, Message::Config(&targets));
+        self.send_snapshot(id);
+
+        let controls = self.controls.lock().unwrap().state(time::get_time());
+        self.clients.send(id, Message::Controls(controls));
+
+        let incidents = self.incidents.lock().unwrap().list(None, RECENT_INCIDENTS);
+        self.clients.send(id, Message::Incidents(incidents));
+    }
+
+    fn send_snapshot(&self, id: u32) {
+        let snapshot = self.recent.lock().unwrap().snapshot();
+        self.clients.send(id, Message::Snapshot(snapshot));
+    }
+
+    // Checks are waited for in the background so other clients are still served
+    fn check_now(&self, id: u32, name: Option<String>, tag: Option<String>, admin: bool) {
+        if !admin {
+            let message = "checking targets needs the admin scope".to_string();
+            return self.clients.send(id, Message::Error(message));
+        }
+
+        let checked = match (name, tag) {
+            (Some(name), None) => self.targets.check_now(&name),
+            (None, Some(tag)) => self.targets.check_tag_now(&tag),
+            _ => {
+                let message = "checks need either a name or a tag".to_string();
+                return self.clients.send(id, Message::Error(message));
+            }
+        };
+
+        // At most `MAX_CHECKS_WAITING` of these threads run at once, further checks are refused
+        let clients = self.clients.clone();
+        thread::spawn(move || match checked.and_then(Triggered::wait) {
+            Ok(checks) => clients.send(id, Message::Checked(checks)),
+            Err(err) => clients.send(id, Message::Error(err.message().to_string())),
+        });
+    }
+
+    /// `admin` is whether the client may change things, such as checking targets
+    fn handle(&self, id: u32, text: &str, admin: bool) {
+        let request = match serde_json::from_str::<Request>(text) {
+            Ok(request) => request,
+            Err(err) => {
+                let message = format!("invalid request: {}", err);
+                return self.clients.send(id, Message::Error(message));
+            }
+        };
+
+        match request {
+            Request::Ping => self.clients.send(id, Message::Pong),
+            Request::Snapshot => self.send_snapshot(id),
+            Request::Check { name, tag } => self.check_now(id, name, tag, admin),
+            request => match self.clients.update(id, request) {
+                Ok(state) => self.clients.send(id, Message::Subscription(state)),
+                Err(err) => self.clients.send(id, Message::Error(err)),
+            },
+        }
+    }
+}
+
+pub struct ClientHandler {
+    ws: Sender,
+    factory: ClientFactory,
+    /// Whether the client authenticated with the admin scope, or auth is disabled
+    admin: bool,
+}
+
+impl Handler for ClientHandler {
+    // Browsers cannot set headers on websockets, so they can pass a token in the URL instead
+    fn on_request(&mut self, request: &ws::Request) -> ws::Result<ws::Response> {
+        if let Some(ref auth) = self.factory.auth {
+            let authorization = request
+                .header("authorization")
+                .and_then(|h| std::str::from_utf8(h).ok());
+            let token = Url::parse(&format!("ws://localhost{}", request.resource()))
+                .ok()
+                .and_then(|url| {
+                    url.query_pairs()
+                        .find(|(key, _)| key == "token")
+                        .map(|(_, value)| value.into_owned())
+                });
+
+            if let Err(denied) =
+                auth.authenticate(authorization, token.as_deref(), CanaryAuthScope::Read)
+            {
+                info!(
+                    "[auth.denied] websocket client {}: {:?}",
+                    self.ws.connection_id(),
+                    denied
+                );
+                return Ok(ws::Response::new(
+                    denied.status_code(),
+                    denied.reason(),
+                    vec![],
+                ));
+            }
+            self.admin = auth
+                .authenticate(authorization, token.as_deref(), CanaryAuthScope::Admin)
+                .is_ok();
+        }
+
+        ws::Response::from_request(request)
+    }
+
+    fn on_open(&mut self, _handshake: Handshake) -> ws::Result<()> {
+        let id = self.ws.connection_id();
+
+        info!("[ws.connect] client {}", id);
+        let ws = self.ws.clone();
+        self.factory.clients.add(id, move |json| {
+            let _ = ws.send(json);
+        });
+        self.factory.connected(id);
+
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        if let ws::Message::Text(text) = message {
+            self.factory
+                .handle(self.ws.connection_id(), &text, self.admin);
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+        self.factory.clients.remove(self.ws.connection_id());
+    }
+}
+
+/// Serves websocket clients, over TLS if it is enabled.
+///
+/// Connections are accepted here and forwarded to the websocket server on a loopback address,
+/// so clients from addresses which are not allowed are refused before the upgrade, and TLS
+/// uses whatever certificate is current.
+pub fn start_ws_server(address: &str, factory: ClientFactory, tls: Option<Arc<Tls>>) {
+    let listener = TcpListener::bind(address).unwrap_or_else(|err| {
+        panic!(
+            "[status.startup] failed to start websocket listener {}",
+            err
+        );
+    });
+    let auth = factory.auth.clone();
+    let server = match ws::WebSocket::new(factory) {
+        Ok(server) => server.bind("127.0.0.1:0"),
+        Err(err) => Err(err),
+    }
+    .unwrap_or_else(|err| {
+        panic!("[status.startup] failed to start websocket server {}", err);
+    });
+    let server_addr = server.local_addr().unwrap_or_else(|err| {
+        panic!("[status.startup] failed to start websocket server {}", err);
+    });
+
+    thread::spawn(move || {
+        server.run().unwrap_or_else(|err| {
+            panic!("[status.startup] websocket server stopped {}", err);
+        });
+    });
+    thread::spawn(move || {
+        tls::accept(listener, tls, move |mut stream, peer| {
+            if auth.as_ref().is_some_and(|auth| !auth.allows(peer.ip())) {
+                info!(
+                    "[auth.denied] websocket client from {}: {:?}",
+                    peer,
+                    Denied::Forbidden
+                );
+                return refuse(&mut stream, &Denied::Forbidden);
+            }
+
+            match std::net::TcpStream::connect(server_addr) {
+                Ok(server) => tls::forward(stream, server),
+                Err(err) => info!("[ws.error] failed to forward {}: {}", peer, err),
+            }
+        })
+    });
+}
+
+// Answers the handshake with an error, once the client has sent it
+fn refuse(stream: &mut tls::Stream, denied: &Denied) {
+    let _ = tls::read_head(stream);
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        denied.status_code(),
+        denied.reason()
+    );
+    let _ = stream.flush();
 }
 
 impl Factory for ClientFactory {
     type Handler = ClientHandler;
 
     fn connection_made(&mut self, ws: Sender) -> ClientHandler {
-        let _ = ws.send(serde_json::to_string(&self.config.targets).unwrap());
-        ClientHandler {}
+        ClientHandler {
+            ws,
+            admin: self.auth.is_none(),
+            factory: self.clone(),
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::target;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn check(name: &str, tag: &str, status: Status) -> CanaryCheck {
+        let mut check = crate::tests::check(name, status);
+        check.target.tag = Some(tag.to_string());
+        check
+    }
+
+    fn client(clients: &Clients, id: u32) -> Arc<Mutex<Vec<String>>> {
+        let received = Arc::new(Mutex::new(vec![]));
+        let client_received = received.clone();
+        clients.add(id, move |json| client_received.lock().unwrap().push(json));
+        received
+    }
+
+    fn subscribe(clients: &Clients, id: u32, json: &str) -> Result<SubscriptionState, String> {
+        clients.update(id, serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn it_wraps_messages_in_a_versioned_envelope() {
+        let targets = CanaryTargetTypes { http: vec![] };
+
+        assert_eq!(
+            r#"{"version":1,"type":"config","data":{"http":[]}}"#,
+            Message::Config(&targets).to_json()
+        );
+        assert_eq!(
+            r#"{"version":1,"type":"incidents","data":[]}"#,
+            Message::Incidents(vec![]).to_json()
+        );
+        assert_eq!(r#"{"version":1,"type":"pong"}"#, Message::Pong.to_json());
+    }
+
+    #[test]
+    fn it_only_broadcasts_what_clients_subscribed_to() {
+        let clients = Clients::new(&CanaryTargetTypes { http: vec![] });
+        let everything = client(&clients, 0);
+        let web = client(&clients, 1);
+        let fire = client(&clients, 2);
+
+        subscribe(&clients, 1, r#"{"type": "subscribe", "tag": "^web-"}"#).unwrap();
+        let state = subscribe(
+            &clients,
+            2,
+            r#"{"type": "subscribe", "names": ["db"], "statuses": ["Fire"]}"#,
+        )
+        .unwrap();
+        assert_eq!(vec!["db"], state.names);
+
+        clients.broadcast_result(&check("api", "web-api", Status::Okay), None);
+        clients.broadcast_result(&check("db", "db", Status::Okay), None);
+        clients.broadcast_result(&check("db", "db", Status::Fire), Some(&Status::Okay));
+        clients.broadcast_result(&check("db", "db", Status::Fire), Some(&Status::Fire));
+
+        assert_eq!(4, everything.lock().unwrap().len());
+        assert_eq!(1, web.lock().unwrap().len());
+        assert_eq!(2, fire.lock().unwrap().len());
+        assert!(fire.lock().unwrap()[0].contains(r#""status":"Fire""#));
+
+        // Recoveries are sent even though the client only asked for failures
+        clients.broadcast_result(&check("db", "db", Status::Okay), Some(&Status::Fire));
+        clients.broadcast_result(&check("db", "db", Status::Okay), Some(&Status::Okay));
+        assert_eq!(3, fire.lock().unwrap().len());
+        assert!(fire.lock().unwrap()[2].contains(r#""status":"Okay""#));
+    }
+
+    #[test]
+    fn it_unsubscribes() {
+        let clients = Clients::new(&CanaryTargetTypes { http: vec![] });
+        client(&clients, 0);
+
+        subscribe(&clients, 0, r#"{"type": "subscribe", "names": ["a", "b"]}"#).unwrap();
+        let state = subscribe(&clients, 0, r#"{"type": "unsubscribe", "names": ["a"]}"#).unwrap();
+        assert_eq!(vec!["b"], state.names);
+
+        let state = subscribe(&clients, 0, r#"{"type": "unsubscribe"}"#).unwrap();
+        assert!(state.names.is_empty());
+
+        assert!(subscribe(&clients, 0, r#"{"type": "subscribe", "tag": "("}"#).is_err());
+        assert!(subscribe(&clients, 1, r#"{"type": "unsubscribe"}"#).is_err());
+    }
+
+    #[test]
+    fn it_sends_changed_targets() {
+        let clients = Clients::new(&CanaryTargetTypes { http: vec![] });
+        let web = client(&clients, 0);
+        subscribe(&clients, 0, r#"{"type": "subscribe", "tag": "^web-"}"#).unwrap();
+
+        let failing = check("api", "web-api", Status::Fire);
+        let mut incidents = IncidentTracker::new(None).unwrap();
+        let incident = incidents.update(&failing).unwrap();
+        clients.broadcast(&Message::Incident(&incident));
+        assert!(web.lock().unwrap().is_empty());
+
+        clients.targets_changed(&CanaryTargetTypes {
+            http: vec![failing.target.clone()],
+        });
+        clients.broadcast(&Message::Incident(&incident));
+
+        let received = web.lock().unwrap();
+        assert_eq!(2, received.len());
+        assert!(received[0].contains(r#""type":"config""#));
+        assert!(received[1].contains(r#""type":"incident""#));
+    }
+
+    fn factory() -> ClientFactory {
+        let targets = vec![target()];
+
+        ClientFactory {
+            auth: None,
+            clients: Clients::new(&CanaryTargetTypes {
+                http: targets.clone(),
+            }),
+            controls: Arc::new(Mutex::new(AlertControls::new(None).unwrap())),
+            incidents: Arc::new(Mutex::new(IncidentTracker::new(None).unwrap())),
+            recent: Arc::new(Mutex::new(RecentChecks::new())),
+            targets: Arc::new(
+                Targets::new(targets, None, Box::new(|_, _| {}), Box::new(|_| {})).unwrap(),
+            ),
+        }
+    }
+
+    #[test]
+    fn it_answers_requests_and_filters_snapshots() {
+        let factory = factory();
+        let clients = factory.clients.clone();
+        {
+            let mut recent = factory.recent.lock().unwrap();
+            recent.push(check("foo", "tag", Status::Okay));
+            recent.push(check("bar", "other", Status::Okay));
+        }
+        let received = client(&clients, 0);
+
+        factory.handle(0, r#"{"type": "ping"}"#, false);
+        factory.handle(0, r#"{"type": "subscribe", "tag": "^tag$"}"#, false);
+        factory.handle(0, r#"{"type": "snapshot"}"#, false);
+        factory.handle(0, r#"{"type": "dance"}"#, false);
+
+        let received = received.lock().unwrap();
+        assert!(received[0].contains(r#""type":"pong""#));
+        assert!(received[1].contains(r#""type":"subscription""#));
+        assert!(received[2].contains(r#""name":"foo""#));
+        assert!(!received[2].contains(r#""name":"bar""#));
+        assert!(received[3].contains(r#""type":"error""#));
+    }
+
+    #[test]
+    fn it_checks_targets_now() {
+        let mut factory = factory();
+        let targets = Targets::new(
+            vec![target()],
+            None,
+            Box::new(|_, triggered| {
+                thread::spawn(move || {
+                    for trigger in triggered {
+                        let _ = trigger.send(check("foo", "tag", Status::Fire));
+                    }
+                });
+            }),
+            Box::new(|_| {}),
+        );
+        factory.targets = Arc::new(targets.unwrap());
+        let (sent, received) = mpsc::channel();
+        let sent = Mutex::new(sent);
+        factory.clients.add(0, move |json| {
+            let _ = sent.lock().unwrap().send(json);
+        });
+
+        factory.handle(0, r#"{"type": "check", "name": "foo"}"#, false);
+        factory.handle(0, r#"{"type": "check"}"#, true);
+        factory.handle(0, r#"{"type": "check", "name": "bar"}"#, true);
+        factory.handle(0, r#"{"type": "check", "tag": "tag"}"#, true);
+
+        // The last answer is sent once the check has been handled, in the background
+        let received = (0..4)
+            .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(received[0].contains("admin scope"));
+        assert!(received[1].contains("either a name or a tag"));
+        assert!(received[2].contains("unknown target `bar`"));
+        assert!(received[3].starts_with(r#"{"version":1,"type":"checked","data":[{"#));
+        assert!(received[3].contains(r#""status":"Fire""#));
+    }
+
+    #[test]
+    fn it_refuses_handshakes_without_credentials() {
+        let mut factory = factory();
+        factory.auth = Some(Arc::new(
+            Authenticator::new(&librcanary::CanaryAuthConfig {
+                enabled: true,
+                users: vec![librcanary::CanaryAuthUser {
+                    name: "wall".to_string(),
+                    password: None,
+                    scope: CanaryAuthScope::Read,
+                    token: Some("secret".to_string()),
+                }],
+                ..librcanary::CanaryAuthConfig::default()
+            })
+            .unwrap(),
+        ));
+
+        start_ws_server("127.0.0.1:56479", factory, None);
+
+        assert!(handshake(56479, "/").starts_with("HTTP/1.1 401"));
+        assert!(handshake(56479, "/?token=nope").starts_with("HTTP/1.1 401"));
+        assert!(handshake(56479, "/?token=secret").starts_with("HTTP/1.1 101"));
+    }
+
+    #[test]
+    fn it_refuses_clients_from_other_addresses_before_the_upgrade() {
+        let mut factory = factory();
+        factory.auth = Some(Arc::new(
+            Authenticator::new(&librcanary::CanaryAuthConfig {
+                enabled: true,
+                allowed_ips: vec!["192.0.2.1".to_string()],
+                ..librcanary::CanaryAuthConfig::default()
+            })
+            .unwrap(),
+        ));
+
+        start_ws_server("127.0.0.1:56483", factory, None);
+
+        assert!(handshake(56483, "/").starts_with("HTTP/1.1 403"));
+    }
+
+    // Returns the status line answering a websocket handshake
+    fn handshake(port: u16, resource: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            resource
+        )
+        .unwrap();
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        status
+    }
+
+    #[test]
+    fn it_serves_websockets_over_tls_and_reloads_certificates() {
+        let config = crate::tls::tests::self_signed("ws-old");
+        let tls = Arc::new(Tls::new(&config).unwrap());
+        start_ws_server("127.0.0.1:56480", factory(), Some(tls.clone()));
+
+        // Returns the handshake status line and the server's certificate
+        let handshake = || {
+            let mut connector =
+                openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()).unwrap();
+            connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+            let stream = TcpStream::connect("127.0.0.1:56480").unwrap();
+            let mut stream = connector.build().connect("localhost", stream).unwrap();
+
+            let certificate = stream.ssl().peer_certificate().unwrap().to_pem().unwrap();
+
+            write!(
+                stream,
+                "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n"
+            )
+            .unwrap();
+            let mut status = String::new();
+            BufReader::new(stream).read_line(&mut status).unwrap();
+
+            (status, certificate)
+        };
+
+        let (status, certificate) = handshake();
+        assert!(status.starts_with("HTTP/1.1 101"));
+        assert_eq!(
+            std::fs::read(&config.certificate_path).unwrap(),
+            certificate
+        );
+
+        let new = crate::tls::tests::self_signed("ws-new");
+        std::fs::copy(&new.certificate_path, &config.certificate_path).unwrap();
+        std::fs::copy(&new.key_path, &config.key_path).unwrap();
+        tls.reload().unwrap();
 
-    fn client_connected(&mut self, _ws: Sender) -> ClientHandler {
-        ClientHandler {}
+        assert_eq!(std::fs::read(&new.certificate_path).unwrap(), handshake().1);
     }
 }